@@ -0,0 +1,59 @@
+#![allow(non_snake_case)]
+
+// Generate the lists of the bundled templates and static files, so
+// that they are embedded in the binary. See src/embedded.rs.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Collect the files under “dir” recursively, in a stable order.
+fn listFiles(dir: &Path, files: &mut Vec<PathBuf>)
+{
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Failed to read {:?}: {}", dir, e))
+        .map(|entry| entry.unwrap().path()).collect();
+    entries.sort();
+    for path in entries
+    {
+        if path.is_dir()
+        {
+            listFiles(&path, files);
+        }
+        else
+        {
+            files.push(path);
+        }
+    }
+}
+
+/// Write a static array named “name” of (path relative to “dir”,
+/// content) pairs of the files under “dir”.
+fn writeFileList(code: &mut String, name: &str, dir: &Path)
+{
+    let mut files = Vec::new();
+    listFiles(dir, &mut files);
+    writeln!(code, "pub static {}: &[(&str, &[u8])] = &[", name).unwrap();
+    for file in files
+    {
+        let relative = file.strip_prefix(dir).unwrap().to_str().unwrap()
+            .replace('\\', "/");
+        writeln!(code, "    ({:?}, include_bytes!({:?})),", relative, file)
+            .unwrap();
+    }
+    writeln!(code, "];").unwrap();
+}
+
+fn main()
+{
+    let root = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let mut code = String::new();
+    for (name, dir) in [("TEMPLATES", "templates"), ("STATICS", "static")]
+    {
+        println!("cargo:rerun-if-changed={}", dir);
+        writeFileList(&mut code, name, &root.join(dir));
+    }
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap())
+        .join("embedded.rs");
+    std::fs::write(&out, code)
+        .unwrap_or_else(|e| panic!("Failed to write {:?}: {}", out, e));
+}
@@ -1,30 +1,104 @@
 use std::path::{PathBuf, Path};
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Read;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
 
+use bytes::Buf;
 use futures_util::TryStreamExt;
 use log::info;
 use log::error as log_error;
 use tera::Tera;
+use tokio::sync::Semaphore;
 use warp::{Filter, Reply};
 use warp::http::status::StatusCode;
 use warp::reply::Response;
+use warp::http::header::{self, HeaderValue};
+use warp::filters::BoxedFilter;
 use base64::engine::Engine;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc2822;
 
-use crate::error;
 use crate::error::Error;
+use crate::embedded;
+use crate::fetch;
 use crate::config::Configuration;
 use crate::data;
-use crate::video_processing::UploadingVideo;
+use crate::data::{PendingUpload, APITokenScope};
+use crate::maintenance;
+use crate::password::verifyPassword;
+use crate::metrics::Metrics;
+use crate::progress::{UploadProgress, UploadLocks, isValidUploadID};
+use crate::storage;
+use crate::queue::ProcessingQueue;
+use crate::storage::{Storage, ByteRange, StoredRange};
+use crate::urls::{urlFor, siteURL, feedURL, pathPrefix};
+use crate::video::{Video, VideoSummary, ContainerType, Visibility,
+                   TimeDisplay, humanReadableSize, formatThousands};
+use crate::video_processing::{UploadingVideo, RawVideo, UploadOptions,
+                              directorySize,
+                              randomTempFilename, hashFile, appendStreamToFile,
+                              convertThumbnail, thumbnailPath, hlsDir, packageHLS,
+                              videoPath, probeVideo};
+
+/// A route that is done with all its filters.
+type Route = BoxedFilter<(Response,)>;
 
 static BASE64: &base64::engine::general_purpose::GeneralPurpose =
     &base64::engine::general_purpose::STANDARD;
 static BASE64_NO_PAD: &base64::engine::general_purpose::GeneralPurpose =
     &base64::engine::general_purpose::STANDARD_NO_PAD;
 static TOKEN_COOKIE: &str = "metube-token";
+static TUS_VERSION: &str = "1.0.0";
+/// The image used as the logo and favicon if none is configured,
+/// relative to the static dir.
+static BUNDLED_IMAGE: &str = "film.svg";
 
 trait ToResponse
 {
     fn toResponse(self) -> Response;
+    /// Like `toResponse`, but for HTML pages. A 404 error is rendered
+    /// with the `404.html` template.
+    fn toHTMLResponse(self, templates: &Tera, config: &Configuration) ->
+        Response;
+    /// Like `toResponse`, but errors are rendered as JSON, for the
+    /// API.
+    fn toJSONResponse(self) -> Response;
+}
+
+/// The template context shared by all pages.
+fn baseContext(config: &Configuration) -> tera::Context
+{
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    context.insert("uploads_enabled", &config.uploads_enabled);
+    // The “loading” attribute of the thumbnails in video listings.
+    context.insert("thumbnail_loading",
+                   if config.lazy_thumbnails { "lazy" } else { "eager" });
+    context
+}
+
+/// Render an error on an HTML page. Only 404 gets a page from the
+/// template; other errors are returned as plain text.
+fn htmlErrorResponse(e: Error, templates: &Tera, config: &Configuration) ->
+    Response
+{
+    log_error!("{}", e);
+    if let Error::HTTPStatus(StatusCode::NOT_FOUND, msg) = &e
+    {
+        let mut context = baseContext(config);
+        context.insert("message", msg);
+        match templates.render("404.html", &context)
+        {
+            Ok(page) => return warp::reply::with_status(
+                warp::reply::html(page), StatusCode::NOT_FOUND)
+                .into_response(),
+            Err(render_error) => log_error!(
+                "Failed to render template 404.html: {}", render_error),
+        }
+    }
+    e.into_response()
 }
 
 impl ToResponse for Result<String, Error>
@@ -40,6 +114,28 @@ impl ToResponse for Result<String, Error>
             },
         }
     }
+
+    fn toHTMLResponse(self, templates: &Tera, config: &Configuration) ->
+        Response
+    {
+        match self
+        {
+            Ok(s) => warp::reply::html(s).into_response(),
+            Err(e) => htmlErrorResponse(e, templates, config),
+        }
+    }
+
+    fn toJSONResponse(self) -> Response
+    {
+        match self
+        {
+            Ok(s) => warp::reply::html(s).into_response(),
+            Err(e) => {
+                log_error!("{}", e);
+                e.toJSONResponse()
+            },
+        }
+    }
 }
 
 impl ToResponse for Result<Response, Error>
@@ -55,6 +151,28 @@ impl ToResponse for Result<Response, Error>
             }
         }
     }
+
+    fn toHTMLResponse(self, templates: &Tera, config: &Configuration) ->
+        Response
+    {
+        match self
+        {
+            Ok(s) => s,
+            Err(e) => htmlErrorResponse(e, templates, config),
+        }
+    }
+
+    fn toJSONResponse(self) -> Response
+    {
+        match self
+        {
+            Ok(s) => s,
+            Err(e) => {
+                log_error!("{}", e);
+                e.toJSONResponse()
+            },
+        }
+    }
 }
 
 fn validateSession(token: &Option<String>, data_manager: &data::Manager,
@@ -62,8 +180,13 @@ fn validateSession(token: &Option<String>, data_manager: &data::Manager,
 {
     if let Some(token) = token
     {
-        data_manager.expireSessions(config.session_life_time_sec)?;
+        data_manager.expireSessions(config.session_life_time_sec,
+                                    config.sessionMaxLifeTime())?;
         data_manager.hasSession(&token)?;
+        if config.session_sliding
+        {
+            data_manager.touchSession(token)?;
+        }
         Ok(true)
     }
     else
@@ -72,296 +195,2949 @@ fn validateSession(token: &Option<String>, data_manager: &data::Manager,
     }
 }
 
-fn handleIndex(data_manager: &data::Manager, templates: &Tera,
+/// Whether “token” is a valid session, or an API token that allows
+/// “scope”.
+fn validateAccess(token: &Option<String>, scope: APITokenScope,
+                  data_manager: &data::Manager, config: &Configuration) ->
+    Result<bool, Error>
+{
+    if validateSession(token, data_manager, config).unwrap_or(false)
+    {
+        return Ok(true);
+    }
+    match token
+    {
+        Some(token) => Ok(data_manager.findAPIToken(token)?
+                          .is_some_and(|t| t.scope >= scope)),
+        None => Ok(false),
+    }
+}
+
+/// Extract the token of a request that accepts API tokens. This is a
+/// bearer token in the “Authorization” header if there is one,
+/// otherwise the session cookie.
+fn authToken() ->
+    impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone
+{
+    warp::header::optional::<String>("Authorization")
+        .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+        .map(|auth: Option<String>, cookie: Option<String>| {
+            auth.as_deref().and_then(|v| v.strip_prefix("Bearer "))
+                .map(|t| t.trim().to_owned()).or(cookie)
+        })
+}
+
+/// Pass the shared state to a handler.
+fn withState(state: &Arc<AppState>) ->
+    impl Filter<Extract = (Arc<AppState>,), Error = Infallible> + Clone
+{
+    let state = state.clone();
+    warp::any().map(move || state.clone())
+}
+
+/// Query parameters of a video listing. Missing values fall back
+/// to the defaults in the config.
+#[derive(Deserialize)]
+struct ListingQuery
+{
+    start: Option<u64>,
+    count: Option<u64>,
+    order: Option<String>,
+    /// Only list videos uploaded at or after this Unix timestamp.
+    from: Option<i64>,
+    /// Only list videos uploaded at or before this Unix timestamp.
+    to: Option<i64>,
+}
+
+impl ListingQuery
+{
+    fn checkTimeRange(&self) -> Result<(), Error>
+    {
+        if let (Some(from), Some(to)) = (self.from, self.to)
+        {
+            if from > to
+            {
+                return Err(Error::HTTPStatus(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid time range: {} > {}", from, to)));
+            }
+        }
+        Ok(())
+    }
+
+    fn getVideos(&self, data_manager: &data::Manager, config: &Configuration)
+                 -> Result<Vec<Video>, Error>
+    {
+        self.checkTimeRange()?;
+        data_manager.getVideosInRange(
+            self.start.unwrap_or(0),
+            config.pageSize(self.count),
+            self.order(config)?, self.from, self.to)
+    }
+
+    fn getVideoSummaries(&self, data_manager: &data::Manager,
+                         config: &Configuration) ->
+        Result<Vec<VideoSummary>, Error>
+    {
+        self.checkTimeRange()?;
+        data_manager.getVideoSummaries(
+            self.start.unwrap_or(0),
+            config.pageSize(self.count),
+            self.order(config)?, self.from, self.to)
+    }
+
+    fn order(&self, config: &Configuration) -> Result<data::VideoOrder, Error>
+    {
+        match &self.order
+        {
+            Some(name) => data::VideoOrder::fromName(name).ok_or_else(
+                || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                     format!("Invalid order: {}", name))),
+            None => Ok(config.defaultOrder()),
+        }
+    }
+}
+
+/// Whether a client with the Accept header “accept” asks for JSON
+/// rather than HTML. This is only when `application/json` has a
+/// higher quality than any media range that covers HTML, so browsers
+/// and ambiguous clients get HTML.
+fn prefersJSON(accept: &str) -> bool
+{
+    let mut json = 0.0;
+    let mut html = 0.0;
+    for item in accept.split(',')
+    {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts.find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f64>().ok()).unwrap_or(1.0);
+        match name.as_str()
+        {
+            "application/json" => json = q,
+            "text/html" | "text/*" | "*/*" => html = f64::max(html, q),
+            _ => {},
+        }
+    }
+    json > 0.0 && json > html
+}
+
+/// The index page, or the video listing as JSON like
+/// `handleVideosJSON` if the client asks for JSON in its Accept
+/// header.
+fn handleIndex(query: ListingQuery, accept: Option<String>,
+               data_manager: &data::Manager, templates: &Tera,
                config: &Configuration) -> Result<Response, Error>
 {
-    let videos = data_manager.getVideos(
-        0, 1000, data::VideoOrder::NewFirst)?;
-    let mut context = tera::Context::new();
+    if accept.as_deref().is_some_and(prefersJSON)
+    {
+        return Ok(handleVideosJSON(query, data_manager, config)
+                  .toJSONResponse());
+    }
+    let videos = query.getVideoSummaries(data_manager, config)?;
+    let mut context = baseContext(config);
     context.insert("videos", &videos);
-    context.insert("site_info", &config.site_info);
+    // Pinned videos are shown above the list, only on its first page.
+    if query.start.unwrap_or(0) == 0 && query.from.is_none() &&
+        query.to.is_none()
+    {
+        context.insert("pinned_videos", &data_manager.getPinnedVideos()?);
+    }
     Ok(warp::reply::html(templates.render("index.html", &context).map_err(
         |e| rterr!("Failed to render template index.html: {}", e))?)
        .into_response())
 }
 
-fn handleVideo(id: String, data_manager: &data::Manager, templates: &Tera,
-               config: &Configuration) -> Result<String, Error>
+/// Query parameters of a paginated page.
+#[derive(Deserialize)]
+struct PageQuery
 {
-    let video = data_manager.findVideoByID(&id).map_err(|_| Error::HTTPStatus(
-        StatusCode::NOT_FOUND, format!("Video {} not found", id)))?;
-    let mut context = tera::Context::new();
+    start: Option<u64>,
+    count: Option<u64>,
+}
+
+/// List the public videos by an artist. An empty artist is shown as
+/// “Unknown”, and lists the videos without an artist. The artist name
+/// is percent-encoded, as it is from the URL.
+fn handleArtist(artist: String, query: PageQuery,
+                data_manager: &data::Manager, templates: &Tera,
+                config: &Configuration) -> Result<String, Error>
+{
+    let artist = urlencoding::decode(&artist).map_err(
+        |_| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                              format!("Invalid artist: {}", artist)))?;
+    let start = query.start.unwrap_or(0);
+    let count = config.pageSize(query.count);
+    let videos = data_manager.getVideosByArtist(&artist, start, count)?;
+    let mut context = baseContext(config);
+    context.insert("artist", &artist);
+    context.insert("videos", &videos);
+    context.insert("count", &count);
+    context.insert("prev_start", &if start > 0
+                   {
+                       Some(start.saturating_sub(count))
+                   }
+                   else
+                   {
+                       None
+                   });
+    context.insert("next_start", &if count > 0 && videos.len() as u64 == count
+                   {
+                       Some(start + count)
+                   }
+                   else
+                   {
+                       None
+                   });
+    templates.render("artist.html", &context).map_err(
+        |e| rterr!("Failed to render template artist.html: {}", e))
+}
+
+/// List the recently viewed videos of the current session. Without
+/// a session the list is empty.
+fn handleHistory(token: Option<String>, data_manager: &data::Manager,
+                 templates: &Tera, config: &Configuration) ->
+    Result<String, Error>
+{
+    let token = validToken(token, data_manager, config);
+    let videos = match &token
+    {
+        Some(token) => data_manager.getHistory(token)?,
+        None => Vec::new(),
+    };
+    let mut context = baseContext(config);
+    context.insert("videos", &videos);
+    context.insert("has_session", &token.is_some());
+    templates.render("history.html", &context).map_err(
+        |e| rterr!("Failed to render template history.html: {}", e))
+}
+
+fn handleClearHistory(token: Option<String>, data_manager: &data::Manager,
+                      config: &Configuration) -> Result<Response, Error>
+{
+    if let Some(token) = validToken(token, data_manager, config)
+    {
+        data_manager.clearHistory(&token)?;
+    }
+    let uri = pathPrefix(&config.serve_under_path) + &urlFor("history", "");
+    Ok(warp::redirect::see_other(uriFromStr(&uri)?).into_response())
+}
+
+/// Toggle whether a video is pinned to the top of the index, and go
+/// back to the video page.
+fn handlePin(id: String, token: Option<String>, data_manager: &data::Manager,
+             config: &Configuration) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config).unwrap_or(false)
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let video = findVideoOr404(&id, data_manager)?;
+    data_manager.setPinned(&id, !video.pinned)?;
+    let uri = pathPrefix(&config.serve_under_path) + &urlFor("video", &id);
+    Ok(warp::redirect::see_other(uriFromStr(&uri)?).into_response())
+}
+
+fn findVideoOr404(id: &str, data_manager: &data::Manager) ->
+    Result<Video, Error>
+{
+    data_manager.findVideoByID(id)?.ok_or_else(|| Error::HTTPStatus(
+        StatusCode::NOT_FOUND, format!("Video {} not found", id)))
+}
+
+/// Private videos are only visible with a valid session. Otherwise
+/// they are reported as not found, so that their existence is not
+/// revealed. This applies to their pages, API and files alike.
+fn checkVisibility(video: &Video, token: &Option<String>,
+                   data_manager: &data::Manager, config: &Configuration) ->
+    Result<(), Error>
+{
+    if video.visibility == Visibility::Private &&
+        !validateAccess(token, APITokenScope::ReadOnly, data_manager, config)
+        .unwrap_or(false)
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::NOT_FOUND, format!("Video {} not found", video.id)));
+    }
+    Ok(())
+}
+
+fn handleVideo(id: String, token: Option<String>,
+               data_manager: &data::Manager, metrics: &Metrics,
+               templates: &Tera, config: &Configuration) ->
+    Result<String, Error>
+{
+    let video = findVideoOr404(&id, data_manager)?;
+    checkVisibility(&video, &token, data_manager, config)?;
+    let token = validToken(token, data_manager, config);
+    let position = match &token
+    {
+        Some(token) =>
+        {
+            if let Err(e) = data_manager.addToHistory(
+                token, &id, config.history_size)
+            {
+                log_error!("{}", e);
+            }
+            data_manager.getWatchPosition(token, &id)?
+        },
+        None => None,
+    };
+    let mut context = baseContext(config);
     context.insert("video", &video);
-    context.insert("site_info", &config.site_info);
+    context.insert("chapters", &data_manager.getChapters(&id)?);
+    let (prev_id, next_id) = data_manager.getAdjacentVideos(
+        &video, config.defaultOrder())?;
+    context.insert("has_session", &token.is_some());
+    context.insert("hls_enabled", &config.enable_hls);
+    context.insert("embed_code", &embedCode(&video, config));
+    context.insert("position", &position);
+    context.insert("prev_id", &prev_id);
+    context.insert("next_id", &next_id);
     let res = templates.render("video.html", &context).map_err(
         |e| rterr!("Failed to render template video.html: {}", e));
     if let Err(e) = data_manager.increaseViewCount(&id)
     {
         log_error!("{}", e);
     }
+    metrics.countView();
     res
 }
 
-fn handleUploadPage(data_manager: &data::Manager, templates: &Tera,
-                    config: &Configuration, token: Option<String>) ->
-    Result<String, Error>
+/// The HTML snippet that embeds the player of a video in another
+/// page.
+fn embedCode(video: &Video, config: &Configuration) -> String
 {
-    if validateSession(&token, data_manager, config)?
-    {
-        templates.render("upload.html", &tera::Context::new())
-            .map_err(|e| rterr!("Failed to render template upload.html: {}",
-                                e))
-    }
-    else
+    format!("<iframe src=\"{}\" width=\"640\" height=\"360\" \
+             title=\"{}\" allowfullscreen></iframe>",
+            escapeXML(&(siteURL(config) + &urlFor("embed", &video.id))),
+            escapeXML(&video.title))
+}
+
+/// Render the player of a video alone, to be embedded in other sites
+/// with an iframe. Unlike `handleVideo`, any site can frame this
+/// page.
+fn handleEmbed(id: String, token: Option<String>,
+               data_manager: &data::Manager, metrics: &Metrics,
+               templates: &Tera, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let video = findVideoOr404(&id, data_manager)?;
+    checkVisibility(&video, &token, data_manager, config)?;
+    let mut context = baseContext(config);
+    context.insert("video", &video);
+    context.insert("hls_enabled", &config.enable_hls);
+    let page = templates.render("embed.html", &context).map_err(
+        |e| rterr!("Failed to render template embed.html: {}", e))?;
+    if let Err(e) = data_manager.increaseViewCount(&id)
     {
-        Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()))
+        log_error!("{}", e);
     }
+    metrics.countView();
+    let csp = HeaderValue::from_str(&makeEmbedCSP(config)).map_err(
+        |_| rterr!("Invalid csp_extra_sources"))?;
+    Ok(warp::reply::with_header(warp::reply::html(page),
+                                header::CONTENT_SECURITY_POLICY, csp)
+       .into_response())
+}
+
+/// A page of the video listing in the JSON API.
+#[derive(Serialize)]
+struct VideoPage
+{
+    items: Vec<Video>,
+    /// Number of videos in the whole listing.
+    total: u64,
+    start: u64,
+    /// The requested page size. The page has fewer items at the end
+    /// of the listing.
+    count: u64,
+}
+
+fn handleVideosJSON(query: ListingQuery, data_manager: &data::Manager,
+                    config: &Configuration) -> Result<Response, Error>
+{
+    let items = query.getVideos(data_manager, config)?;
+    Ok(warp::reply::json(&VideoPage {
+        items,
+        total: data_manager.countVideos(query.from, query.to)?,
+        start: query.start.unwrap_or(0),
+        count: config.pageSize(query.count),
+    }).into_response())
+}
+
+/// Return the metadata of a video as JSON. Unlike `handleVideo`,
+/// this does not count as a view.
+fn handleVideoJSON(id: String, token: Option<String>,
+                   data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let video = findVideoOr404(&id, data_manager)?;
+    checkVisibility(&video, &token, data_manager, config)?;
+    Ok(warp::reply::json(&video).into_response())
 }
 
-async fn handleUpload(token: Option<String>,
-                      form_data: warp::multipart::FormData,
-                      data_manager: &data::Manager,
-                      config: &Configuration) ->
-    Result<String, warp::Rejection>
+/// The body of a metadata update. The fields that are left out are
+/// not changed.
+#[derive(Deserialize)]
+struct VideoPatchBody
 {
-    if !validateSession(&token, data_manager, config).map_err(
-        |_| warp::reject::reject())?
+    title: Option<String>,
+    desc: Option<String>,
+    artist: Option<String>,
+    visibility: Option<String>,
+    autoplay: Option<bool>,
+    #[serde(rename = "loop")]
+    looping: Option<bool>,
+}
+
+/// Update some of the metadata of a video, and reply with the
+/// updated video.
+fn handlePatchVideo(id: String, body: VideoPatchBody, token: Option<String>,
+                    data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !validateAccess(&token, APITokenScope::Upload, data_manager, config)?
     {
-        return Err(warp::reject::reject());
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
     }
-    // let parts: Vec<_> = form_data.and_then(
-    //     |part| async move { videoFromPart(part, config).await })
-    //     .try_collect().await.map_err(|e| {
-    //         log_error!("Failed to collect video: {}", e);
-    //         warp::reject::reject()
-    //     })?;
-    let parts: Vec<_> = form_data.and_then(
-        |part| async move {
-            let v = UploadingVideo { part };
-            let v = v.saveToTemp(config).await;
-            // v is a Result<_, error::Error>. But this async stream
-            // thing requires a Result<_, warp::Error>. So here we
-            // just wrap a extra layer of Result<_, warp::Error>.
-            // Later we will just unwrap it.
-            Ok(v)
-        }).try_collect().await
-        // Unwrap the Result<_, warp::Error> here.
-        .unwrap();
+    findVideoOr404(&id, data_manager)?;
+    let visibility = body.visibility.map(|name| {
+        Visibility::fromName(&name).ok_or_else(|| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, format!("Invalid visibility: {}", name)))
+    }).transpose()?;
+    data_manager.patchVideo(&id, &data::VideoPatch {
+        title: body.title,
+        desc: body.desc,
+        artist: body.artist,
+        visibility,
+        autoplay: body.autoplay,
+        looping: body.looping,
+    })?;
+    let video = findVideoOr404(&id, data_manager)?;
+    info!("Updated metadata of video {}.", id);
+    Ok(warp::reply::json(&video).into_response())
+}
 
-    for part in parts
+/// The stats API can be restricted to valid sessions by config.
+fn checkStatsAccess(token: &Option<String>, data_manager: &data::Manager,
+                    config: &Configuration) -> Result<(), Error>
+{
+    if config.stats_require_auth &&
+        !validateAccess(token, APITokenScope::ReadOnly, data_manager, config)
+        .unwrap_or(false)
     {
-        part.map_err(error::reject)?
-            .moveToLibrary(config).map_err(error::reject)?
-            .makeRelativePath(config).map_err(error::reject)?
-            .probeMetadata(config).map_err(error::reject)?
-            .generateThumbnail(config).map_err(error::reject)?
-            .addToDatabase(config, data_manager).map_err(error::reject)?;
-        break;
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
     }
-    Ok::<_, warp::Rejection>(String::from("OK"))
+    Ok(())
 }
 
-fn createToken() -> String
+fn handleStatsJSON(token: Option<String>, data_manager: &data::Manager,
+                   config: &Configuration) -> Result<Response, Error>
 {
-    BASE64_NO_PAD.encode(rand::random::<i128>().to_ne_bytes())
+    checkStatsAccess(&token, data_manager, config)?;
+    let stats = data_manager.getLibraryStats()?;
+    Ok(warp::reply::json(&stats).into_response())
 }
 
-fn uriFromStr(s: &str) -> Result<warp::http::uri::Uri, Error>
+fn handleMetrics(data_manager: &data::Manager, metrics: &Metrics) ->
+    Result<Response, Error>
 {
-    s.parse::<warp::http::uri::Uri>().map_err(|_| rterr!("Invalid URI: {}", s))
+    let stats = data_manager.getLibraryStats()?;
+    Ok(warp::reply::with_header(
+        metrics.render(&stats), header::CONTENT_TYPE,
+        "text/plain; version=0.0.4; charset=utf-8").into_response())
 }
 
-fn makeCookie(token: String, session_life_time: u64) -> String
+#[derive(Serialize)]
+struct ViewCount
 {
-    format!("{}={}; Max-Age={}; Path=/", TOKEN_COOKIE, token, session_life_time)
+    id: String,
+    views: u32,
 }
 
-fn handleLogin(auth_value_maybe: Option<String>, data_manager: &data::Manager,
-               config: &Configuration) -> Result<Response, Error>
+fn handleViewsJSON(id: String, token: Option<String>,
+                   data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
 {
-    if let Some(auth_value) = auth_value_maybe
+    checkStatsAccess(&token, data_manager, config)?;
+    let video = findVideoOr404(&id, data_manager)?;
+    checkVisibility(&video, &token, data_manager, config)?;
+    Ok(warp::reply::json(&ViewCount { id, views: video.views })
+       .into_response())
+}
+
+/// Return the session token if it belongs to a valid session.
+fn validToken(token: Option<String>, data_manager: &data::Manager,
+              config: &Configuration) -> Option<String>
+{
+    if validateSession(&token, data_manager, config).unwrap_or(false)
     {
-        if !auth_value.starts_with("Basic ")
-        {
-            return Err(Error::HTTPStatus(
-                StatusCode::UNAUTHORIZED,
-                "Not using basic authentication".to_owned()));
-        }
-        let expeced = BASE64.encode(format!("user:{}", config.password));
-        if expeced.as_str() == &auth_value[6..]
-        {
-            // Authentication is good.
-            let token = createToken();
-            data_manager.createSession(&token)?;
-            return Ok(warp::reply::with_header(
-                warp::redirect::found(uriFromStr(&config.serve_under_path)?),
-                "Set-Cookie", makeCookie(token, config.session_life_time_sec))
-                      .into_response());
-        }
-        else
-        {
-            return Err(Error::HTTPStatus(
-                StatusCode::UNAUTHORIZED,
-                "Invalid credential".to_owned()));
-        }
+        token
+    }
+    else
+    {
+        None
     }
+}
 
-    Ok(warp::reply::with_header(
-        warp::reply::with_status(warp::reply::reply(), StatusCode::UNAUTHORIZED),
-        "WWW-Authenticate",
-        r#"Basic realm="metube", charset="UTF-8""#).into_response())
+#[derive(Deserialize)]
+struct PositionBody
+{
+    /// Playback offset in seconds.
+    position: f64,
 }
 
-fn urlFor(name: &str, arg: &str) -> String
+#[derive(Serialize)]
+struct PositionReply
 {
-    match name
-    {
-        "index" => String::from("/"),
-        "video" => String::from("/v/") + arg,
-        "upload" => String::from("/upload/"),
-        "login" => String::from("/login/"),
-        "static" => String::from("/static/") + arg,
-        "video_file" => String::from("/video/") + arg,
-        _ => String::from("/"),
-    }
+    position: Option<f64>,
 }
 
-fn getTeraFuncArgs(args: &HashMap<String, tera::Value>, arg_name: &str) ->
-    tera::Result<String>
+/// Probe the video file again and return the raw metadata from
+/// ffprobe, for debugging. The file must be in the video directory.
+async fn handleProbeJSON(id: String, token: Option<String>,
+                         data_manager: &data::Manager,
+                         config: &Configuration) -> Result<Response, Error>
 {
-    let value = args.get(arg_name);
-    if value.is_none()
+    if !validateAccess(&token, APITokenScope::ReadOnly, data_manager, config)?
     {
-        return Err(format!("Argument {} not found in function call.", arg_name)
-                   .into());
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
     }
-    let value: String = tera::from_value(value.unwrap().clone())?;
-    Ok(value)
+    let video = findVideoOr404(&id, data_manager)?;
+    let path = videoPath(&video, config);
+    if !path.exists()
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::NOT_FOUND,
+            format!("File of video {} is not in the video directory", id)));
+    }
+    let metadata = tokio::task::spawn_blocking(move || probeVideo(&path))
+        .await.map_err(|e| rterr!("Failed to probe video: {}", e))??;
+    Ok(warp::reply::json(&metadata).into_response())
 }
 
-fn makeURLFor(serve_path: String) -> impl tera::Function
+/// Return the saved playback position of a video, as
+/// “{"position": seconds}”. The position is null for viewers without
+/// a session, or if nothing was saved.
+fn handleGetPosition(id: String, token: Option<String>,
+                     data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
 {
-    move |args: &HashMap<String, tera::Value>| ->
-        tera::Result<tera::Value> {
-            let path_prefix: String = if serve_path == "" || serve_path == "/"
-            {
-                String::new()
-            }
-            else if serve_path.starts_with("/")
-            {
-                serve_path.to_owned()
-            }
-            else
-            {
-                String::from("/") + &serve_path
-            };
+    let video = findVideoOr404(&id, data_manager)?;
+    checkVisibility(&video, &token, data_manager, config)?;
+    let position = match validToken(token, data_manager, config)
+    {
+        Some(token) => data_manager.getWatchPosition(&token, &id)?,
+        None => None,
+    };
+    Ok(warp::reply::json(&PositionReply { position }).into_response())
+}
 
-            let name = getTeraFuncArgs(args, "name")?;
-            let arg = getTeraFuncArgs(args, "arg")?;
-            Ok(tera::to_value(path_prefix + &urlFor(&name, &arg)).unwrap())
+/// Record the playback position of a video. Positions of viewers
+/// without a session are not stored.
+fn handleSetPosition(id: String, body: PositionBody, token: Option<String>,
+                     data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !body.position.is_finite() || body.position < 0.0
+    {
+        return Err(Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                     String::from("Invalid position")));
     }
+    let video = findVideoOr404(&id, data_manager)?;
+    checkVisibility(&video, &token, data_manager, config)?;
+    if let Some(token) = validToken(token, data_manager, config)
+    {
+        data_manager.setWatchPosition(&token, &id, body.position)?;
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
 }
 
-pub struct App
+/// Escape a string for XML text or attribute values.
+fn escapeXML(s: &str) -> String
 {
-    data_manager: data::Manager,
-    templates: Tera,
-    config: Configuration,
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('"', "&quot;").replace('\'', "&apos;")
 }
 
-impl App
+/// Return a sitemap that lists the pages of all public videos,
+/// newest first. The sitemap protocol allows at most 50,000 URLs in
+/// one file, so only the newest ones are listed if there are more.
+fn handleSitemap(data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
 {
-    pub fn new(config: Configuration) -> Result<Self, Error>
+    let videos = data_manager.getVideos(0, 50000, data::VideoOrder::NewFirst)?;
+    let prefix = siteURL(config);
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for video in videos
     {
-        let db_path = Path::new(&config.data_dir).join("db.sqlite");
-        let mut result = Self {
-            data_manager: data::Manager::newWithFilename(&db_path),
-            templates: Tera::default(),
-            config,
-        };
-        result.init()?;
-        Ok(result)
+        let t = video.upload_time;
+        let lastmod = format!("{:04}-{:02}-{:02}", t.year(), u8::from(t.month()),
+                              t.day());
+        xml += &format!("  <url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+                        escapeXML(&(prefix.clone() +
+                                    &urlFor("video", &video.id))),
+                        lastmod);
     }
+    xml += "</urlset>\n";
+    Ok(warp::reply::with_header(xml, "Content-Type", "application/xml")
+       .into_response())
+}
 
-    fn init(&mut self) -> Result<(), Error>
+/// Number of the newest videos listed in the feed.
+const FEED_SIZE: u64 = 50;
+
+/// Return an RSS feed of the newest public videos. If
+/// “websub_hub” is set, the hub is advertised, so that subscribers
+/// are notified of new videos.
+fn handleFeed(data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let videos = data_manager.getVideos(0, FEED_SIZE,
+                                        data::VideoOrder::NewFirst)?;
+    let prefix = siteURL(config);
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n\
+         <channel>\n\
+         <title>{}</title>\n<link>{}</link>\n<description>{}</description>\n\
+         <atom:link rel=\"self\" type=\"application/rss+xml\" href=\"{}\"/>\n",
+        escapeXML(&config.site_info.site_title),
+        escapeXML(&(prefix.clone() + &urlFor("index", ""))),
+        escapeXML(&config.site_info.site_title), escapeXML(&feedURL(config)));
+    if let Some(hub) = &config.websub_hub
     {
-        self.data_manager.connect()?;
-        self.data_manager.init()?;
-        let template_path = PathBuf::from(&self.config.data_dir)
-            .join("templates").canonicalize()
-            .map_err(|_| rterr!("Invalid template dir"))?
+        xml += &format!("<atom:link rel=\"hub\" href=\"{}\"/>\n",
+                        escapeXML(hub));
+    }
+    for video in videos
+    {
+        let link = escapeXML(&(prefix.clone() + &urlFor("video", &video.id)));
+        let date = video.upload_time.format(&Rfc2822).map_err(
+            |e| rterr!("Failed to format upload time: {}", e))?;
+        xml += &format!("<item>\n<title>{}</title>\n<link>{}</link>\n\
+                         <guid>{}</guid>\n<pubDate>{}</pubDate>\n\
+                         <description>{}</description>\n</item>\n",
+                        escapeXML(&video.title), link, link, date,
+                        escapeXML(&video.desc));
+    }
+    xml += "</channel>\n</rss>\n";
+    Ok(warp::reply::with_header(xml, "Content-Type", "application/rss+xml")
+       .into_response())
+}
+
+fn handleUploadPage(data_manager: &data::Manager, templates: &Tera,
+                    config: &Configuration, token: Option<String>) ->
+    Result<String, Error>
+{
+    if validateSession(&token, data_manager, config)?
+    {
+        let mut context = baseContext(config);
+        context.insert("video_field", &config.upload_video_field);
+        templates.render("upload.html", &context)
+            .map_err(|e| rterr!("Failed to render template upload.html: {}",
+                                e))
+    }
+    else
+    {
+        Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()))
+    }
+}
+
+/// A video shown on the admin pages, with information that is not
+/// shown publicly.
+#[derive(Serialize)]
+struct AdminVideo<'a>
+{
+    video: &'a Video,
+    uploader: &'a str,
+}
+
+/// The admin page. Measuring the storage walks the whole video
+/// directory, so it is done on a blocking thread.
+async fn handleAdmin(token: Option<String>, state: &AppState) ->
+    Result<String, Error>
+{
+    let data_manager = &state.data_manager;
+    let config = &state.config;
+    if !validateSession(&token, data_manager, config).unwrap_or(false)
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let stats = data_manager.getLibraryStats()?;
+    let top_videos = data_manager.getVideos(
+        0, 10, data::VideoOrder::MostViewed)?;
+    let storage_used = {
+        let video_dir = config.video_dir.clone();
+        tokio::task::spawn_blocking(move || directorySize(&video_dir))
+    }.await.map_err(|e| rterr!("Failed to measure storage: {}", e))??;
+    let mut context = baseContext(config);
+    context.insert("video_count", &formatThousands(stats.video_count));
+    context.insert("total_size", &humanReadableSize(stats.total_size));
+    context.insert("total_views", &formatThousands(stats.total_views));
+    context.insert("storage_used", &humanReadableSize(storage_used));
+    let top_videos: Vec<AdminVideo> = top_videos.iter().map(
+        |v| AdminVideo { video: v, uploader: &v.uploader }).collect();
+    context.insert("top_videos", &top_videos);
+    state.templates.get().render("admin.html", &context).map_err(
+        |e| rterr!("Failed to render template admin.html: {}", e))
+}
+
+/// The videos to delete, as a JSON body “{"ids": [...]}”.
+#[derive(Deserialize)]
+struct DeleteRequest
+{
+    ids: Vec<String>,
+}
+
+/// The videos to delete, as a form with a field “ids” of IDs
+/// separated by commas or whitespaces.
+#[derive(Deserialize)]
+struct DeleteForm
+{
+    ids: String,
+}
+
+impl From<DeleteForm> for DeleteRequest
+{
+    fn from(form: DeleteForm) -> Self
+    {
+        Self {
+            ids: form.ids.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|id| !id.is_empty()).map(|id| id.to_owned())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DeleteFailure
+{
+    id: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct DeleteReply
+{
+    deleted: Vec<String>,
+    failed: Vec<DeleteFailure>,
+}
+
+/// Delete a batch of videos and their files. Videos that are not
+/// found or whose files cannot be removed are reported as failed.
+/// A failure to remove the files does not bring back the database
+/// entry of the video.
+fn handleDeleteVideos(request: DeleteRequest, token: Option<String>,
+                      data_manager: &data::Manager, storage: &dyn Storage,
+                      config: &Configuration) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config).unwrap_or(false)
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let result = maintenance::deleteVideos(&request.ids, config, data_manager,
+                                           storage)?;
+    let reply = DeleteReply {
+        deleted: result.deleted,
+        failed: result.failed.into_iter().map(
+            |(id, error)| DeleteFailure { id, error }).collect(),
+    };
+    info!("Deleted {} videos.", reply.deleted.len());
+    Ok(warp::reply::json(&reply).into_response())
+}
+
+/// Check that the database and the video files agree. Orphaned files
+/// are only listed, and should be reviewed manually. If “fix” is
+/// true, videos whose file is renamed are relinked to it, and the
+/// other videos whose file is missing are deleted. Scanning reads the
+/// whole video directory, and relinking hashes files, so this is done
+/// on a blocking thread.
+async fn handleVerify(fix: bool, token: Option<String>,
+                      state: &Arc<AppState>) -> Result<String, Error>
+{
+    if !validateSession(&token, &state.data_manager, &state.config)
+        .unwrap_or(false)
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let context = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || verifyContext(fix, &state))
+    }.await.map_err(|e| rterr!("Failed to verify library: {}", e))??;
+    state.templates.get().render("admin-verify.html", &context).map_err(
+        |e| rterr!("Failed to render template admin-verify.html: {}", e))
+}
+
+/// The template context of the library verification page. See
+/// `handleVerify`.
+fn verifyContext(fix: bool, state: &AppState) ->
+    Result<tera::Context, Error>
+{
+    let config = &state.config;
+    let mut report = maintenance::verifyLibrary(config, &state.data_manager,
+                                                state.storage.as_ref())?;
+    let mut context = baseContext(config);
+    if fix
+    {
+        maintenance::relinkMovedVideos(&mut report, config,
+                                       &state.data_manager);
+    }
+    if fix && !report.missing_files.is_empty()
+    {
+        let ids: Vec<String> = report.missing_files.iter()
+            .map(|v| v.id.clone()).collect();
+        let result = maintenance::deleteVideos(
+            &ids, config, &state.data_manager, state.storage.as_ref())?;
+        info!("Deleted {} videos with missing files.", result.deleted.len());
+        context.insert("fixed", &result.deleted);
+    }
+    context.insert("report", &report);
+    Ok(context)
+}
+
+/// Read a non-file part of a multipart form as a string.
+async fn readTextPart(part: warp::multipart::Part) -> Result<String, Error>
+{
+    let name = part.name().to_owned();
+    let bytes: Vec<u8> = part.stream().try_fold(Vec::new(), |mut v, buf| {
+        v.extend_from_slice(buf.chunk());
+        async move { Ok(v) }
+    }).await.map_err(|e| rterr!("Failed to read form field {}: {}", name, e))?;
+    String::from_utf8(bytes).map_err(
+        |_| rterr!("Form field {} is not valid UTF-8", name))
+}
+
+/// An empty form field is the same as a missing one.
+fn nonEmpty(value: String) -> Option<String>
+{
+    if value.is_empty() { None } else { Some(value) }
+}
+
+/// Describe who is making a request, for auditing. This is the user
+/// of the session if it is logged in with a user account, or the name
+/// of the API token prefixed by “token:”, otherwise the address of the
+/// client.
+fn uploaderOf(token: &str, addr: Option<std::net::SocketAddr>,
+              data_manager: &data::Manager) -> Result<String, Error>
+{
+    if let Some(username) = data_manager.sessionUser(token)?
+    {
+        return Ok(username);
+    }
+    if let Some(api_token) = data_manager.findAPIToken(token)?
+    {
+        return Ok(format!("token:{}", api_token.name));
+    }
+    Ok(addr.map(|a| a.ip().to_string()).unwrap_or_default())
+}
+
+/// A multipart upload request.
+struct UploadRequest
+{
+    remote: Option<std::net::SocketAddr>,
+    form_data: warp::multipart::FormData,
+    /// Length of the request body, used to report the progress.
+    content_length: Option<u64>,
+}
+
+async fn handleUpload(token: Option<String>, request: UploadRequest,
+                      state: &AppState) -> Result<Response, Error>
+{
+    let data_manager = &state.data_manager;
+    let queue = &state.queue;
+    let progress = &state.progress;
+    let metrics = state.metrics.as_ref();
+    let config = &state.config;
+    if !validateAccess(&token, APITokenScope::Upload, data_manager,
+                       config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    // Only authorized requests take an upload slot. It is held until
+    // the upload is received, whether it succeeds or not.
+    let _slot = match state.upload_slots.clone().try_acquire_owned()
+    {
+        Ok(slot) => slot,
+        Err(_) => return Ok(uploadsBusyResponse(metrics)),
+    };
+    let token = token.unwrap_or_default();
+    // This is checked before receiving the video, so that it is not
+    // written only to be rejected.
+    if let Some(response) = checkUploadQuota(&token, request.content_length,
+                                             data_manager, config)
+        .inspect_err(|e| metrics.countUploadFailure(e))?
+    {
+        metrics.countUploadFailure(&Error::HTTPStatus(
+            StatusCode::TOO_MANY_REQUESTS, String::new()));
+        return Ok(response);
+    }
+    let uploader = uploaderOf(&token, request.remote, data_manager)?;
+    let mut form_data = request.form_data;
+    let mut upload_id = None;
+    let mut visibility = String::from("public");
+    let mut raw_video = None;
+    let mut thumbnail = None;
+    let mut title = None;
+    let mut desc = None;
+    let mut artist = None;
+    let read_result: Result<(), Error> = async {
+        while let Some(part) = form_data.try_next().await.map_err(
+            |e| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                  format!("Failed to read form data: {}", e)))?
+        {
+            match part.name()
+            {
+                "visibility" => visibility = readTextPart(part).await?,
+                "title" => title = nonEmpty(readTextPart(part).await?),
+                "desc" => desc = nonEmpty(readTextPart(part).await?),
+                "artist" => artist = nonEmpty(readTextPart(part).await?),
+                // This should come before the video to be useful.
+                "upload_id" => upload_id = nonEmpty(readTextPart(part).await?),
+                "thumbnail" if thumbnail.is_none() =>
+                    thumbnail = Some(saveImagePart(part, config).await?),
+                // Only the first file is accepted.
+                name if name == config.upload_video_field &&
+                    raw_video.is_none() =>
+                {
+                    let progress = match &upload_id
+                    {
+                        Some(id) => Some(progress.start(
+                            id, request.content_length)?),
+                        None => None,
+                    };
+                    raw_video = Some(UploadingVideo { part, progress }
+                                     .saveToTemp(config).await?);
+                },
+                // Unknown and repeated fields are ignored.
+                _ => {},
+            }
+        }
+        Ok(())
+    }.await;
+
+    let prepared = read_result.and_then(|_| {
+        let raw_video = raw_video.take().ok_or_else(
+            || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                 String::from("No video in upload")))?;
+        let visibility = match Visibility::fromName(&visibility)
+        {
+            Some(v) => v,
+            None => {
+                std::fs::remove_file(&raw_video.path).ok();
+                return Err(Error::HTTPStatus(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid visibility: {}", visibility)));
+            },
+        };
+        let options = UploadOptions {
+            visibility,
+            uploader,
+            thumbnail: thumbnail.clone(),
+            title,
+            desc,
+            artist,
+        };
+        let size = fileSize(&raw_video.path)?;
+        Ok((raw_video, options, size))
+    });
+    // Admitting probes and hashes the whole file.
+    let result = match prepared
+    {
+        Ok((raw_video, options, size)) =>
+        {
+            let config = config.clone();
+            let data_manager = data_manager.clone();
+            tokio::task::spawn_blocking(move || {
+                raw_video.admit(options, &config, &data_manager)
+                    .map(|v| (v, size))
+            }).await.map_err(|e| rterr!("Failed to add upload: {}", e))
+                .and_then(|result| result)
+        },
+        Err(e) => Err(e),
+    };
+    if let Some(raw_video) = raw_video
+    {
+        std::fs::remove_file(raw_video.path).ok();
+    }
+    if let Some(f) = thumbnail
+    {
+        std::fs::remove_file(f).ok();
+    }
+    submitUpload(result, &token, data_manager, queue, metrics).await
+}
+
+/// Queue an admitted video of “size” bytes for processing, and count
+/// it in the quota of “token” and in the metrics.
+async fn submitUpload(admitted: Result<(Video, u64), Error>, token: &str,
+                      data_manager: &data::Manager, queue: &ProcessingQueue,
+                      metrics: &Metrics) -> Result<Response, Error>
+{
+    let result = match admitted
+    {
+        Ok((video, size)) => queue.submit(video).await.and_then(
+            |_| data_manager.recordUpload(token, size)),
+        Err(e) => Err(e),
+    };
+    match result
+    {
+        Ok(_) => metrics.countUpload(),
+        Err(e) =>
+        {
+            metrics.countUploadFailure(&e);
+            return Err(e);
+        },
+    }
+    Ok(warp::reply::html("OK").into_response())
+}
+
+/// The body of an upload from a URL.
+#[derive(Deserialize)]
+struct URLUploadForm
+{
+    url: String,
+    visibility: Option<String>,
+    title: Option<String>,
+    desc: Option<String>,
+    artist: Option<String>,
+    /// Where to report the progress of fetching the video. See
+    /// `handleUploadProgress`.
+    upload_id: Option<String>,
+}
+
+/// A request to upload a video that the server fetches from a URL.
+struct URLUploadRequest
+{
+    remote: Option<std::net::SocketAddr>,
+    form: URLUploadForm,
+}
+
+/// Fetch the video at a URL, and add it like an uploaded one. The
+/// video file is limited by “per_file_size_max”, or by
+/// “upload_size_max” if that is not set.
+async fn handleUploadURL(token: Option<String>, request: URLUploadRequest,
+                         state: &AppState) -> Result<Response, Error>
+{
+    let data_manager = &state.data_manager;
+    let queue = &state.queue;
+    let progress = &state.progress;
+    let metrics = state.metrics.as_ref();
+    let config = &state.config;
+    if !validateAccess(&token, APITokenScope::Upload, data_manager,
+                       config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    // Only authorized requests take an upload slot. It is held until
+    // the upload is received, whether it succeeds or not.
+    let _slot = match state.upload_slots.clone().try_acquire_owned()
+    {
+        Ok(slot) => slot,
+        Err(_) => return Ok(uploadsBusyResponse(metrics)),
+    };
+    let token = token.unwrap_or_default();
+    if let Some(response) = checkUploadQuota(&token, None, data_manager, config)
+        .inspect_err(|e| metrics.countUploadFailure(e))?
+    {
+        metrics.countUploadFailure(&Error::HTTPStatus(
+            StatusCode::TOO_MANY_REQUESTS, String::new()));
+        return Ok(response);
+    }
+    let uploader = uploaderOf(&token, request.remote, data_manager)?;
+    let form = request.form;
+    let visibility = form.visibility.and_then(nonEmpty)
+        .unwrap_or_else(|| String::from("public"));
+    let visibility = Visibility::fromName(&visibility).ok_or_else(
+        || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                             format!("Invalid visibility: {}", visibility)))?;
+    let handle = match form.upload_id.and_then(nonEmpty)
+    {
+        Some(id) => Some(progress.start(&id, None)?),
+        None => None,
+    };
+    let max_size = config.per_file_size_max.unwrap_or(config.upload_size_max);
+    let url = form.url;
+    let options = UploadOptions {
+        visibility,
+        uploader,
+        thumbnail: None,
+        title: form.title.and_then(nonEmpty),
+        desc: form.desc.and_then(nonEmpty),
+        artist: form.artist.and_then(nonEmpty),
+    };
+    // Admitting probes and hashes the whole file, so it runs on the
+    // same blocking thread as the fetch.
+    let result = {
+        let config = config.clone();
+        let data_manager = data_manager.clone();
+        tokio::task::spawn_blocking(move || {
+            let raw_video = fetch::fetchToTemp(&url, max_size, handle,
+                                               &config)?;
+            let size = fileSize(&raw_video.path)?;
+            raw_video.admit(options, &config, &data_manager).map(|v| (v, size))
+        })
+    }.await.map_err(|e| rterr!("Failed to fetch video: {}", e))
+        .and_then(|result| result);
+    submitUpload(result, &token, data_manager, queue, metrics).await
+}
+
+/// Seconds after which a client should retry an upload that is
+/// rejected because too many uploads are in progress.
+const UPLOAD_RETRY_AFTER_SEC: u64 = 10;
+
+/// The response to an upload when “max_concurrent_uploads” uploads
+/// are already being received.
+fn uploadsBusyResponse(metrics: &Metrics) -> Response
+{
+    let error = Error::HTTPStatus(StatusCode::SERVICE_UNAVAILABLE,
+                                  String::from("Too many uploads in progress"));
+    metrics.countUploadFailure(&error);
+    warp::reply::with_header(error, "Retry-After",
+                             UPLOAD_RETRY_AFTER_SEC.to_string())
+        .into_response()
+}
+
+/// Check the upload quota of session “token” before it uploads
+/// “length” bytes. If the quota is exceeded, return a 429 response,
+/// with “Retry-After” set to when the earliest upload in the window
+/// stops counting.
+fn checkUploadQuota(token: &str, length: Option<u64>,
+                    data_manager: &data::Manager, config: &Configuration) ->
+    Result<Option<Response>, Error>
+{
+    if config.upload_quota_count.is_none() && config.upload_quota_bytes.is_none()
+    {
+        return Ok(None);
+    }
+    let length = length.unwrap_or(0);
+    if config.upload_quota_bytes.is_some_and(|max| length > max)
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            String::from("Upload is larger than the upload quota")));
+    }
+    let now = time::OffsetDateTime::now_utc();
+    let window = time::Duration::seconds(
+        i64::try_from(config.upload_quota_window_sec).unwrap_or(i64::MAX));
+    let since = now.checked_sub(window)
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+    let usage = data_manager.uploadUsageSince(token, since)?;
+    let exceeded =
+        config.upload_quota_count.is_some_and(|max| usage.count >= max) ||
+        config.upload_quota_bytes.is_some_and(
+            |max| usage.bytes.saturating_add(length) > max);
+    if !exceeded
+    {
+        return Ok(None);
+    }
+    let retry_after = usage.earliest_time.and_then(|t| t.checked_add(window))
+        .map(|t| (t - now).whole_seconds().max(1))
+        .unwrap_or(config.upload_quota_window_sec as i64);
+    Ok(Some(warp::reply::with_header(
+        Error::HTTPStatus(StatusCode::TOO_MANY_REQUESTS,
+                          String::from("Upload quota exceeded")),
+        "Retry-After", retry_after.to_string()).into_response()))
+}
+
+/// Stream the progress of upload “id” as server-sent events. Each
+/// event is the percentage received. A “done” event is sent when the
+/// whole video is received, or the upload fails. The client may
+/// connect before the upload starts, but the stream ends if the
+/// upload does not start in a while.
+fn handleUploadProgress(id: String, token: Option<String>,
+                        data_manager: &data::Manager,
+                        progress: Arc<UploadProgress>, config: &Configuration)
+                        -> Result<Response, Error>
+{
+    if !validateAccess(&token, APITokenScope::Upload, data_manager,
+                       config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    if !isValidUploadID(&id)
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, format!("Invalid upload ID: {}", id)));
+    }
+    const INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+    // Number of intervals to wait for the upload to start.
+    const START_WAIT_MAX: u32 = 60;
+
+    // The state is (whether the upload has been seen, the last
+    // reported percentage, number of intervals waited, whether the
+    // stream is done).
+    let events = futures_util::stream::unfold(
+        (false, None, 0, false),
+        move |(seen, last, waited, done)| {
+            let progress = progress.clone();
+            let id = id.clone();
+            async move {
+                if done
+                {
+                    return None;
+                }
+                let mut waited = waited;
+                loop
+                {
+                    match progress.get(&id)
+                    {
+                        Some(p) if p.percent() != last =>
+                        {
+                            let event = warp::sse::Event::default().data(
+                                p.percent().unwrap_or(0).to_string());
+                            return Some((Ok::<_, std::convert::Infallible>(
+                                event), (true, p.percent(), waited, false)));
+                        },
+                        Some(_) => {},
+                        None if seen =>
+                        {
+                            let event = warp::sse::Event::default()
+                                .event("done").data("");
+                            return Some((Ok(event), (true, last, waited, true)));
+                        },
+                        None if waited >= START_WAIT_MAX => return None,
+                        None => waited += 1,
+                    }
+                    tokio::time::sleep(INTERVAL).await;
+                }
+            }
+        });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events))
+       .into_response())
+}
+
+/// Thumbnail images are much smaller than videos.
+const THUMBNAIL_SIZE_MAX: u64 = 20 * 1024 * 1024;
+
+/// Save an uploaded image to a temp file in the video directory, and
+/// return its path.
+async fn saveImagePart(part: warp::multipart::Part, config: &Configuration) ->
+    Result<PathBuf, Error>
+{
+    let path = randomTempFilename(&config.video_dir);
+    std::fs::File::create(&path).map_err(
+        |e| rterr!("Failed to create temp file: {}", e))?;
+    if let Err(e) = appendStreamToFile(&path, part.stream(), THUMBNAIL_SIZE_MAX)
+        .await
+    {
+        std::fs::remove_file(&path).ok();
+        return Err(e);
+    }
+    Ok(path)
+}
+
+/// Replace the thumbnail of a video with the image in the “thumbnail”
+/// part of a multipart form.
+async fn handleSetThumbnail(id: String, token: Option<String>,
+                            form_data: warp::multipart::FormData,
+                            data_manager: &data::Manager,
+                            storage: &dyn Storage, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config).unwrap_or(false)
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let mut video = findVideoOr404(&id, data_manager)?;
+    let mut form_data = form_data;
+    let mut image = None;
+    while let Some(part) = form_data.try_next().await.map_err(
+        |e| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                              format!("Failed to read form data: {}", e)))?
+    {
+        if part.name() == "thumbnail"
+        {
+            image = Some(saveImagePart(part, config).await?);
+            break;
+        }
+    }
+    let image = image.ok_or_else(
+        || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                             String::from("No thumbnail in upload")))?;
+    // The old thumbnail may be in a different format.
+    let thumbnail_path = thumbnailPath(&video.path, config);
+    let converted = randomTempFilename(&config.video_dir).with_extension(
+        thumbnail_path.extension().unwrap_or_default());
+    let result = convertThumbnail(&image, &converted, config);
+    std::fs::remove_file(&image).ok();
+    result?;
+
+    let result = storage::keyFromPath(&thumbnail_path)
+        .and_then(|key| storage.put(&key, &converted));
+    std::fs::remove_file(&converted).ok();
+    result?;
+    // The uploaded thumbnail is not replaced when the thumbnail
+    // quality changes.
+    if video.thumbnail_path.as_ref() != Some(&thumbnail_path) ||
+        video.thumbnail_quality.is_some()
+    {
+        let old_path = video.thumbnail_path.replace(thumbnail_path.clone())
+            .filter(|p| p != &thumbnail_path);
+        video.thumbnail_quality = None;
+        data_manager.updateVideo(&video)?;
+        if let Some(old_path) = old_path
+        {
+            if let Err(e) = storage::keyFromPath(&old_path)
+                .and_then(|key| storage.delete(&key))
+            {
+                log_error!("Failed to delete old thumbnail {:?}: {}",
+                           old_path, e);
+            }
+        }
+    }
+    info!("Replaced thumbnail of video {}.", id);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Get the value of “key” from a tus-style “Upload-Metadata” header,
+/// which is a comma-separated list of keys and base64-encoded
+/// values.
+fn uploadMetadataValue(value: &str, key: &str) -> Option<String>
+{
+    for pair in value.split(',')
+    {
+        let mut split = pair.trim().splitn(2, ' ');
+        if split.next() == Some(key)
+        {
+            let bytes = BASE64.decode(split.next()?).ok()?;
+            return String::from_utf8(bytes).ok();
+        }
+    }
+    None
+}
+
+#[derive(Serialize)]
+struct CreatedUpload
+{
+    id: String,
+    offset: u64,
+}
+
+fn findPendingUploadOr404(id: &str, data_manager: &data::Manager) ->
+    Result<PendingUpload, Error>
+{
+    data_manager.findPendingUpload(id)?.ok_or_else(|| Error::HTTPStatus(
+        StatusCode::NOT_FOUND, format!("Upload {} not found", id)))
+}
+
+fn fileSize(path: &Path) -> Result<u64, Error>
+{
+    Ok(std::fs::metadata(path).map_err(
+        |e| rterr!("Failed to get metadata of {:?}: {}", path, e))?.len())
+}
+
+/// Start a resumable upload of “length” bytes. The response contains
+/// the ID of the upload, which is used to append data to the upload
+/// and to query its offset.
+fn handleCreateUpload(token: Option<String>,
+                      remote: Option<std::net::SocketAddr>, length: u64,
+                      metadata: Option<String>, data_manager: &data::Manager,
+                      config: &Configuration) -> Result<Response, Error>
+{
+    if !validateAccess(&token, APITokenScope::Upload, data_manager,
+                       config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    if length > config.upload_size_max ||
+        config.per_file_size_max.is_some_and(|max| length > max)
+    {
+        return Err(Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE,
+                                     format!("Upload too large: {}", length)));
+    }
+    let token = token.unwrap_or_default();
+    if let Some(response) = checkUploadQuota(&token, Some(length),
+                                             data_manager, config)?
+    {
+        return Ok(response);
+    }
+    let filename = metadata.as_deref()
+        .and_then(|m| uploadMetadataValue(m, "filename"))
+        .ok_or_else(|| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST,
+            String::from("No filename in upload metadata")))?;
+    let visibility = match metadata.as_deref()
+        .and_then(|m| uploadMetadataValue(m, "visibility"))
+    {
+        Some(name) => Visibility::fromName(&name).ok_or_else(
+            || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                 format!("Invalid visibility: {}", name)))?,
+        None => Visibility::Public,
+    };
+    let temp_path = randomTempFilename(&config.video_dir).with_extension(
+        Path::new(&filename).extension().unwrap_or_default());
+    std::fs::File::create(&temp_path).map_err(
+        |e| rterr!("Failed to create temp file: {}", e))?;
+    let upload = PendingUpload {
+        id: format!("{:032x}", rand::random::<u128>()),
+        temp_path,
+        original_filename: filename,
+        length,
+        visibility,
+        uploader: uploaderOf(&token, remote, data_manager)?,
+        create_time: time::OffsetDateTime::now_utc(),
+    };
+    if let Err(e) = data_manager.addPendingUpload(&upload)
+    {
+        std::fs::remove_file(&upload.temp_path).ok();
+        return Err(e);
+    }
+    // Resumable uploads count towards the quota when they start.
+    data_manager.recordUpload(&token, length)?;
+    let location = pathPrefix(&config.serve_under_path) +
+        &urlFor("uploads", &upload.id);
+    let body = warp::reply::json(&CreatedUpload { id: upload.id, offset: 0 });
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(
+            warp::reply::with_header(
+                warp::reply::with_status(body, StatusCode::CREATED),
+                "Location", location),
+            "Upload-Offset", "0"),
+        "Tus-Resumable", TUS_VERSION).into_response())
+}
+
+/// Report the number of bytes received so far by a resumable upload.
+fn handleUploadOffset(id: String, token: Option<String>,
+                      data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !validateAccess(&token, APITokenScope::Upload, data_manager,
+                       config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let upload = findPendingUploadOr404(&id, data_manager)?;
+    let offset = fileSize(&upload.temp_path)?;
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(
+            warp::reply::with_header(
+                warp::reply::with_header(
+                    warp::reply::reply(), "Upload-Offset", offset.to_string()),
+                "Upload-Length", upload.length.to_string()),
+            "Cache-Control", "no-store"),
+        "Tus-Resumable", TUS_VERSION).into_response())
+}
+
+/// Append data to a resumable upload. “offset” must match the
+/// number of bytes already received. When all bytes are received,
+/// the video is added to the library, and queued for processing.
+/// Only one request appends to an upload at a time; the others are
+/// `LOCKED`.
+async fn handleUploadPatch<S, B>(id: String, token: Option<String>,
+                                 offset: u64, body: S, state: &AppState) ->
+    Result<Response, Error>
+where
+    S: futures_util::Stream<Item = Result<B, warp::Error>>,
+    B: bytes::Buf,
+{
+    let data_manager = &state.data_manager;
+    let config = &state.config;
+    if !validateAccess(&token, APITokenScope::Upload, data_manager,
+                       config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    // Lock before looking up the upload, which another request
+    // could complete in the meantime.
+    let _lock = state.upload_locks.lock(&id)?;
+    let upload = findPendingUploadOr404(&id, data_manager)?;
+    let current_offset = fileSize(&upload.temp_path)?;
+    if offset != current_offset
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::CONFLICT,
+            format!("Offset mismatch: expect {}, found {}", current_offset,
+                    offset)));
+    }
+    data_manager.touchPendingUpload(&id)?;
+    let size = appendStreamToFile(&upload.temp_path, body, upload.length)
+        .await?;
+    if size == upload.length
+    {
+        info!("Upload {} completed.", id);
+        data_manager.removePendingUpload(&id)?;
+        // Hashing and probing read the whole file.
+        let video = {
+            let config = config.clone();
+            let data_manager = data_manager.clone();
+            tokio::task::spawn_blocking(move || {
+                let hash = hashFile(&upload.temp_path, &config)
+                    .inspect_err(|_| {
+                        std::fs::remove_file(&upload.temp_path).ok();
+                    })?;
+                RawVideo {
+                    path: upload.temp_path,
+                    hash,
+                    original_filename: upload.original_filename,
+                }.admit(UploadOptions {
+                    visibility: upload.visibility,
+                    uploader: upload.uploader,
+                    thumbnail: None,
+                    title: None,
+                    desc: None,
+                    artist: None,
+                }, &config, &data_manager)
+            })
+        }.await.map_err(|e| rterr!("Failed to add upload {}: {}", id, e))??;
+        state.queue.submit(video).await?;
+    }
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(
+            warp::reply::with_status(warp::reply::reply(),
+                                     StatusCode::NO_CONTENT),
+            "Upload-Offset", size.to_string()),
+        "Tus-Resumable", TUS_VERSION).into_response())
+}
+
+/// Read from “reader” chunk by chunk in a blocking thread.
+fn readerStream(reader: Box<dyn Read + Send>) ->
+    impl futures_util::Stream<Item = Result<Vec<u8>, std::io::Error>>
+{
+    futures_util::stream::try_unfold(reader, |mut reader| async move {
+        let (reader, result) = tokio::task::spawn_blocking(move || {
+            let mut buffer = vec![0; 64 * 1024];
+            let result = reader.read(&mut buffer).map(|size| {
+                buffer.truncate(size);
+                buffer
+            });
+            (reader, result)
+        }).await.map_err(std::io::Error::other)?;
+        let buffer = result?;
+        if buffer.is_empty()
+        {
+            Ok(None)
+        }
+        else
+        {
+            Ok(Some((buffer, reader)))
+        }
+    })
+}
+
+fn contentTypeFromKey(key: &str) -> &'static str
+{
+    let ext = Path::new(key).extension().and_then(|e| e.to_str())
+        .unwrap_or("");
+    match ContainerType::fromExtension(ext)
+    {
+        Some(container) => container.contentType(),
+        None => match ext.to_ascii_lowercase().as_str()
+        {
+            "webp" => "image/webp",
+            "svg" => "image/svg+xml",
+            "css" => "text/css; charset=utf-8",
+            "js" => "text/javascript; charset=utf-8",
+            "avif" => "image/avif",
+            "jpg" | "jpeg" => "image/jpeg",
+            "png" => "image/png",
+            "vtt" => "text/vtt; charset=utf-8",
+            "srt" => "application/x-subrip",
+            "m3u8" => "application/vnd.apple.mpegurl",
+            "m4s" => "video/iso.segment",
+            "ts" => "video/mp2t",
+            _ => "application/octet-stream",
+        },
+    }
+}
+
+/// Serve a file of the HLS package of a video, packaging the video
+/// first if needed. “file” is the playlist “index.m3u8”, or a
+/// segment referred to by it.
+async fn handleHLS(id: String, file: String, token: Option<String>,
+                   data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let valid_name = !file.starts_with('.') && file.chars().all(
+        |c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+    if !valid_name
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND,
+                                     format!("File {} not found", file)));
+    }
+    let video = findVideoOr404(&id, data_manager)?;
+    checkVisibility(&video, &token, data_manager, config)?;
+    let dir = hlsDir(&id, config);
+    if !dir.exists()
+    {
+        info!("Packaging video {} into HLS...", id);
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || packageHLS(&video, &config))
+            .await.map_err(|e| rterr!("Failed to package HLS: {}", e))??;
+    }
+    let content = tokio::fs::read(dir.join(&file)).await.map_err(
+        |_| Error::HTTPStatus(StatusCode::NOT_FOUND,
+                              format!("File {} not found", file)))?;
+    Ok(warp::reply::with_header(content, header::CONTENT_TYPE,
+                                contentTypeFromKey(&file)).into_response())
+}
+
+/// Whether an “If-None-Match” header value matches “etag”.
+fn etagMatches(if_none_match: &str, etag: &str) -> bool
+{
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',').map(|tag| tag.trim()).any(
+        |tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether an “If-Range” header value matches the file with
+/// “etag” and “last_modified”, in which case the requested range is
+/// served. Entity tags are compared strongly, and a date matches
+/// only if it is exactly the modification time.
+fn ifRangeMatches(if_range: &str, etag: Option<&str>,
+                  last_modified: Option<SystemTime>) -> bool
+{
+    let if_range = if_range.trim();
+    if if_range.starts_with('"')
+    {
+        etag.is_some_and(|etag| !etag.starts_with("W/") && etag == if_range)
+    }
+    else
+    {
+        last_modified.is_some_and(
+            |t| httpdate::fmt_http_date(t) == if_range)
+    }
+}
+
+/// Whether the file modified at “last_modified” has not changed
+/// since the date in an “If-Modified-Since” header value. HTTP dates
+/// have a resolution of seconds.
+fn notModifiedSince(if_modified_since: &str, last_modified: SystemTime) ->
+    bool
+{
+    httpdate::parse_http_date(if_modified_since.trim()).is_ok_and(
+        |since| httpdate::HttpDate::from(last_modified) <=
+            httpdate::HttpDate::from(since))
+}
+
+/// The headers of a request for a file from the storage that make it
+/// partial or conditional.
+struct FileRequest
+{
+    range: Option<String>,
+    if_none_match: Option<String>,
+    if_range: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+fn fileRequest() ->
+    impl Filter<Extract = (FileRequest,), Error = warp::Rejection> + Clone
+{
+    warp::header::optional::<String>("Range")
+        .and(warp::header::optional::<String>("If-None-Match"))
+        .and(warp::header::optional::<String>("If-Range"))
+        .and(warp::header::optional::<String>("If-Modified-Since"))
+        .map(|range, if_none_match, if_range, if_modified_since| FileRequest {
+            range, if_none_match, if_range, if_modified_since,
+        })
+}
+
+async fn getStoredRange(key: &str, range: ByteRange,
+                        storage: &Arc<dyn Storage>) ->
+    Result<StoredRange, Error>
+{
+    let stored = {
+        let key = key.to_owned();
+        let storage = storage.clone();
+        tokio::task::spawn_blocking(move || storage.getRange(&key, range))
+    }.await.map_err(|e| rterr!("Failed to read {}: {}", key, e))??;
+    stored.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND,
+                             format!("File {} not found", key)))
+}
+
+/// Serve a file from the storage, with the content type from its
+/// extension. A single byte range is supported, and so are
+/// conditional requests with “If-None-Match”, “If-Modified-Since”
+/// and “If-Range”. All routes that serve files from the storage go
+/// through this.
+async fn serveFileWithRange(key: String, request: FileRequest,
+                            storage: Arc<dyn Storage>) ->
+    Result<Response, Error>
+{
+    let mut range = request.range.map(|r| ByteRange::fromHeader(&r))
+        .unwrap_or(ByteRange::All);
+    let content_type = contentTypeFromKey(&key);
+    let mut stored = getStoredRange(&key, range, &storage).await?;
+    // If the file has changed since the client got the other parts,
+    // the whole file is sent instead of the range.
+    if !matches!(range, ByteRange::All) && request.if_range.is_some_and(
+        |v| !ifRangeMatches(&v, stored.etag.as_deref(), stored.last_modified))
+    {
+        range = ByteRange::All;
+        stored = getStoredRange(&key, range, &storage).await?;
+    }
+    let mut builder = warp::http::Response::builder()
+        .header("Accept-Ranges", "bytes");
+    if let Some(etag) = &stored.etag
+    {
+        builder = builder.header("ETag", etag);
+    }
+    if let Some(last_modified) = stored.last_modified
+    {
+        builder = builder.header("Last-Modified",
+                                 httpdate::fmt_http_date(last_modified));
+    }
+    // “If-Modified-Since” is ignored if “If-None-Match” is present.
+    let not_modified = match (&request.if_none_match, &stored.etag)
+    {
+        (Some(v), Some(etag)) => etagMatches(v, etag),
+        (Some(_), None) => false,
+        (None, _) => request.if_modified_since.zip(stored.last_modified)
+            .is_some_and(|(v, t)| notModifiedSince(&v, t)),
+    };
+    if not_modified
+    {
+        return builder.status(StatusCode::NOT_MODIFIED)
+            .body(warp::hyper::Body::empty())
+            .map_err(|e| rterr!("Failed to make response: {}", e));
+    }
+    builder = builder.header("Content-Type", content_type)
+        .header("Content-Length", stored.length);
+    builder = if let ByteRange::All = range
+    {
+        builder.status(StatusCode::OK)
+    }
+    else
+    {
+        builder.status(StatusCode::PARTIAL_CONTENT).header(
+            "Content-Range", format!(
+                "bytes {}-{}/{}", stored.start,
+                (stored.start + stored.length).saturating_sub(1),
+                stored.total))
+    };
+    builder.body(warp::hyper::Body::wrap_stream(readerStream(stored.reader)))
+        .map_err(|e| rterr!("Failed to make response: {}", e))
+}
+
+/// Find the video that owns the file at storage key “key”. Files of
+/// the HLS package are under “hls/<id>/”, and the other files are
+/// recorded with the video.
+fn findVideoOfKey(key: &str, data_manager: &data::Manager) ->
+    Result<Video, Error>
+{
+    let video = match key.strip_prefix("hls/").and_then(|k| k.split_once('/'))
+    {
+        Some((id, _)) => data_manager.findVideoByID(id)?,
+        None => data_manager.findVideoByFile(key)?,
+    };
+    video.ok_or_else(|| Error::HTTPStatus(
+        StatusCode::NOT_FOUND, format!("File {} not found", key)))
+}
+
+/// Serve a file of a video from the storage by its percent-encoded
+/// key, if the video is visible to “token”. Video files get the
+/// “Content-Disposition” of
+/// `Configuration::default_video_disposition`.
+async fn handleVideoFile(key: String, request: FileRequest,
+                         token: Option<String>, state: &AppState) ->
+    Result<Response, Error>
+{
+    let key = urlencoding::decode(&key).map_err(
+        |_| Error::HTTPStatus(StatusCode::NOT_FOUND,
+                              format!("Invalid path: {}", key)))?.into_owned();
+    let video = findVideoOfKey(&key, &state.data_manager)?;
+    checkVisibility(&video, &token, &state.data_manager, &state.config)?;
+    let is_video = Path::new(&key).extension().and_then(|e| e.to_str())
+        .and_then(ContainerType::fromExtension).is_some();
+    let mut res = serveFileWithRange(key, request, state.storage.clone())
+        .await?;
+    if is_video
+    {
+        res.headers_mut().insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&state.config.default_video_disposition)
+                .map_err(|e| rterr!("Invalid Content-Disposition: {}", e))?);
+    }
+    Ok(res)
+}
+
+/// Find the storage key of the file of video “id” for downloading,
+/// and the value of its “Content-Disposition” header. The file is
+/// named after the original upload, with the extension of the stored
+/// container, since the video may have been transcoded.
+fn downloadTarget(id: &str, token: &Option<String>,
+                  data_manager: &data::Manager, config: &Configuration) ->
+    Result<(String, String), Error>
+{
+    let video = findVideoOr404(id, data_manager)?;
+    checkVisibility(&video, token, data_manager, config)?;
+    let ext = video.container_type.toExtension();
+    let filename = if video.original_filename.is_empty()
+    {
+        format!("{}.{}", video.id, ext)
+    }
+    else
+    {
+        Path::new(&video.original_filename).with_extension(ext)
+            .to_string_lossy().into_owned()
+    };
+    Ok((storage::keyFromPath(&video.path)?,
+        format!("attachment; filename*=UTF-8''{}",
+                urlencoding::encode(&filename))))
+}
+
+/// Serve the file of a video as an attachment. See `downloadTarget`.
+async fn handleDownload(key: String, disposition: String,
+                        request: FileRequest, storage: Arc<dyn Storage>) ->
+    Result<Response, Error>
+{
+    let mut res = serveFileWithRange(key, request, storage).await?;
+    res.headers_mut().insert(
+        "Content-Disposition", disposition.parse().map_err(
+            |e| rterr!("Invalid Content-Disposition: {}", e))?);
+    Ok(res)
+}
+
+/// Find the storage key of the thumbnail of video “id”, which is
+/// served at “/thumb/<id>”. Unlike the path of the file, this URL
+/// does not change with the thumbnail format. The key is None if the
+/// video has no thumbnail.
+fn thumbnailKey(id: &str, token: &Option<String>,
+                data_manager: &data::Manager, config: &Configuration) ->
+    Result<Option<String>, Error>
+{
+    let video = findVideoOr404(id, data_manager)?;
+    checkVisibility(&video, token, data_manager, config)?;
+    video.thumbnail_path.as_deref().map(storage::keyFromPath).transpose()
+}
+
+/// The placeholder thumbnail of videos without one. See
+/// `Configuration::placeholderThumbnailPath`.
+fn placeholderThumbnail(config: &Configuration) -> Result<Response, Error>
+{
+    let path = config.placeholderThumbnailPath();
+    match std::fs::read(&path)
+    {
+        Ok(content) => Ok(warp::reply::with_header(
+            content, header::CONTENT_TYPE,
+            contentTypeFromKey(&path.to_string_lossy())).into_response()),
+        Err(_) => embeddedStaticFile(BUNDLED_IMAGE).map_err(
+            |_| rterr!("Bundled image {} not found", BUNDLED_IMAGE)),
+    }
+}
+
+fn createToken() -> String
+{
+    BASE64_NO_PAD.encode(rand::random::<i128>().to_ne_bytes())
+}
+
+fn uriFromStr(s: &str) -> Result<warp::http::uri::Uri, Error>
+{
+    s.parse::<warp::http::uri::Uri>().map_err(|_| rterr!("Invalid URI: {}", s))
+}
+
+/// Make the value of the “Set-Cookie” header for the session token.
+/// A cookie with an empty token and a zero “max_age” clears the
+/// session cookie.
+fn makeCookie(token: &str, max_age: u64, config: &Configuration) -> String
+{
+    // The cookie is only sent to the pages of this site, which are
+    // under “serve_under_path”.
+    let mut cookie = format!("{}={}; Max-Age={}; Path={}; SameSite={}",
+                             TOKEN_COOKIE, token, max_age,
+                             config.serve_under_path, config.cookie_same_site);
+    if let Some(domain) = &config.cookie_domain
+    {
+        cookie = cookie + "; Domain=" + domain;
+    }
+    if config.cookie_http_only
+    {
+        cookie += "; HttpOnly";
+    }
+    if config.cookieSecure()
+    {
+        cookie += "; Secure";
+    }
+    cookie
+}
+
+/// Check the credential of basic authentication. With user accounts,
+/// it must be one of the users and their password; otherwise it must
+/// be “user” and the site password. Return the logged-in username
+/// (empty for the site password), or None if the credential is
+/// wrong.
+fn checkCredential(credential: &str, data_manager: &data::Manager,
+                   config: &Configuration) -> Result<Option<String>, Error>
+{
+    if !data_manager.hasUsers()?
+    {
+        let expected = BASE64.encode(format!("user:{}", config.password));
+        return Ok((expected == credential).then(String::new));
+    }
+    let decoded = match BASE64.decode(credential).ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+    {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let (username, password) = match decoded.split_once(':')
+    {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    match data_manager.userPasswordHash(username)?
+    {
+        Some(hash) if verifyPassword(password, &hash) =>
+            Ok(Some(username.to_owned())),
+        _ => Ok(None),
+    }
+}
+
+/// Log in with basic authentication. Checking the credential hashes
+/// the password, so it runs on a blocking thread.
+async fn handleLogin(auth_value_maybe: Option<String>, state: &Arc<AppState>) ->
+    Result<Response, Error>
+{
+    let data_manager = &state.data_manager;
+    let config = &state.config;
+    if let Some(auth_value) = auth_value_maybe
+    {
+        if !auth_value.starts_with("Basic ")
+        {
+            return Err(Error::HTTPStatus(
+                StatusCode::UNAUTHORIZED,
+                "Not using basic authentication".to_owned()));
+        }
+        let username = {
+            let credential = auth_value[6..].to_owned();
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || checkCredential(
+                &credential, &state.data_manager, &state.config))
+        }.await.map_err(|e| rterr!("Failed to check credential: {}", e))??;
+        if let Some(username) = username
+        {
+            // Authentication is good.
+            let token = createToken();
+            data_manager.createSession(&token, &username)?;
+            return Ok(warp::reply::with_header(
+                warp::redirect::found(uriFromStr(&config.serve_under_path)?),
+                "Set-Cookie", makeCookie(&token, config.cookieMaxAge(), config))
+                      .into_response());
+        }
+        else
+        {
+            return Err(Error::HTTPStatus(
+                StatusCode::UNAUTHORIZED,
+                "Invalid credential".to_owned()));
+        }
+    }
+
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(warp::reply::reply(), StatusCode::UNAUTHORIZED),
+        "WWW-Authenticate",
+        r#"Basic realm="metube", charset="UTF-8""#).into_response())
+}
+
+/// The sections whose canonical path ends with a slash, like
+/// “/upload/”. Every other path is canonical without the trailing
+/// slash, like “/v/<id>”. This matches the paths from `urlFor`.
+const SLASHED_SECTIONS: &[&str] = &["upload", "login", "admin", "history",
+                                    "artist"];
+
+/// The canonical form of “path”, relative to the site root and
+/// without the leading slash, or None if it is canonical already. The
+/// routes accept both forms; this is only used to redirect when
+/// “normalize_trailing_slash” is on.
+fn canonicalPath(path: &str) -> Option<String>
+{
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty()
+    {
+        return None;
+    }
+    let canonical = if SLASHED_SECTIONS.contains(&trimmed)
+    {
+        format!("{}/", trimmed)
+    }
+    else
+    {
+        trimmed.to_owned()
+    };
+    if canonical == path
+    {
+        None
+    }
+    else
+    {
+        Some(canonical)
+    }
+}
+
+fn getTeraFuncArgs(args: &HashMap<String, tera::Value>, arg_name: &str) ->
+    tera::Result<String>
+{
+    let value = args.get(arg_name);
+    if value.is_none()
+    {
+        return Err(format!("Argument {} not found in function call.", arg_name)
+                   .into());
+    }
+    let value: String = tera::from_value(value.unwrap().clone())?;
+    Ok(value)
+}
+
+
+fn makeURLFor(serve_path: String) -> impl tera::Function
+{
+    move |args: &HashMap<String, tera::Value>| ->
+        tera::Result<tera::Value> {
+            let path_prefix = pathPrefix(&serve_path);
+            let name = getTeraFuncArgs(args, "name")?;
+            let arg = getTeraFuncArgs(args, "arg")?;
+            Ok(tera::to_value(path_prefix + &urlFor(&name, &arg)).unwrap())
+    }
+}
+
+/// A Tera filter that formats a Unix timestamp for display, like
+/// `{{ video.upload_time | local_time }}`.
+fn makeLocalTime(time_display: TimeDisplay) -> impl tera::Filter
+{
+    move |value: &tera::Value, _: &HashMap<String, tera::Value>| ->
+        tera::Result<tera::Value> {
+            let timestamp: i64 = tera::from_value(value.clone())?;
+            let t = time::OffsetDateTime::from_unix_timestamp(timestamp)
+                .map_err(|_| format!("Invalid timestamp: {}", timestamp))?;
+            let s = time_display.format(t)
+                .map_err(|e| format!("Failed to format time: {}", e))?;
+            Ok(tera::to_value(s).unwrap())
+    }
+}
+
+/// Report the rejections under /api as JSON errors. Every request
+/// under /api is answered by the API, so unknown paths are 404.
+async fn apiRejectionToJSON(rejection: warp::Rejection) ->
+    Result<Response, std::convert::Infallible>
+{
+    // Rejections of the request body come before “method not
+    // allowed”, which the routes for the other methods of the same
+    // path also reject with.
+    let (code, msg) = if rejection.is_not_found()
+    {
+        (StatusCode::NOT_FOUND, String::from("Not found"))
+    }
+    else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>()
+    {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>()
+    {
+        (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    }
+    else if let Some(e) =
+        rejection.find::<warp::reject::UnsupportedMediaType>()
+    {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<warp::reject::LengthRequired>()
+    {
+        (StatusCode::LENGTH_REQUIRED, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<warp::reject::MethodNotAllowed>()
+    {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>()
+    {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>()
+    {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<warp::reject::MissingHeader>()
+    {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<warp::cors::CorsForbidden>()
+    {
+        (StatusCode::FORBIDDEN, e.to_string())
+    }
+    else
+    {
+        log_error!("Unhandled rejection: {:?}", rejection);
+        (StatusCode::INTERNAL_SERVER_ERROR, String::from("Unhandled rejection"))
+    };
+    Ok(Error::HTTPStatus(code, msg).toJSONResponse())
+}
+
+/// Whether the client accepts the content “encoding”, according to
+/// the value of its Accept-Encoding header.
+fn acceptsEncoding(header: &str, encoding: &str) -> bool
+{
+    header.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let refused = parts.any(|param| {
+            param.trim().strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f64>().ok()) == Some(0.0)
+        });
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !refused
+    })
+}
+
+/// Compress the responses from “routes” with gzip or deflate, if the
+/// client accepts either of them.
+fn withCompression(routes: Route) -> Route
+{
+    // Not accepting an encoding is reported as “not found”, so that
+    // it does not take precedence over the rejections from the
+    // routes.
+    let accepts = |encoding: &'static str| {
+        warp::header::optional::<String>("accept-encoding").and_then(
+            move |value: Option<String>| async move {
+                if value.is_some_and(|v| acceptsEncoding(&v, encoding))
+                {
+                    Ok(())
+                }
+                else
+                {
+                    Err(warp::reject::not_found())
+                }
+            }).untuple_one()
+    };
+    let gzip = accepts("gzip").and(routes.clone())
+        .with(warp::compression::gzip()).map(Reply::into_response);
+    let deflate = accepts("deflate").and(routes.clone())
+        .with(warp::compression::deflate()).map(Reply::into_response);
+    // The header is appended, since some routes also vary on other
+    // headers.
+    gzip.or(deflate).unify().or(routes).unify()
+        .map(|mut res: Response| {
+            res.headers_mut().append(
+                header::VARY, HeaderValue::from_static("Accept-Encoding"));
+            res
+        }).boxed()
+}
+
+/// Assemble the Content-Security-Policy of HTML pages from the
+/// config. Inline styles are allowed because the video lists set
+/// thumbnails with the “style” attribute, and so is the origin of
+/// an absolute “custom_css_url”.
+fn makeCSP(config: &Configuration) -> String
+{
+    if config.frame_ancestors.is_empty()
+    {
+        makeCSPWithFrameAncestors(config, "'none'")
+    }
+    else
+    {
+        makeCSPWithFrameAncestors(config, &config.frame_ancestors.join(" "))
+    }
+}
+
+/// The CSP of the embeddable player, which any site can frame.
+fn makeEmbedCSP(config: &Configuration) -> String
+{
+    makeCSPWithFrameAncestors(config, "*")
+}
+
+fn makeCSPWithFrameAncestors(config: &Configuration, frame_ancestors: &str) ->
+    String
+{
+    let sources = std::iter::once("'self'")
+        .chain(config.csp_extra_sources.iter().map(|s| s.as_str()))
+        .collect::<Vec<_>>().join(" ");
+    let style_sources = match config.customCSSOrigin()
+    {
+        Some(origin) => format!("{} {}", sources, origin),
+        None => sources.clone(),
+    };
+    format!("default-src {sources}; img-src {sources} data:; \
+             style-src {style_sources} 'unsafe-inline'; object-src 'none'; \
+             base-uri 'self'; form-action 'self'; \
+             frame-ancestors {frame_ancestors}")
+}
+
+/// Add security headers to a response. Every response gets
+/// “X-Content-Type-Options”, and HTML pages also get the CSP and the
+/// referrer policy. Videos, thumbnails and other files are exempt
+/// from the CSP. A CSP already set by the handler is kept.
+fn addSecurityHeaders(mut res: Response, csp: &HeaderValue,
+                      referrer_policy: &HeaderValue) -> Response
+{
+    let is_html = res.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    let headers = res.headers_mut();
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS,
+                   HeaderValue::from_static("nosniff"));
+    if is_html
+    {
+        if !headers.contains_key(header::CONTENT_SECURITY_POLICY)
+        {
+            headers.insert(header::CONTENT_SECURITY_POLICY, csp.clone());
+        }
+        headers.insert(header::REFERRER_POLICY, referrer_policy.clone());
+    }
+    res
+}
+
+/// Load the templates in “dir”, falling back to the embedded ones
+/// for those not found there. “dir” does not have to exist.
+fn loadTemplates(dir: &Path, serve_path: &str, time_display: &TimeDisplay) ->
+    Result<Tera, Error>
+{
+    let mut tera = if dir.is_dir()
+    {
+        let glob = dir.canonicalize()
+            .map_err(|_| rterr!("Invalid template dir"))?
             .join("**").join("*");
-        info!("Template dir is {}", template_path.display());
-        let template_dir = template_path.to_str().ok_or_else(
-                || rterr!("Invalid template path"))?;
-        self.templates = Tera::new(template_dir).map_err(
-            |e| rterr!("Failed to compile templates: {}", e))?;
-        self.templates.register_function(
-            "url_for", makeURLFor(self.config.serve_under_path.clone()));
+        let glob = glob.to_str().ok_or_else(
+            || rterr!("Invalid template path"))?;
+        Tera::new(glob).map_err(
+            |e| rterr!("Failed to compile templates: {}", e))?
+    }
+    else
+    {
+        Tera::default()
+    };
+    let mut embedded = Tera::default();
+    embedded.add_raw_templates(embedded::templates()).map_err(
+        |e| rterr!("Failed to compile embedded templates: {}", e))?;
+    // Templates already loaded from “dir” are kept.
+    tera.extend(&embedded).map_err(
+        |e| rterr!("Failed to add embedded templates: {}", e))?;
+    tera.register_function("url_for", makeURLFor(serve_path.to_owned()));
+    tera.register_filter("local_time", makeLocalTime(time_display.clone()));
+    Ok(tera)
+}
+
+/// The compiled templates. In development mode they are reloaded from
+/// disk for every page, so that edits to the templates show up
+/// without restarting the server.
+#[derive(Clone)]
+struct Templates
+{
+    tera: Arc<RwLock<Tera>>,
+    reload: bool,
+    dir: PathBuf,
+    serve_path: String,
+    time_display: TimeDisplay,
+}
+
+impl Templates
+{
+    fn get(&self) -> RwLockReadGuard<'_, Tera>
+    {
+        if self.reload
+        {
+            match loadTemplates(&self.dir, &self.serve_path,
+                                &self.time_display)
+            {
+                Ok(tera) => *self.tera.write().unwrap() = tera,
+                Err(e) => log_error!("Failed to reload templates: {}", e),
+            }
+        }
+        self.tera.read().unwrap()
+    }
+}
+
+/// Serve the embedded static file at “path”, relative to the static
+/// dir.
+fn embeddedStaticFile(path: &str) -> Result<Response, warp::Rejection>
+{
+    let content = embedded::staticFile(path).ok_or_else(
+        warp::reject::not_found)?;
+    Ok(warp::reply::with_header(
+        content, header::CONTENT_TYPE, contentTypeFromKey(path))
+       .into_response())
+}
+
+/// Listen on the Unix socket at “path”. A stale socket left by a
+/// previous run is removed first. The socket is readable and writable
+/// by the owner and the group, so that a reverse proxy in the group
+/// can connect to it.
+fn bindUnixSocket(path: &Path) -> Result<tokio::net::UnixListener, Error>
+{
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(meta) = std::fs::symlink_metadata(path)
+    {
+        if !meta.file_type().is_socket()
+        {
+            return Err(rterr!("{} exists and is not a socket",
+                              path.display()));
+        }
+        std::fs::remove_file(path).map_err(
+            |e| rterr!("Failed to remove stale socket {}: {}",
+                       path.display(), e))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path).map_err(
+        |e| rterr!("Failed to bind to {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))
+        .map_err(|e| rterr!("Failed to set permissions of {}: {}",
+                            path.display(), e))?;
+    Ok(listener)
+}
+
+/// A route that matches nothing, in place of a disabled feature.
+fn disabledRoute() -> Route
+{
+    warp::any().and_then(|| async {
+        Err::<Response, _>(warp::reject::not_found())
+    }).boxed()
+}
+
+/// What the handlers share. Routes get it with `withState`.
+struct AppState
+{
+    data_manager: data::Manager,
+    templates: Templates,
+    storage: Arc<dyn Storage>,
+    metrics: Arc<Metrics>,
+    progress: Arc<UploadProgress>,
+    upload_locks: Arc<UploadLocks>,
+    /// One permit for each multipart upload being received. See
+    /// “max_concurrent_uploads”.
+    upload_slots: Arc<Semaphore>,
+    queue: ProcessingQueue,
+    config: Configuration,
+}
+
+/// Check that files can be created in “dir”, by creating and
+/// removing a temp file. “name” is the name of the option of the
+/// directory, for the error message.
+fn checkWritable(dir: &str, name: &str) -> Result<(), Error>
+{
+    let path = randomTempFilename(dir);
+    std::fs::File::create(&path).map_err(
+        |e| rterr!("{} {:?} is not writable: {}", name, dir, e))?;
+    std::fs::remove_file(&path).map_err(
+        |e| rterr!("Failed to remove {:?} from {} {:?}: {}", path, name, dir,
+                   e))
+}
+
+pub struct App
+{
+    data_manager: data::Manager,
+    templates: Templates,
+    storage: Arc<dyn Storage>,
+    metrics: Arc<Metrics>,
+    progress: Arc<UploadProgress>,
+    upload_locks: Arc<UploadLocks>,
+    /// One permit for each multipart upload being received. See
+    /// “max_concurrent_uploads”.
+    upload_slots: Arc<Semaphore>,
+    config: Configuration,
+}
+
+impl App
+{
+    pub fn new(config: Configuration) -> Result<Self, Error>
+    {
+        config.validate()?;
+        // Uploads are received in the video directory, even with a
+        // remote storage.
+        checkWritable(&config.video_dir, "video_dir")?;
+        checkWritable(&config.data_dir, "data_dir")?;
+        let mut result = Self {
+            data_manager: data::Manager::newWithFilename(
+                config.databasePath()),
+            templates: Templates {
+                tera: Arc::new(RwLock::new(Tera::default())),
+                reload: false,
+                dir: PathBuf::new(),
+                serve_path: String::new(),
+                time_display: TimeDisplay::default(),
+            },
+            storage: storage::fromConfig(&config),
+            metrics: Arc::new(Metrics::new()),
+            progress: Arc::new(UploadProgress::new()),
+            upload_locks: Arc::new(UploadLocks::new()),
+            upload_slots: Arc::new(Semaphore::new(
+                config.max_concurrent_uploads)),
+            config,
+        };
+        result.init()?;
+        Ok(result)
+    }
+
+    fn init(&mut self) -> Result<(), Error>
+    {
+        self.data_manager.connect()?;
+        self.data_manager.init()?;
+        let template_dir = PathBuf::from(&self.config.data_dir)
+            .join("templates");
+        info!("Template dir is {}", template_dir.display());
+        let time_display = self.config.timeDisplay();
+        let tera = loadTemplates(&template_dir,
+                                 &self.config.serve_under_path,
+                                 &time_display)?;
+        self.templates = Templates {
+            tera: Arc::new(RwLock::new(tera)),
+            reload: self.config.dev_mode,
+            dir: template_dir,
+            serve_path: self.config.serve_under_path.clone(),
+            time_display,
+        };
+        if self.config.dev_mode
+        {
+            info!("Running in development mode. Templates are reloaded \
+                   for every page.");
+        }
+        else
+        {
+            info!("Running in production mode.");
+        }
         Ok(())
     }
 
-    pub async fn serve(self) -> Result<(), Error>
+    /// Build the routes of the site, and the route of “/metrics”,
+    /// which is also served on its own port if configured so.
+    fn routes(&self, processing_queue: &ProcessingQueue) ->
+        Result<(Route, Route), Error>
     {
         let static_dir = PathBuf::from(&self.config.static_dir);
         info!("Static dir is {}", static_dir.display());
+        // Files in the static dir take precedence over the embedded
+        // ones.
         let statics = warp::get().and(warp::path("static"))
-            .and(warp::fs::dir(static_dir));
-        let statics = statics.or(warp::get().and(warp::path("video")).and(
-            warp::fs::dir(PathBuf::from(&self.config.video_dir))));
-
-        let data_manager = self.data_manager.clone();
-        let temp = self.templates.clone();
-        let config = self.config.clone();
-        let index = warp::get().and(warp::path::end()).map(move || {
-            handleIndex(&data_manager, &temp, &config).toResponse()
+            .and(warp::fs::dir(static_dir).map(Reply::into_response)
+                 .or(warp::path::tail().and_then(|tail: warp::path::Tail| async move {
+                     embeddedStaticFile(tail.as_str())
+                 })).unify());
+        let state = Arc::new(AppState {
+            data_manager: self.data_manager.clone(),
+            templates: self.templates.clone(),
+            storage: self.storage.clone(),
+            metrics: self.metrics.clone(),
+            progress: self.progress.clone(),
+            upload_locks: self.upload_locks.clone(),
+            upload_slots: self.upload_slots.clone(),
+            queue: processing_queue.clone(),
+            config: self.config.clone(),
+        });
+        // HEAD is answered like GET; hyper leaves out the body.
+        let video_file = warp::get().or(warp::head()).unify()
+            .and(warp::path("video"))
+            .and(warp::path::tail())
+            .and(fileRequest())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .then(|tail: warp::path::Tail, request: FileRequest,
+                  token: Option<String>, state: Arc<AppState>| async move {
+                handleVideoFile(tail.as_str().to_owned(), request, token,
+                                &state).await.toResponse()
+            });
+        let download = warp::get().or(warp::head()).unify()
+            .and(warp::path("download")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(fileRequest())
+            .and(withState(&state))
+            .then(|id: String, token: Option<String>, request: FileRequest,
+                  state: Arc<AppState>| async move {
+                match downloadTarget(&id, &token, &state.data_manager,
+                                     &state.config)
+                {
+                    Ok((key, disposition)) => handleDownload(
+                        key, disposition, request, state.storage.clone())
+                        .await,
+                    Err(e) => Err(e),
+                }.toResponse()
+            });
+        let thumb = warp::get().or(warp::head()).unify()
+            .and(warp::path("thumb")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(fileRequest())
+            .and(withState(&state))
+            .then(|id: String, token: Option<String>, request: FileRequest,
+                  state: Arc<AppState>| async move {
+                match thumbnailKey(&id, &token, &state.data_manager,
+                                   &state.config)
+                {
+                    Ok(Some(key)) => serveFileWithRange(
+                        key, request, state.storage.clone()).await,
+                    Ok(None) => placeholderThumbnail(&state.config),
+                    Err(e) => Err(e),
+                }.toResponse()
+            });
+        let statics = statics.or(video_file).or(download).or(thumb);
+        let favicon = warp::get().and(warp::path("favicon.ico"))
+            .and(warp::path::end())
+            .and(warp::fs::file(self.config.faviconPath())
+                 .map(Reply::into_response)
+                 .or(warp::any().and_then(|| async {
+                     embeddedStaticFile(BUNDLED_IMAGE)
+                 })).unify());
+        let logo = warp::get().and(warp::path("logo")).and(warp::path::end())
+            .and(warp::fs::file(self.config.logoPath())
+                 .map(Reply::into_response)
+                 .or(warp::any().and_then(|| async {
+                     embeddedStaticFile(BUNDLED_IMAGE)
+                 })).unify());
+        let statics = statics.or(favicon).or(logo);
+
+        let index = warp::get().and(warp::path::end())
+            .and(warp::query::<ListingQuery>())
+            .and(warp::header::optional::<String>("Accept"))
+            .and(withState(&state))
+            .map(|q: ListingQuery, accept: Option<String>,
+                  state: Arc<AppState>| {
+            let temp = state.templates.get();
+            let mut res = handleIndex(q, accept, &state.data_manager, &temp,
+                                      &state.config)
+                .toHTMLResponse(&temp, &state.config);
+            res.headers_mut().append(header::VARY,
+                                     HeaderValue::from_static("Accept"));
+            res
         });
 
-        let data_manager = self.data_manager.clone();
-        let temp = self.templates.clone();
-        let config = self.config.clone();
         let video = warp::get().and(warp::path("v")).and(warp::path::param())
-            .and(warp::path::end()).map(move |id: String| {
-            handleVideo(id, &data_manager, &temp, &config).toResponse()
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+            let temp = state.templates.get();
+            handleVideo(id, token, &state.data_manager, &state.metrics, &temp,
+                        &state.config)
+                .toHTMLResponse(&temp, &state.config)
+        });
+
+        let embed = warp::get().and(warp::path("embed"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+                let temp = state.templates.get();
+                handleEmbed(id, token, &state.data_manager, &state.metrics,
+                            &temp, &state.config)
+                    .unwrap_or_else(|e| htmlErrorResponse(e, &temp,
+                                                          &state.config))
+            });
+
+        // The videos without an artist are at “/artist/”.
+        let artist = warp::get().and(warp::path("artist"))
+            .and(warp::path::param().or(warp::any().map(String::new)).unify())
+            .and(warp::path::end())
+            .and(warp::query::<PageQuery>())
+            .and(withState(&state))
+            .map(|name: String, q: PageQuery, state: Arc<AppState>| {
+            let temp = state.templates.get();
+            handleArtist(name, q, &state.data_manager, &temp, &state.config)
+                .toHTMLResponse(&temp, &state.config)
+        });
+
+        let history = warp::get().and(warp::path("history"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .map(|token: Option<String>, state: Arc<AppState>| {
+            let temp = state.templates.get();
+            handleHistory(token, &state.data_manager, &temp, &state.config)
+                .toHTMLResponse(&temp, &state.config)
         });
 
-        let temp = self.templates.clone();
-        let data_manager = self.data_manager.clone();
-        let config = self.config.clone();
+        let clear_history = warp::post().and(warp::path("history"))
+            .and(warp::path("clear")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .map(|token: Option<String>, state: Arc<AppState>| {
+                handleClearHistory(token, &state.data_manager, &state.config)
+                    .toResponse()
+            });
+
+        let pin = warp::post().and(warp::path("pin"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+                handlePin(id, token, &state.data_manager, &state.config)
+                    .toResponse()
+            });
+
+        let video_json = warp::get()
+            .and(warp::path("video")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(authToken()).and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+                handleVideoJSON(id, token, &state.data_manager, &state.config)
+                    .toJSONResponse()
+            });
+
+        let patch_video = warp::patch()
+            .and(warp::path("video")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::body::content_length_limit(1024 * 1024))
+            .and(warp::body::json())
+            .and(authToken()).and(withState(&state))
+            .map(|id: String, body: VideoPatchBody, token: Option<String>,
+                  state: Arc<AppState>| {
+                handlePatchVideo(id, body, token, &state.data_manager,
+                                 &state.config).toJSONResponse()
+            });
+
+        let views_json = warp::get()
+            .and(warp::path("video")).and(warp::path::param())
+            .and(warp::path("views")).and(warp::path::end())
+            .and(authToken()).and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+                handleViewsJSON(id, token, &state.data_manager, &state.config)
+                    .toJSONResponse()
+            });
+
+        let probe_json = warp::get()
+            .and(warp::path("video")).and(warp::path::param())
+            .and(warp::path("probe")).and(warp::path::end())
+            .and(authToken()).and(withState(&state))
+            .then(|id: String, token: Option<String>,
+                  state: Arc<AppState>| async move {
+                handleProbeJSON(id, token, &state.data_manager, &state.config)
+                    .await.toJSONResponse()
+            });
+
+        let stats_json = warp::get().and(warp::path("stats"))
+            .and(warp::path::end())
+            .and(authToken()).and(withState(&state))
+            .map(|token: Option<String>, state: Arc<AppState>| {
+                handleStatsJSON(token, &state.data_manager, &state.config)
+                    .toJSONResponse()
+            });
+
+        let videos_json = warp::get().and(warp::path("videos"))
+            .and(warp::path::end()).and(warp::query::<ListingQuery>())
+            .and(withState(&state))
+            .map(|q: ListingQuery, state: Arc<AppState>| {
+                handleVideosJSON(q, &state.data_manager, &state.config)
+                    .toJSONResponse()
+            });
+
+        let get_position = warp::get()
+            .and(warp::path("position")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(authToken()).and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+                handleGetPosition(id, token, &state.data_manager, &state.config)
+                    .toJSONResponse()
+            });
+
+        let set_position = warp::post()
+            .and(warp::path("position")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::body::content_length_limit(1024))
+            .and(warp::body::json())
+            .and(authToken()).and(withState(&state))
+            .map(|id: String, body: PositionBody, token: Option<String>,
+                  state: Arc<AppState>| {
+                handleSetPosition(id, body, token, &state.data_manager,
+                                  &state.config).toJSONResponse()
+            });
+
+        // Routes under /api. The “api” path segment is matched
+        // outside of the CORS wrapper, so that preflight requests to
+        // other paths are not answered.
+        let api = video_json.or(patch_video).unify()
+            .or(views_json).unify().or(probe_json).unify()
+            .or(videos_json).unify()
+            .or(stats_json).unify()
+            .or(get_position).unify().or(set_position).unify();
+        let api = if self.config.allowed_origins.is_empty()
+        {
+            api.boxed()
+        }
+        else
+        {
+            let cors = warp::cors()
+                .allow_origins(self.config.allowed_origins.iter()
+                               .map(|s| s.as_str()))
+                .allow_methods(["GET", "POST", "PATCH", "DELETE"])
+                .allow_headers(["Authorization", "Content-Type"]);
+            api.with(cors).map(Reply::into_response)
+                .boxed()
+        };
+        let api = warp::path("api").and(
+            api.recover(apiRejectionToJSON).unify());
+
+        let sitemap = warp::get().and(warp::path("sitemap.xml"))
+            .and(warp::path::end()).and(withState(&state))
+            .map(|state: Arc<AppState>| {
+                handleSitemap(&state.data_manager, &state.config).toResponse()
+            });
+
+        let feed = warp::get().and(warp::path("feed.xml"))
+            .and(warp::path::end()).and(withState(&state))
+            .map(|state: Arc<AppState>| {
+                handleFeed(&state.data_manager, &state.config).toResponse()
+            });
+
         let upload_page = warp::get().and(warp::path("upload"))
             .and(warp::path::end())
-            .and(warp::filters::cookie::optional(TOKEN_COOKIE)).map(
-                move |token: Option<String>|
-                handleUploadPage(&data_manager, &temp, &config, token)
-                    .toResponse());
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .map(|token: Option<String>, state: Arc<AppState>| {
+                let temp = state.templates.get();
+                handleUploadPage(&state.data_manager, &temp, &state.config,
+                                 token)
+                    .toHTMLResponse(&temp, &state.config)
+            });
 
-        let config = self.config.clone();
-        let data_manager = self.data_manager.clone();
         let upload = warp::post().and(warp::path("upload"))
             .and(warp::path::end())
-            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(authToken())
+            .and(warp::addr::remote())
+            .and(warp::header::optional::<u64>("content-length"))
             .and(warp::multipart::form().max_length(self.config.upload_size_max))
-            .and_then(move |token: Option<String>, data: warp::multipart::FormData| {
-                let config = config.clone();
-                let data_manager = data_manager.clone();
-                async move {
-                    handleUpload(token, data, &data_manager, &config).await
-                }
+            .and(withState(&state))
+            .then(|token: Option<String>,
+                  remote: Option<std::net::SocketAddr>,
+                  content_length: Option<u64>,
+                  form_data: warp::multipart::FormData,
+                  state: Arc<AppState>| async move {
+                let request = UploadRequest {
+                    remote, form_data, content_length };
+                handleUpload(token, request, &state).await.toResponse()
+            });
+
+        let upload_url = warp::post().and(warp::path("upload-url"))
+            .and(warp::path::end())
+            .and(authToken())
+            .and(warp::addr::remote())
+            .and(warp::body::content_length_limit(64 * 1024))
+            .and(warp::body::json::<URLUploadForm>().or(
+                warp::body::form::<URLUploadForm>()).unify())
+            .and(withState(&state))
+            .then(|token: Option<String>,
+                  remote: Option<std::net::SocketAddr>, form: URLUploadForm,
+                  state: Arc<AppState>| async move {
+                let request = URLUploadRequest { remote, form };
+                handleUploadURL(token, request, &state).await.toResponse()
+            });
+
+        let upload_progress = warp::get().and(warp::path("upload"))
+            .and(warp::path("progress")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(authToken()).and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+                handleUploadProgress(id, token, &state.data_manager,
+                                     state.progress.clone(), &state.config)
+                    .toResponse()
+            });
+
+        let admin = warp::get().and(warp::path("admin"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .then(|token: Option<String>, state: Arc<AppState>| async move {
+                let html = handleAdmin(token, &state).await;
+                html.toHTMLResponse(&state.templates.get(), &state.config)
+            });
+
+        let delete_videos = warp::post().and(warp::path("admin"))
+            .and(warp::path("delete")).and(warp::path::end())
+            .and(warp::body::content_length_limit(1024 * 1024))
+            .and(warp::body::json::<DeleteRequest>().or(
+                warp::body::form::<DeleteForm>().map(DeleteRequest::from))
+                 .unify())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .map(|request: DeleteRequest, token: Option<String>,
+                  state: Arc<AppState>| {
+                handleDeleteVideos(request, token, &state.data_manager,
+                                   state.storage.as_ref(), &state.config)
+                    .toJSONResponse()
+            });
+
+        let set_thumbnail = warp::post().and(warp::path("thumbnail"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(warp::multipart::form().max_length(THUMBNAIL_SIZE_MAX))
+            .and(withState(&state))
+            .then(|id: String, token: Option<String>,
+                  f: warp::multipart::FormData,
+                  state: Arc<AppState>| async move {
+                handleSetThumbnail(id, token, f, &state.data_manager,
+                                   state.storage.as_ref(), &state.config)
+                    .await.toResponse()
+            });
+
+        // Fixing deletes videos, so it is only done on POST.
+        let verify = warp::get().map(|| false)
+            .or(warp::post().map(|| true)).unify()
+            .and(warp::path("admin"))
+            .and(warp::path("verify")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(withState(&state))
+            .then(|fix: bool, token: Option<String>,
+                   state: Arc<AppState>| async move {
+                let html = handleVerify(fix, token, &state).await;
+                html.toHTMLResponse(&state.templates.get(), &state.config)
+            });
+
+        let create_upload = warp::post().and(warp::path("uploads"))
+            .and(warp::path::end())
+            .and(authToken())
+            .and(warp::addr::remote())
+            .and(warp::header::<u64>("Upload-Length"))
+            .and(warp::header::optional::<String>("Upload-Metadata"))
+            .and(withState(&state))
+            .map(|token: Option<String>,
+                  remote: Option<std::net::SocketAddr>, length: u64,
+                  metadata: Option<String>, state: Arc<AppState>| {
+                handleCreateUpload(token, remote, length, metadata,
+                                   &state.data_manager, &state.config)
+                    .toResponse()
+            });
+
+        let upload_offset = warp::head().and(warp::path("uploads"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(authToken()).and(withState(&state))
+            .map(|id: String, token: Option<String>, state: Arc<AppState>| {
+                handleUploadOffset(id, token, &state.data_manager,
+                                   &state.config).toResponse()
+            });
+
+        let upload_patch = warp::patch().and(warp::path("uploads"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(authToken())
+            .and(warp::header::<u64>("Upload-Offset"))
+            .and(warp::body::stream())
+            .and(withState(&state))
+            .then(|id: String, token: Option<String>, offset: u64, body,
+                  state: Arc<AppState>| async move {
+                handleUploadPatch(id, token, offset, body, &state)
+                    .await.toResponse()
             });
 
-        let config = self.config.clone();
-        let data_manager = self.data_manager.clone();
         let login = warp::get().and(warp::path("login")).and(warp::path::end())
             .and(warp::header::optional::<String>("Authorization"))
-            .map(move |auth_value: Option<String>| {
-                handleLogin(auth_value, &data_manager, &config).toResponse()
+            .and(withState(&state))
+            .then(|auth_value: Option<String>, state: Arc<AppState>| async move {
+                handleLogin(auth_value, &state).await.toResponse()
             });
 
+        // The HTML and JSON pages. These can be compressed.
+        let pages = index.or(video).unify().or(embed).unify()
+            .or(artist).unify()
+            .or(history).unify().or(api).unify()
+            .or(sitemap).unify().or(feed).unify().or(admin).unify().or(verify).unify()
+            .boxed();
+        let pages = if self.config.uploads_enabled
+        {
+            pages.or(upload_page).unify().boxed()
+        }
+        else
+        {
+            pages
+        };
+        let pages = if self.config.enable_compression
+        {
+            withCompression(pages)
+        }
+        else
+        {
+            pages
+        };
+
+        let csp = HeaderValue::from_str(&makeCSP(&self.config)).map_err(
+            |_| rterr!("Invalid csp_extra_sources or frame_ancestors"))?;
+        let referrer_policy = HeaderValue::from_str(
+            &self.config.referrer_policy).map_err(
+            |_| rterr!("Invalid referrer_policy: {}",
+                       self.config.referrer_policy))?;
+        // Without uploads, the upload routes are left out, and their
+        // paths are reported as not found.
+        let uploads = if self.config.uploads_enabled
+        {
+            upload.or(upload_url).or(upload_progress).or(create_upload)
+                .or(upload_offset)
+                .or(upload_patch)
+                .map(Reply::into_response).boxed()
+        }
+        else
+        {
+            warp::path("upload").or(warp::path("uploads")).unify()
+                .or(warp::path("upload-url")).unify()
+                .and(withState(&state)).map(|state: Arc<AppState>| {
+                    htmlErrorResponse(Error::HTTPStatus(
+                        StatusCode::NOT_FOUND,
+                        String::from("Uploads are disabled")),
+                                      &state.templates.get(), &state.config)
+                }).boxed()
+        };
+
+        let metrics_route = warp::get().and(warp::path("metrics"))
+            .and(warp::path::end()).and(withState(&state))
+            .map(|state: Arc<AppState>| {
+                handleMetrics(&state.data_manager, &state.metrics).toResponse()
+            }).boxed();
+        // With a separate port, “/metrics” is only served there.
+        let metrics_page = if self.config.metrics_enabled &&
+            self.config.metrics_listen_port.is_none()
+        {
+            metrics_route.clone()
+        }
+        else
+        {
+            disabledRoute()
+        };
+
+        let hls = if self.config.enable_hls
+        {
+            warp::get().and(warp::path("hls")).and(warp::path::param())
+                .and(warp::path::param()).and(warp::path::end())
+                .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+                .and(withState(&state))
+                .then(|id: String, file: String, token: Option<String>,
+                       state: Arc<AppState>| async move {
+                    handleHLS(id, file, token, &state.data_manager,
+                              &state.config).await.toResponse()
+                }).boxed()
+        }
+        else
+        {
+            disabledRoute()
+        };
+
+        // Permanently redirect GET and HEAD requests from the
+        // non-canonical form of a path to the canonical one.
+        let canonical_redirect = if self.config.normalize_trailing_slash
+        {
+            let serve_path = self.config.serve_under_path.clone();
+            warp::get().or(warp::head()).unify().and(warp::path::tail())
+                .and(warp::query::raw().or(warp::any().map(String::new))
+                     .unify())
+                .and_then(move |tail: warp::path::Tail, query: String| {
+                    let location = canonicalPath(tail.as_str()).map(
+                        |path| {
+                            let mut location = format!(
+                                "{}/{}", pathPrefix(&serve_path), path);
+                            if !query.is_empty()
+                            {
+                                location = location + "?" + &query;
+                            }
+                            location
+                        });
+                    async move {
+                        match location
+                        {
+                            Some(location) => Ok(uriFromStr(&location).map(
+                                |uri| warp::redirect::permanent(uri)
+                                    .into_response()).toResponse()),
+                            None => Err(warp::reject::not_found()),
+                        }
+                    }
+                }).boxed()
+        }
+        else
+        {
+            disabledRoute()
+        };
+
+        let routes = canonical_redirect.or(statics).or(pages).or(uploads)
+            .or(login)
+            .or(clear_history).or(pin).or(delete_videos).or(set_thumbnail)
+            .or(metrics_page).or(hls).map(move |r| {
+                addSecurityHeaders(Reply::into_response(r), &csp,
+                                   &referrer_policy)
+            });
         let route = if self.config.serve_under_path == String::from("/") ||
             self.config.serve_under_path.is_empty()
         {
-            statics.or(index).or(video).or(upload_page).or(upload).or(login)
-                .boxed()
+            routes.boxed()
         }
         else
         {
@@ -376,20 +3152,370 @@ impl App
             {
                 r = r.and(warp::path(seg.to_owned())).boxed();
             }
-            r.and(statics.or(index).or(video).or(upload_page).or(upload)
-                  .or(login))
-                .boxed()
+            r.and(routes).boxed()
+        };
+        // Crawlers only look for “/robots.txt” at the root of the
+        // host, so it is served there even under a subpath.
+        let robots_txt = self.config.site_info.robots.clone();
+        let robots = warp::get().and(warp::path("robots.txt"))
+            .and(warp::path::end()).map(move || {
+                warp::reply::with_header(
+                    robots_txt.clone(), header::CONTENT_TYPE,
+                    "text/plain; charset=utf-8").into_response()
+            });
+        Ok((robots.or(route).unify().boxed(), metrics_route))
+    }
+
+    pub async fn serve(self) -> Result<(), Error>
+    {
+        let processing_queue = ProcessingQueue::start(
+            self.config.clone(), self.storage.clone(),
+            self.data_manager.clone());
+        let (route, metrics_route) = self.routes(&processing_queue)?;
+
+        if self.config.regenerate_stale_thumbnails
+        {
+            let config = self.config.clone();
+            let data_manager = self.data_manager.clone();
+            let storage = self.storage.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = maintenance::regenerateStaleThumbnails(
+                    &config, &data_manager, storage.as_ref())
+                {
+                    log_error!("Failed to regenerate thumbnails: {}", e);
+                }
+            });
+        }
+
+        {
+            let config = self.config.clone();
+            let data_manager = self.data_manager.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(
+                    std::time::Duration::from_secs(3600));
+                loop
+                {
+                    interval.tick().await;
+                    let config = config.clone();
+                    let data_manager = data_manager.clone();
+                    let result = tokio::task::spawn_blocking(move || {
+                        maintenance::expirePendingUploads(&config,
+                                                          &data_manager)
+                    }).await;
+                    match result
+                    {
+                        Ok(Ok(_)) => {},
+                        Ok(Err(e)) => log_error!(
+                            "Failed to expire uploads: {}", e),
+                        Err(e) => log_error!("Failed to expire uploads: {}", e),
+                    }
+                }
+            });
+        }
+
+        if let Some(days) = self.config.retention_days
+        {
+            info!("Videos older than {} days are pruned.", days);
+            let config = self.config.clone();
+            let data_manager = self.data_manager.clone();
+            let storage = self.storage.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(
+                    std::time::Duration::from_secs(3600));
+                loop
+                {
+                    interval.tick().await;
+                    let config = config.clone();
+                    let data_manager = data_manager.clone();
+                    let storage = storage.clone();
+                    let result = tokio::task::spawn_blocking(move || {
+                        maintenance::pruneOldVideos(
+                            &config, &data_manager, storage.as_ref())
+                    }).await;
+                    match result
+                    {
+                        Ok(Ok(_)) => {},
+                        Ok(Err(e)) => log_error!("Failed to prune videos: {}", e),
+                        Err(e) => log_error!("Failed to prune videos: {}", e),
+                    }
+                }
+            });
+        }
+
+        // Metrics on a separate port are served on localhost when
+        // the main site is on a Unix socket.
+        let address: std::net::IpAddr = match self.config.unixSocketPath()
+        {
+            Some(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            None => self.config.listen_address.parse().map_err(
+                |_| rterr!("Invalid listen address: {}",
+                           self.config.listen_address))?,
         };
+        if let (true, Some(port)) = (self.config.metrics_enabled,
+                                     self.config.metrics_listen_port)
+        {
+            info!("Serving metrics at {}:{}...", address, port);
+            tokio::spawn(warp::serve(metrics_route).run(
+                std::net::SocketAddr::new(address, port)));
+        }
+
+        if let Some(path) = self.config.unixSocketPath()
+        {
+            let listener = bindUnixSocket(Path::new(path))?;
+            info!("Listening at {}...", self.config.listen_address);
+            let incoming = futures_util::stream::unfold(
+                listener, |listener| async move {
+                    let conn = listener.accept().await.map(|(s, _)| s);
+                    Some((conn, listener))
+                });
+            warp::serve(route).run_incoming(incoming).await;
+            return Ok(());
+        }
 
         info!("Listening at {}:{}...", self.config.listen_address,
               self.config.listen_port);
 
         warp::serve(route).run(
-            std::net::SocketAddr::new(
-                self.config.listen_address.parse().map_err(
-                    |_| rterr!("Invalid listen address: {}",
-                               self.config.listen_address))?,
-                self.config.listen_port)).await;
+            std::net::SocketAddr::new(address, self.config.listen_port)).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn testPrefersJSON()
+    {
+        assert!(prefersJSON("application/json"));
+        assert!(prefersJSON("application/json, text/html;q=0.5"));
+        // What browsers send.
+        assert!(!prefersJSON("text/html,application/xhtml+xml,\
+                              application/xml;q=0.9,*/*;q=0.8"));
+        assert!(!prefersJSON("application/json, */*"));
+        assert!(!prefersJSON("application/json;q=0"));
+        assert!(!prefersJSON("*/*"));
+    }
+
+    #[test]
+    fn testMakeCSP()
+    {
+        let mut config = Configuration::default();
+        assert!(makeCSP(&config).contains("style-src 'self' 'unsafe-inline';"));
+        config.site_info.custom_css_url =
+            Some(String::from("https://cdn.example.org/site.css"));
+        assert!(makeCSP(&config).contains(
+            "style-src 'self' https://cdn.example.org 'unsafe-inline';"));
+        assert!(makeCSP(&config).contains("default-src 'self';"));
+    }
+
+    #[test]
+    fn testFeed() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let mut video = Video::new(String::from("a"), String::from("a.mp4"));
+        video.title = String::from("Tom & Jerry");
+        data_manager.addVideo(&video)?;
+        let mut private = Video::new(String::from("b"), String::from("b.mp4"));
+        private.visibility = Visibility::Private;
+        data_manager.addVideo(&private)?;
+        let mut config = Configuration {
+            serve_under_path: String::from("/tube"),
+            ..Configuration::default()
+        };
+        config.site_info.url_domain = String::from("https://example.com/");
+
+        let res = handleFeed(&data_manager, &config)?;
+        assert_eq!(res.headers()["Content-Type"], "application/rss+xml");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let body = runtime.block_on(warp::hyper::body::to_bytes(
+            res.into_body())).unwrap();
+        let xml = String::from_utf8(body.to_vec()).unwrap();
+        assert!(xml.contains("href=\"https://example.com/tube/feed.xml\""));
+        assert!(xml.contains("<title>Tom &amp; Jerry</title>"));
+        assert!(xml.contains("<link>https://example.com/tube/v/a</link>"));
+        assert_eq!(xml.matches("<item>").count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn testServeUnderSubpath() -> Result<(), Error>
+    {
+        let dir = randomTempFilename(std::env::temp_dir());
+        std::fs::create_dir_all(dir.join("videos")).unwrap();
+        let config = Configuration {
+            serve_under_path: String::from("/tube"),
+            password: String::from("secret"),
+            data_dir: dir.to_str().unwrap().to_owned(),
+            video_dir: dir.join("videos").to_str().unwrap().to_owned(),
+            ..Configuration::default()
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let app = App::new(config)?;
+            let queue = ProcessingQueue::start(
+                app.config.clone(), app.storage.clone(),
+                app.data_manager.clone());
+            let (route, _) = app.routes(&queue)?;
+
+            let res = warp::test::request().path("/tube/login/")
+                .header("Authorization",
+                        format!("Basic {}", BASE64.encode("user:secret")))
+                .reply(&route).await;
+            assert_eq!(res.status(), StatusCode::FOUND);
+            assert_eq!(res.headers()["Location"], "/tube");
+            let set_cookie = res.headers()["Set-Cookie"].to_str().unwrap();
+            assert!(set_cookie.contains("; Path=/tube;"));
+            let cookie = set_cookie.split(';').next().unwrap().to_owned();
+
+            // The browser sends the cookie to the pages under the
+            // cookie path, including the upload routes.
+            let res = warp::test::request().path("/tube/upload/")
+                .reply(&route).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let res = warp::test::request().path("/tube/upload/")
+                .header("Cookie", &cookie).reply(&route).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            // A form without a video passes the session check, and is
+            // then rejected.
+            let body = "--b\r\nContent-Disposition: form-data; \
+                        name=\"title\"\r\n\r\nA\r\n--b--\r\n";
+            let res = warp::test::request().method("POST")
+                .path("/tube/upload/").header("Cookie", &cookie)
+                .header("Content-Type", "multipart/form-data; boundary=b")
+                .body(body).reply(&route).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+            // robots.txt is at the root of the host.
+            let res = warp::test::request().path("/robots.txt")
+                .reply(&route).await;
+            assert_eq!(res.body().as_ref(), b"User-agent: *\nDisallow:\n");
+            Ok::<(), Error>(())
+        })?;
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn testVideoFileVisibility() -> Result<(), Error>
+    {
+        let dir = randomTempFilename(std::env::temp_dir());
+        std::fs::create_dir_all(dir.join("videos")).unwrap();
+        std::fs::write(dir.join("videos").join("private.mp4"), b"0123")
+            .unwrap();
+        let config = Configuration {
+            password: String::from("secret"),
+            data_dir: dir.to_str().unwrap().to_owned(),
+            video_dir: dir.join("videos").to_str().unwrap().to_owned(),
+            ..Configuration::default()
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let app = App::new(config)?;
+            let mut video = Video::new(String::from("private"),
+                                       String::from("private.mp4"));
+            video.visibility = Visibility::Private;
+            app.data_manager.addVideo(&video)?;
+            let queue = ProcessingQueue::start(
+                app.config.clone(), app.storage.clone(),
+                app.data_manager.clone());
+            let (route, _) = app.routes(&queue)?;
+
+            let res = warp::test::request().path("/video/private.mp4")
+                .reply(&route).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            // Files that belong to no video are not served either.
+            let res = warp::test::request().path("/video/db.sqlite")
+                .reply(&route).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            let res = warp::test::request().path("/login/")
+                .header("Authorization",
+                        format!("Basic {}", BASE64.encode("user:secret")))
+                .reply(&route).await;
+            let cookie = res.headers()["Set-Cookie"].to_str().unwrap()
+                .split(';').next().unwrap().to_owned();
+            let res = warp::test::request().method("HEAD")
+                .path("/video/private.mp4").header("Cookie", &cookie)
+                .reply(&route).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Length"], "4");
+            let last_modified = res.headers()["Last-Modified"].clone();
+            let res = warp::test::request().path("/video/private.mp4")
+                .header("Cookie", &cookie)
+                .header("If-Modified-Since", last_modified)
+                .reply(&route).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            Ok::<(), Error>(())
+        })?;
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn testPatchVideoAPI() -> Result<(), Error>
+    {
+        let dir = randomTempFilename(std::env::temp_dir());
+        std::fs::create_dir_all(dir.join("videos")).unwrap();
+        let config = Configuration {
+            password: String::from("secret"),
+            data_dir: dir.to_str().unwrap().to_owned(),
+            video_dir: dir.join("videos").to_str().unwrap().to_owned(),
+            ..Configuration::default()
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let app = App::new(config)?;
+            app.data_manager.addVideo(&Video::new(String::from("a"),
+                                                  String::from("a.mp4")))?;
+            let queue = ProcessingQueue::start(
+                app.config.clone(), app.storage.clone(),
+                app.data_manager.clone());
+            let (route, _) = app.routes(&queue)?;
+            let patch = |body: &str, content_type: &str| {
+                warp::test::request().method("PATCH").path("/api/video/a")
+                    .header("Content-Type", content_type)
+                    .body(body)
+            };
+
+            let res = patch(r#"{"title": "New"}"#, "application/json")
+                .reply(&route).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let res = warp::test::request().path("/login/")
+                .header("Authorization",
+                        format!("Basic {}", BASE64.encode("user:secret")))
+                .reply(&route).await;
+            let cookie = res.headers()["Set-Cookie"].to_str().unwrap()
+                .split(';').next().unwrap().to_owned();
+
+            let res = patch(r#"{"title": "New"}"#, "application/json")
+                .header("Cookie", &cookie).reply(&route).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(app.data_manager.findVideoByID("a")?.unwrap().title,
+                       "New");
+            // Bad bodies are rejected with JSON errors.
+            for (body, content_type, code) in [
+                (r#"{"title": "#, "application/json", StatusCode::BAD_REQUEST),
+                (r#"{"title": 1}"#, "application/json",
+                 StatusCode::BAD_REQUEST),
+                (r#"{"title": "New"}"#, "text/plain",
+                 StatusCode::UNSUPPORTED_MEDIA_TYPE)]
+            {
+                let res = patch(body, content_type).header("Cookie", &cookie)
+                    .reply(&route).await;
+                assert_eq!(res.status(), code);
+                let error: serde_json::Value =
+                    serde_json::from_slice(res.body()).unwrap();
+                assert_eq!(error["code"], code.as_u16());
+            }
+            Ok::<(), Error>(())
+        })?;
+        std::fs::remove_dir_all(&dir).ok();
         Ok(())
     }
 }
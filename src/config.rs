@@ -1,6 +1,11 @@
+use std::path::{Path, PathBuf};
+
 use serde::{Deserialize, Serialize};
 
 use crate::error::Error;
+use crate::data::VideoOrder;
+use crate::video::{ContainerType, ThumbnailFormat, Visibility, TimeDisplay,
+                   DEFAULT_TIME_FORMAT};
 
 fn defaultListenAddr() -> String
 {
@@ -14,14 +19,167 @@ fn defaultServePath() -> String
 
 fn defaultListenPort() -> u16 { 8080 }
 fn defaultUploadSizeMax() -> u64 { 10 * 1024 * 1024 * 1024 }
+fn defaultPerFileSizeMax() -> Option<u64> { None }
 fn defaultPassword() -> String { "metube".to_owned() }
 fn defaultSessionLifeTime() -> u64 {
     time::Duration::days(30).as_seconds_f64() as u64
 }
 fn defaultThumbnailQuality() -> u8 { 85 }
+fn defaultRegenerateStaleThumbnails() -> bool { false }
+fn defaultIndexPageSize() -> u64 { 200 }
+fn defaultMaxPageSize() -> u64 { 200 }
+fn defaultLazyThumbnails() -> bool { true }
+fn defaultNormalizeTrailingSlash() -> bool { false }
+fn defaultIdLengthBytes() -> usize { 12 }
+fn defaultAllowedOrigins() -> Vec<String> { Vec::new() }
+fn defaultMaxDurationSec() -> Option<u64> { None }
+fn defaultLoopDurationMaxSec() -> Option<u64> { None }
+fn defaultAllowedContainers() -> Vec<String> { Vec::new() }
+fn defaultStorage() -> StorageConfig { StorageConfig::Local }
+fn defaultEnableCompression() -> bool { false }
+fn defaultVideoDisposition() -> String { String::from("inline") }
+fn defaultRetentionDays() -> Option<u64> { None }
+fn defaultProcessingWorkers() -> usize { 2 }
+fn defaultMaxConcurrentUploads() -> usize { 4 }
+fn defaultUploadQuotaWindowSec() -> u64 { 3600 }
+fn defaultUploadQuota() -> Option<u64> { None }
+fn defaultPendingUploadLifeTimeSec() -> u64 {
+    time::Duration::days(1).as_seconds_f64() as u64
+}
+fn defaultEnableHLS() -> bool { false }
+fn defaultScrubThumbnails() -> bool { false }
+fn defaultScrubIntervalSec() -> u64 { 10 }
+fn defaultScrubTileWidth() -> u32 { 160 }
+fn defaultScrubTileHeight() -> u32 { 90 }
+fn defaultScrubColumns() -> u32 { 10 }
+fn defaultUploadVideoField() -> String { String::from("video") }
+fn defaultDefaultArtist() -> String { String::new() }
+fn defaultWebSubHub() -> Option<String> { None }
+fn defaultMetricsEnabled() -> bool { false }
+fn defaultMetricsListenPort() -> Option<u16> { None }
+fn defaultRetentionVisibilities() -> Vec<String>
+{
+    vec![String::from("public"), String::from("unlisted")]
+}
+fn defaultSessionSliding() -> bool { false }
+fn defaultSessionMaxLifeTime() -> Option<u64> { None }
+fn defaultStatsRequireAuth() -> bool { false }
+fn defaultDevMode() -> bool { false }
+fn defaultUploadsEnabled() -> bool { true }
+fn defaultCookieHttpOnly() -> bool { true }
+fn defaultCookieSameSite() -> String { String::from("Lax") }
+fn defaultCookieSecure() -> Option<bool> { None }
+fn defaultCookieDomain() -> Option<String> { None }
+fn defaultHistorySize() -> u64 { 100 }
+fn defaultCSPExtraSources() -> Vec<String> { Vec::new() }
+fn defaultFrameAncestors() -> Vec<String> { vec![String::from("'self'")] }
+fn defaultReferrerPolicy() -> String { String::from("same-origin") }
+fn defaultTranscodeOnUpload() -> bool { false }
+fn defaultTranscodeContainer() -> String { String::from("mp4") }
+fn defaultTranscodePreset() -> Option<String> { None }
+fn defaultFFmpegThreads() -> u32 { 2 }
+fn defaultThumbnailFormat() -> String { String::from("webp") }
+fn defaultFastThumbnailSeek() -> bool { false }
+fn defaultDefaultOrder() -> String { String::from("new_first") }
 fn defaultSiteTitle() -> String { String::from("MeTube") }
 fn defaultFootnote() -> String { String::new() }
 fn defaultUrlDomain() -> String { String::from("http://example.org") }
+fn defaultLogoPath() -> Option<String> { None }
+fn defaultFaviconPath() -> Option<String> { None }
+fn defaultPlaceholderThumbnailPath() -> Option<String> { None }
+fn defaultNavLinks() -> Vec<NavLink> { Vec::new() }
+fn defaultCustomCSS() -> Option<String> { None }
+fn defaultRobots() -> String { String::from("User-agent: *\nDisallow:\n") }
+fn defaultTimezone() -> Option<String> { None }
+fn defaultTimeFormat() -> String { String::from(DEFAULT_TIME_FORMAT) }
+
+/// Set “field” to “value” parsed as its type, if there is a value.
+fn overrideField<T: std::str::FromStr>(field: &mut T, name: &str,
+                                       value: Option<String>) ->
+    Result<(), Error>
+{
+    if let Some(value) = value
+    {
+        *field = value.parse().map_err(
+            |_| rterr!("Invalid value of METUBE_{} in environment: {}",
+                       name.to_ascii_uppercase(), value))?;
+    }
+    Ok(())
+}
+
+/// Like `overrideField`, but for an optional field. An empty value
+/// unsets it.
+fn overrideOptionalField<T: std::str::FromStr>(
+    field: &mut Option<T>, name: &str, value: Option<String>) ->
+    Result<(), Error>
+{
+    if let Some(value) = value
+    {
+        *field = if value.is_empty()
+        {
+            None
+        }
+        else
+        {
+            Some(value.parse().map_err(
+                |_| rterr!("Invalid value of METUBE_{} in environment: {}",
+                           name.to_ascii_uppercase(), value))?)
+        };
+    }
+    Ok(())
+}
+
+/// Describe a TOML error in one line, such as “missing field
+/// `video_dir` at line 3”.
+fn describeTomlError(e: &toml::de::Error, content: &str) -> String
+{
+    let message = e.message().trim_end().replace('\n', "; ");
+    match e.span()
+    {
+        Some(span) =>
+        {
+            let line = content[..span.start.min(content.len())]
+                .matches('\n').count() + 1;
+            format!("{} at line {}", message, line)
+        },
+        None => message.to_owned(),
+    }
+}
+
+/// Connection to an S3-compatible object store.
+#[derive(Deserialize, Clone)]
+pub struct S3Config
+{
+    /// Such as `https://s3.us-east-1.amazonaws.com`. Objects are
+    /// accessed path-style under this.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to the keys of all objects, such as `metube/`.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Where the video files and thumbnails are stored. Uploads are
+/// always processed in `video_dir` first.
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig
+{
+    /// In `video_dir`.
+    Local,
+    S3(S3Config),
+}
+
+/// A link in the navbar and the footer of every page.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct NavLink
+{
+    pub label: String,
+    pub url: String,
+}
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct SiteInfo
@@ -32,17 +190,67 @@ pub struct SiteInfo
     pub footnote: String,
     /// The beginning part of the URL of the website, including only
     /// the protocol and domain, without the trailing slash. This is
-    /// only used in the OGP metadata. Example: http://example.org.
+    /// used in the OGP metadata, the sitemap, and the feed. Example:
+    /// http://example.org.
     #[serde(default = "defaultUrlDomain")]
     pub url_domain: String,
+    /// Path of the image file shown as the site logo in the navbar.
+    /// If not set, the bundled `film.svg` in the static dir is used.
+    #[serde(default = "defaultLogoPath", skip_serializing)]
+    pub logo_path: Option<String>,
+    /// Path of the image file served at `/favicon.ico`. If not set,
+    /// the bundled `film.svg` in the static dir is used.
+    #[serde(default = "defaultFaviconPath", skip_serializing)]
+    pub favicon_path: Option<String>,
+    /// Path of the image file shown as the thumbnail of videos
+    /// without one, such as when generating it failed. If not set,
+    /// the bundled `film.svg` in the static dir is used.
+    #[serde(default = "defaultPlaceholderThumbnailPath", skip_serializing)]
+    pub placeholder_thumbnail_path: Option<String>,
+    /// Extra links shown in the navbar and the footer, such as
+    /// `{ label = "Blog", url = "https://example.org/blog" }`.
+    #[serde(default = "defaultNavLinks")]
+    pub nav_links: Vec<NavLink>,
+    /// URL of a stylesheet loaded after the bundled one on every
+    /// page, so that it can override its rules. Either absolute
+    /// (`https://…`) or starting with `/`.
+    #[serde(default = "defaultCustomCSS")]
+    pub custom_css_url: Option<String>,
+    /// CSS inlined in every page after the bundled stylesheet, and
+    /// after “custom_css_url”.
+    #[serde(default = "defaultCustomCSS")]
+    pub custom_css: Option<String>,
+    /// Content of `/robots.txt`. The default allows all crawlers; set
+    /// it to “User-agent: *\nDisallow: /” to keep them out.
+    #[serde(default = "defaultRobots", skip_serializing)]
+    pub robots: String,
+    /// IANA name of the timezone of the upload times on the pages,
+    /// like “Asia/Tokyo”. UTC if not set or unknown. The JSON APIs
+    /// are not affected.
+    #[serde(default = "defaultTimezone", skip_serializing)]
+    pub timezone: Option<String>,
+    /// Format of the upload times on the pages, in the format
+    /// description syntax of the “time” crate. The abbreviation of
+    /// the timezone is appended.
+    #[serde(default = "defaultTimeFormat", skip_serializing)]
+    pub time_format: String,
 }
 
+/// Values of “transcode_preset”.
+const X264_PRESETS: &[&str] = &["ultrafast", "superfast", "veryfast", "faster",
+                                "fast", "medium", "slow", "slower", "veryslow"];
+
+/// Maximal length of “custom_css” in bytes.
+const CUSTOM_CSS_SIZE_MAX: usize = 64 * 1024;
+
 #[derive(Deserialize, Clone)]
 pub struct Configuration
 {
     pub video_dir: String,
     pub static_dir: String,
     pub data_dir: String,
+    /// An IP address, or “unix:/path/to.sock” to listen on a Unix
+    /// socket instead, in which case “listen_port” is not used.
     #[serde(default = "defaultListenAddr")]
     pub listen_address: String,
     #[serde(default = "defaultListenPort")]
@@ -53,15 +261,250 @@ pub struct Configuration
     pub serve_under_path: String,
     #[serde(default = "defaultUploadSizeMax")]
     pub upload_size_max: u64,
+    /// The video file of an upload larger than this is rejected,
+    /// while “upload_size_max” limits the whole request. No limit if
+    /// not set, except for videos fetched from URLs, which are then
+    /// limited by “upload_size_max”.
+    #[serde(default = "defaultPerFileSizeMax")]
+    pub per_file_size_max: Option<u64>,
     #[serde(default = "defaultPassword")]
     pub password: String,
+    /// How long a session lasts, in seconds. 0 means sessions never
+    /// expire.
     #[serde(default = "defaultSessionLifeTime")]
     pub session_life_time_sec: u64,
-    /// Default compression quality of the WebP thumbnail images,
-    /// ranging from 0 to 100. Higher is better. This is passed to
-    /// ffmpeg’s `-q:v` argument.
+    /// Default compression quality of the thumbnail images, ranging
+    /// from 0 to 100. Higher is better. This is mapped to the quality
+    /// argument of the encoder of “thumbnail_format”.
     #[serde(default = "defaultThumbnailQuality")]
     pub thumbnail_quality: u8,
+    /// Whether to regenerate the thumbnails generated with another
+    /// “thumbnail_quality” on startup, in the background. Uploaded
+    /// thumbnails are kept.
+    #[serde(default = "defaultRegenerateStaleThumbnails")]
+    pub regenerate_stale_thumbnails: bool,
+    /// Image format of the thumbnails and scrubbing previews. One of
+    /// `webp`, `jpeg`, or `avif`. Use `jpeg` for older clients that
+    /// do not render WebP. Existing thumbnails are not converted.
+    #[serde(default = "defaultThumbnailFormat")]
+    pub thumbnail_format: String,
+    /// Whether to seek to the thumbnail frame by key frames, which
+    /// is much faster on large videos, but may pick a frame a few
+    /// seconds off. Otherwise the seeking is frame-accurate.
+    #[serde(default = "defaultFastThumbnailSeek")]
+    pub fast_thumbnail_seek: bool,
+    /// Number of videos shown on the index page when the request
+    /// does not specify a count. This is at most “max_page_size”.
+    #[serde(default = "defaultIndexPageSize")]
+    pub index_page_size: u64,
+    /// Largest number of videos a client can request in one page of
+    /// a listing, with the `count` query parameter. Larger counts are
+    /// silently reduced to this.
+    #[serde(default = "defaultMaxPageSize")]
+    pub max_page_size: u64,
+    /// Whether the thumbnails in video listings are only loaded when
+    /// they are scrolled near the view. This saves bandwidth on long
+    /// pages.
+    #[serde(default = "defaultLazyThumbnails")]
+    pub lazy_thumbnails: bool,
+    /// Whether to redirect requests for pages and files to the
+    /// canonical form of their paths. Section pages like `/upload/`
+    /// end with a slash, and the others like `/v/<id>` do not. Both
+    /// forms are served either way; this keeps one URL per page.
+    #[serde(default = "defaultNormalizeTrailingSlash")]
+    pub normalize_trailing_slash: bool,
+    /// Default ordering of the video listing. One of `new_first`,
+    /// `old_first`, and `most_viewed`.
+    #[serde(default = "defaultDefaultOrder")]
+    pub default_order: String,
+    /// Number of bytes of the SHA-256 hash of the video content
+    /// used as the ID of newly uploaded videos, between 1 and 32.
+    /// The ID is the hex representation of these bytes. Changing
+    /// this does not affect existing videos.
+    #[serde(default = "defaultIdLengthBytes")]
+    pub id_length_bytes: usize,
+    /// Origins allowed to access the JSON API from another site,
+    /// such as `https://example.org`. If empty, CORS is disabled,
+    /// and only same-origin requests work from browsers.
+    #[serde(default = "defaultAllowedOrigins")]
+    pub allowed_origins: Vec<String>,
+    /// Uploaded videos longer than this are rejected. No limit if
+    /// not set.
+    #[serde(default = "defaultMaxDurationSec")]
+    pub max_duration_sec: Option<u64>,
+    /// Uploaded videos that are not longer than this are set to
+    /// loop, like short clips. It can be changed for each video
+    /// afterwards. No video is set to loop if not set.
+    #[serde(default = "defaultLoopDurationMaxSec")]
+    pub loop_duration_max_sec: Option<u64>,
+    /// Containers of the videos that can be uploaded, such as `mp4`
+    /// and `webm`. If empty, all supported containers are allowed.
+    #[serde(default = "defaultAllowedContainers")]
+    pub allowed_containers: Vec<String>,
+    /// Whether to transcode uploaded videos that browsers may not be
+    /// able to play. Videos that are already web-compatible are kept
+    /// as is.
+    #[serde(default = "defaultTranscodeOnUpload")]
+    pub transcode_on_upload: bool,
+    /// Container of the transcoded videos. Either `mp4` (H.264 and
+    /// AAC) or `webm` (VP9 and Opus).
+    #[serde(default = "defaultTranscodeContainer")]
+    pub transcode_container: String,
+    /// The x264 “-preset” of transcoding into `mp4`, such as
+    /// `veryfast` or `slow`. Faster presets use less CPU for a larger
+    /// file. If not set, ffmpeg picks its default, `medium`. This is
+    /// not used for `webm`.
+    #[serde(default = "defaultTranscodePreset")]
+    pub transcode_preset: Option<String>,
+    /// Number of threads each ffmpeg process uses for decoding,
+    /// filtering and encoding. 0 lets ffmpeg decide, which usually
+    /// means all cores.
+    #[serde(default = "defaultFFmpegThreads")]
+    pub ffmpeg_threads: u32,
+    /// Whether to compress HTML and JSON responses with gzip or
+    /// deflate, if the client accepts it. Videos and thumbnails are
+    /// never compressed.
+    #[serde(default = "defaultEnableCompression")]
+    pub enable_compression: bool,
+    /// The “Content-Disposition” of the video files at `/video/<path>`,
+    /// which are what the video links point to. Either `inline`,
+    /// which lets browsers play them, or `attachment`, which makes
+    /// them download. The player on the video page works either way.
+    #[serde(default = "defaultVideoDisposition")]
+    pub default_video_disposition: String,
+    #[serde(default = "defaultStorage")]
+    pub storage: StorageConfig,
+    /// Number of uploaded videos that are transcoded and have their
+    /// thumbnails generated at the same time. This happens after the
+    /// upload request returns. With remote storage, the videos are
+    /// only playable after this is done.
+    #[serde(default = "defaultProcessingWorkers")]
+    pub processing_workers: usize,
+    /// Number of multipart uploads that can be received at the same
+    /// time. More uploads are rejected with 503, and the client
+    /// should retry later.
+    #[serde(default = "defaultMaxConcurrentUploads")]
+    pub max_concurrent_uploads: usize,
+    /// A session can upload at most “upload_quota_count” videos, and
+    /// “upload_quota_bytes” bytes in total, in the last
+    /// “upload_quota_window_sec” seconds. Uploads beyond that are
+    /// rejected with 429. No limit if not set.
+    #[serde(default = "defaultUploadQuotaWindowSec")]
+    pub upload_quota_window_sec: u64,
+    #[serde(default = "defaultUploadQuota")]
+    pub upload_quota_count: Option<u64>,
+    #[serde(default = "defaultUploadQuota")]
+    pub upload_quota_bytes: Option<u64>,
+    /// Resumable uploads that receive no data for this many seconds
+    /// are abandoned, and their temp files are removed.
+    #[serde(default = "defaultPendingUploadLifeTimeSec")]
+    pub pending_upload_life_time_sec: u64,
+    /// Whether to serve videos over HLS at “/hls/<id>/index.m3u8”,
+    /// in addition to progressive download. Videos are packaged on
+    /// their first request. This only works with local storage.
+    #[serde(default = "defaultEnableHLS")]
+    pub enable_hls: bool,
+    /// Whether to generate a sprite sheet of preview images for
+    /// uploaded videos, which are shown when hovering over the seek
+    /// bar.
+    #[serde(default = "defaultScrubThumbnails")]
+    pub scrub_thumbnails: bool,
+    /// Seconds between two preview images. Long videos use a longer
+    /// interval, so that the sprite sheet is not too large.
+    #[serde(default = "defaultScrubIntervalSec")]
+    pub scrub_interval_sec: u64,
+    /// Size in pixels of each preview image in the sprite sheet.
+    #[serde(default = "defaultScrubTileWidth")]
+    pub scrub_tile_width: u32,
+    #[serde(default = "defaultScrubTileHeight")]
+    pub scrub_tile_height: u32,
+    /// Number of preview images in each row of the sprite sheet. The
+    /// width of a row is at most 16383 pixels.
+    #[serde(default = "defaultScrubColumns")]
+    pub scrub_columns: u32,
+    /// Name of the multipart form field that carries the video file
+    /// in an upload.
+    #[serde(default = "defaultUploadVideoField")]
+    pub upload_video_field: String,
+    /// Artist of uploaded and imported videos that do not have one
+    /// from the upload form, a sidecar file, or the embedded tags.
+    /// Empty means no artist.
+    #[serde(default = "defaultDefaultArtist")]
+    pub default_artist: String,
+    /// Whether to serve Prometheus metrics at “/metrics”.
+    #[serde(default = "defaultMetricsEnabled")]
+    pub metrics_enabled: bool,
+    /// If set, “/metrics” is served on this port of
+    /// “listen_address” instead of with the other pages, so that it
+    /// can be kept internal.
+    #[serde(default = "defaultMetricsListenPort")]
+    pub metrics_listen_port: Option<u16>,
+    /// If set, videos uploaded more than this many days ago are
+    /// deleted automatically. This must be positive.
+    #[serde(default = "defaultRetentionDays")]
+    pub retention_days: Option<u64>,
+    /// Only videos with these visibilities are deleted by
+    /// “retention_days”. By default private videos are kept.
+    #[serde(default = "defaultRetentionVisibilities")]
+    pub retention_visibilities: Vec<String>,
+    /// If false, sessions expire “session_life_time_sec” seconds
+    /// after login. If true, they expire after not being used for
+    /// that long instead.
+    #[serde(default = "defaultSessionSliding")]
+    pub session_sliding: bool,
+    /// With sliding sessions, the maximal number of seconds a session
+    /// lasts after login regardless of use. No limit if not set.
+    #[serde(default = "defaultSessionMaxLifeTime")]
+    pub session_max_life_time_sec: Option<u64>,
+    /// Whether the stats API (“/api/stats” and
+    /// “/api/video/<id>/views”) requires a valid session.
+    #[serde(default = "defaultStatsRequireAuth")]
+    pub stats_require_auth: bool,
+    /// In development mode, templates are reloaded from disk for
+    /// every page.
+    #[serde(default = "defaultDevMode")]
+    pub dev_mode: bool,
+    /// Whether videos can be uploaded at all. If false, the upload
+    /// routes do not exist.
+    #[serde(default = "defaultUploadsEnabled")]
+    pub uploads_enabled: bool,
+    /// Whether the session cookie is hidden from JavaScript.
+    #[serde(default = "defaultCookieHttpOnly")]
+    pub cookie_http_only: bool,
+    /// The “SameSite” attribute of the session cookie. One of
+    /// “Strict”, “Lax”, and “None”.
+    #[serde(default = "defaultCookieSameSite")]
+    pub cookie_same_site: String,
+    /// Whether the session cookie is only sent over HTTPS. If not
+    /// set, this is on when “url_domain” is an https URL.
+    #[serde(default = "defaultCookieSecure")]
+    pub cookie_secure: Option<bool>,
+    /// The “Domain” attribute of the session cookie, such as
+    /// “example.org”, to share the session with its subdomains. If
+    /// not set, the cookie is only sent to the host that set it.
+    #[serde(default = "defaultCookieDomain")]
+    pub cookie_domain: Option<String>,
+    /// How many recently viewed videos to keep per session.
+    #[serde(default = "defaultHistorySize")]
+    pub history_size: u64,
+    /// Extra sources allowed in the Content-Security-Policy of HTML
+    /// pages, such as “https://cdn.example.org”. By default only
+    /// resources from this server are allowed.
+    #[serde(default = "defaultCSPExtraSources")]
+    pub csp_extra_sources: Vec<String>,
+    /// Who can embed the HTML pages in a frame, as the
+    /// “frame-ancestors” directive of the CSP. An empty list forbids
+    /// embedding entirely.
+    #[serde(default = "defaultFrameAncestors")]
+    pub frame_ancestors: Vec<String>,
+    /// URL of a WebSub hub. If set, the feed at “/feed.xml”
+    /// advertises the hub, and the hub is notified whenever a public
+    /// video is uploaded.
+    #[serde(default = "defaultWebSubHub")]
+    pub websub_hub: Option<String>,
+    /// Value of the “Referrer-Policy” header of HTML pages.
+    #[serde(default = "defaultReferrerPolicy")]
+    pub referrer_policy: String,
     pub site_info: SiteInfo,
 }
 
@@ -71,8 +514,430 @@ impl Configuration
     {
         let content = std::fs::read_to_string(path).map_err(
             |_| rterr!("Failed to read config file at {}", path))?;
-        toml::from_str(&content).map_err(
-            |_| rterr!("Failed to parse config file"))
+        let config: Self = toml::from_str(&content).map_err(
+            |e| rterr!("Failed to parse config file at {}: {}", path,
+                       describeTomlError(&e, &content)))?;
+        Ok(config)
+    }
+
+    /// Override the config with the environment variables named
+    /// after the fields, such as “METUBE_LISTEN_PORT” for
+    /// “listen_port”. Fields of “site_info” are also named without
+    /// the prefix, such as “METUBE_SITE_TITLE”. Variables that are
+    /// not set leave the fields untouched, and empty ones unset
+    /// optional fields.
+    pub fn applyEnv(&mut self) -> Result<(), Error>
+    {
+        self.applyOverrides(|name| std::env::var(name).ok())
+    }
+
+    fn applyOverrides<F>(&mut self, lookup: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Option<String>
+    {
+        let get = |field: &str| lookup(
+            &format!("METUBE_{}", field.to_ascii_uppercase()));
+        overrideField(&mut self.video_dir, "video_dir", get("video_dir"))?;
+        overrideField(&mut self.static_dir, "static_dir", get("static_dir"))?;
+        overrideField(&mut self.data_dir, "data_dir", get("data_dir"))?;
+        overrideField(&mut self.listen_address, "listen_address",
+                      get("listen_address"))?;
+        overrideField(&mut self.listen_port, "listen_port",
+                      get("listen_port"))?;
+        overrideField(&mut self.serve_under_path, "serve_under_path",
+                      get("serve_under_path"))?;
+        overrideField(&mut self.upload_size_max, "upload_size_max",
+                      get("upload_size_max"))?;
+        overrideField(&mut self.password, "password", get("password"))?;
+        overrideField(&mut self.session_life_time_sec, "session_life_time_sec",
+                      get("session_life_time_sec"))?;
+        overrideField(&mut self.thumbnail_quality, "thumbnail_quality",
+                      get("thumbnail_quality"))?;
+        overrideField(&mut self.regenerate_stale_thumbnails,
+                      "regenerate_stale_thumbnails",
+                      get("regenerate_stale_thumbnails"))?;
+        overrideField(&mut self.thumbnail_format, "thumbnail_format",
+                      get("thumbnail_format"))?;
+        overrideField(&mut self.fast_thumbnail_seek, "fast_thumbnail_seek",
+                      get("fast_thumbnail_seek"))?;
+        overrideField(&mut self.index_page_size, "index_page_size",
+                      get("index_page_size"))?;
+        overrideField(&mut self.max_page_size, "max_page_size",
+                      get("max_page_size"))?;
+        overrideField(&mut self.lazy_thumbnails, "lazy_thumbnails",
+                      get("lazy_thumbnails"))?;
+        overrideField(&mut self.normalize_trailing_slash,
+                      "normalize_trailing_slash",
+                      get("normalize_trailing_slash"))?;
+        overrideField(&mut self.default_order, "default_order",
+                      get("default_order"))?;
+        overrideField(&mut self.default_artist, "default_artist",
+                      get("default_artist"))?;
+        overrideField(&mut self.transcode_on_upload, "transcode_on_upload",
+                      get("transcode_on_upload"))?;
+        overrideOptionalField(&mut self.transcode_preset, "transcode_preset",
+                              get("transcode_preset"))?;
+        overrideField(&mut self.ffmpeg_threads, "ffmpeg_threads",
+                      get("ffmpeg_threads"))?;
+        overrideField(&mut self.enable_compression, "enable_compression",
+                      get("enable_compression"))?;
+        overrideField(&mut self.default_video_disposition,
+                      "default_video_disposition",
+                      get("default_video_disposition"))?;
+        overrideField(&mut self.uploads_enabled, "uploads_enabled",
+                      get("uploads_enabled"))?;
+        overrideField(&mut self.metrics_enabled, "metrics_enabled",
+                      get("metrics_enabled"))?;
+        overrideField(&mut self.dev_mode, "dev_mode", get("dev_mode"))?;
+        overrideField(&mut self.site_info.site_title, "site_title",
+                      get("site_title"))?;
+        overrideField(&mut self.site_info.url_domain, "url_domain",
+                      get("url_domain"))?;
+        Ok(())
+    }
+
+    /// Check the invariants of the config that cannot be expressed
+    /// in its types.
+    pub fn validate(&self) -> Result<(), Error>
+    {
+        let path = &self.serve_under_path;
+        if !path.starts_with('/') || (path != "/" && path.ends_with('/'))
+        {
+            return Err(rterr!("Invalid serve_under_path: {}. It should start \
+                               with “/”, and not end with “/”.",
+                              self.serve_under_path));
+        }
+        if self.thumbnail_quality > 100
+        {
+            return Err(rterr!("Invalid thumbnail_quality: {}. It should be \
+                               between 0 and 100.", self.thumbnail_quality));
+        }
+        if self.unixSocketPath().is_none() &&
+            self.listen_address.parse::<std::net::IpAddr>().is_err()
+        {
+            return Err(rterr!("Invalid listen_address: {}. It should be an \
+                               IP address or “unix:/path/to.sock”.",
+                              self.listen_address));
+        }
+        if self.metrics_listen_port == Some(self.listen_port)
+        {
+            return Err(rterr!("metrics_listen_port should be different from \
+                               listen_port"));
+        }
+        if self.scrub_interval_sec == 0 || self.scrub_tile_width == 0 ||
+            self.scrub_tile_height == 0 || self.scrub_columns == 0
+        {
+            return Err(rterr!("scrub_interval_sec, scrub_tile_width, \
+                               scrub_tile_height, and scrub_columns should \
+                               be positive"));
+        }
+        // WebP images are at most 16383 pixels on each side. Long
+        // videos get fewer rows of previews to fit the height.
+        if self.scrub_tile_width as u64 * self.scrub_columns as u64 > 16383 ||
+            self.scrub_tile_height > 16383
+        {
+            return Err(rterr!("The sprite sheet of scrubbing previews is too \
+                               large. scrub_tile_width × scrub_columns and \
+                               scrub_tile_height should be at most 16383."));
+        }
+        if self.enable_hls && !matches!(self.storage, StorageConfig::Local)
+        {
+            return Err(rterr!("enable_hls requires local storage"));
+        }
+        if let Some(url) = &self.site_info.custom_css_url
+        {
+            let has_scheme = url.strip_prefix("https://")
+                .or_else(|| url.strip_prefix("http://"))
+                .is_some_and(|rest| !rest.is_empty() && !rest.starts_with('/'));
+            if !(has_scheme || url.starts_with('/')) ||
+                url.chars().any(|c| c.is_whitespace() ||
+                                "\"'<>".contains(c))
+            {
+                return Err(rterr!("Invalid custom_css_url: {}. It should be \
+                                   an http(s) URL or start with “/”.", url));
+            }
+        }
+        if let Some(hub) = &self.websub_hub
+        {
+            if !(hub.starts_with("https://") || hub.starts_with("http://"))
+            {
+                return Err(rterr!("Invalid websub_hub: {}. It should be an \
+                                   http(s) URL.", hub));
+            }
+        }
+        if let Some(css) = &self.site_info.custom_css
+        {
+            if css.len() > CUSTOM_CSS_SIZE_MAX
+            {
+                return Err(rterr!("custom_css should be at most {} bytes",
+                                  CUSTOM_CSS_SIZE_MAX));
+            }
+            // The CSS is put in a <style> element unescaped.
+            if css.to_ascii_lowercase().contains("</style")
+            {
+                return Err(rterr!("custom_css should not contain “</style”"));
+            }
+        }
+        if self.upload_quota_window_sec == 0
+        {
+            return Err(rterr!("upload_quota_window_sec should be positive"));
+        }
+        if self.pending_upload_life_time_sec == 0
+        {
+            return Err(rterr!("pending_upload_life_time_sec should be \
+                               positive"));
+        }
+        if self.processing_workers == 0
+        {
+            return Err(rterr!("processing_workers should be positive"));
+        }
+        if self.max_concurrent_uploads == 0
+        {
+            return Err(rterr!("max_concurrent_uploads should be positive"));
+        }
+        if self.max_page_size == 0
+        {
+            return Err(rterr!("max_page_size should be positive"));
+        }
+        if self.index_page_size == 0 || self.index_page_size > self.max_page_size
+        {
+            return Err(rterr!("index_page_size should be positive, and at \
+                               most max_page_size ({})", self.max_page_size));
+        }
+        if VideoOrder::fromName(&self.default_order).is_none()
+        {
+            return Err(rterr!("Invalid default_order: {}",
+                              self.default_order));
+        }
+        if self.id_length_bytes == 0 || self.id_length_bytes > 32
+        {
+            return Err(rterr!("Invalid id_length_bytes: {}",
+                              self.id_length_bytes));
+        }
+        for container in &self.allowed_containers
+        {
+            if ContainerType::fromExtension(container).is_none()
+            {
+                return Err(rterr!("Invalid container in allowed_containers: {}",
+                                  container));
+            }
+        }
+        if self.default_video_disposition != "inline" &&
+            self.default_video_disposition != "attachment"
+        {
+            return Err(rterr!("Invalid default_video_disposition: {}. It \
+                               should be “inline” or “attachment”.",
+                              self.default_video_disposition));
+        }
+        if ThumbnailFormat::fromName(&self.thumbnail_format).is_none()
+        {
+            return Err(rterr!("Invalid thumbnail_format: {}. It should be \
+                               “webp”, “jpeg”, or “avif”.",
+                              self.thumbnail_format));
+        }
+        if ContainerType::fromExtension(&self.transcode_container).is_none()
+        {
+            return Err(rterr!("Invalid transcode_container: {}",
+                              self.transcode_container));
+        }
+        if let Some(preset) = &self.transcode_preset
+        {
+            if !X264_PRESETS.contains(&preset.as_str())
+            {
+                return Err(rterr!("Invalid transcode_preset: {}. It should \
+                                   be one of {}.", preset,
+                                  X264_PRESETS.join(", ")));
+            }
+        }
+        // Deleting everything is more likely a mistake than what
+        // the user wants.
+        if self.retention_days == Some(0)
+        {
+            return Err(rterr!("retention_days should be positive. Leave it \
+                               unset to keep videos forever."));
+        }
+        for name in &self.retention_visibilities
+        {
+            if Visibility::fromName(name).is_none()
+            {
+                return Err(rterr!("Invalid visibility in \
+                                   retention_visibilities: {}", name));
+            }
+        }
+        if !["Strict", "Lax", "None"].contains(
+            &self.cookie_same_site.as_str())
+        {
+            return Err(rterr!("Invalid cookie_same_site: {}",
+                              self.cookie_same_site));
+        }
+        if self.cookie_same_site == "None" && !self.cookieSecure()
+        {
+            return Err(rterr!("cookie_same_site = \"None\" requires a \
+                               secure cookie"));
+        }
+        if let Some(domain) = &self.cookie_domain
+        {
+            if domain.is_empty() || !domain.chars().all(
+                |c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            {
+                return Err(rterr!("Invalid cookie_domain: {}", domain));
+            }
+        }
+        for origin in &self.allowed_origins
+        {
+            let uri = origin.parse::<warp::http::Uri>().map_err(
+                |_| rterr!("Invalid origin: {}", origin))?;
+            if uri.scheme().is_none() || uri.authority().is_none()
+            {
+                return Err(rterr!("Invalid origin: {}", origin));
+            }
+        }
+        Ok(())
+    }
+
+    /// The path of the Unix socket to listen on, if “listen_address”
+    /// is in the form of “unix:/path/to.sock”.
+    pub fn unixSocketPath(&self) -> Option<&str>
+    {
+        self.listen_address.strip_prefix("unix:")
+            .filter(|path| !path.is_empty())
+    }
+
+    /// The origin of “custom_css_url”, like “https://cdn.example.org”,
+    /// if it is an absolute URL. The CSP allows stylesheets from it.
+    pub fn customCSSOrigin(&self) -> Option<&str>
+    {
+        let url = self.site_info.custom_css_url.as_deref()?;
+        let scheme_end = url.find("://")? + 3;
+        let host_end = url[scheme_end..].find(['/', '?', '#'])
+            .map_or(url.len(), |i| scheme_end + i);
+        Some(&url[..host_end])
+    }
+
+    /// The visibilities of videos that are deleted by
+    /// “retention_days”. These have already been validated when the
+    /// config is loaded.
+    pub fn retentionVisibilities(&self) -> Vec<Visibility>
+    {
+        self.retention_visibilities.iter()
+            .filter_map(|name| Visibility::fromName(name)).collect()
+    }
+
+    /// The absolute lifetime limit of sessions, which only applies
+    /// to sliding sessions. A limit of 0 is no limit.
+    pub fn sessionMaxLifeTime(&self) -> Option<u64>
+    {
+        if self.session_sliding
+        {
+            self.session_max_life_time_sec.filter(|max| *max > 0)
+        }
+        else
+        {
+            None
+        }
+    }
+
+    /// The “Max-Age” of the session cookie. A sliding session can
+    /// outlive “session_life_time_sec”, so its cookie lasts until the
+    /// absolute limit, or as long as browsers allow (400 days) if
+    /// there is none.
+    pub fn cookieMaxAge(&self) -> u64
+    {
+        const BROWSER_MAX_AGE: u64 = 400 * 24 * 3600;
+        if self.session_sliding
+        {
+            self.sessionMaxLifeTime().unwrap_or(BROWSER_MAX_AGE)
+        }
+        else if self.session_life_time_sec == 0
+        {
+            BROWSER_MAX_AGE
+        }
+        else
+        {
+            self.session_life_time_sec
+        }
+    }
+
+    /// Whether the session cookie has the “Secure” attribute.
+    pub fn cookieSecure(&self) -> bool
+    {
+        self.cookie_secure.unwrap_or_else(
+            || self.site_info.url_domain.starts_with("https://"))
+    }
+
+    /// The number of videos in a page of a listing, when the client
+    /// asks for “count” of them. See “max_page_size”.
+    pub fn pageSize(&self, count: Option<u64>) -> u64
+    {
+        match count
+        {
+            Some(count) => count.min(self.max_page_size),
+            None => self.index_page_size,
+        }
+    }
+
+    /// The default video ordering. This has already been validated
+    /// when the config is loaded.
+    pub fn defaultOrder(&self) -> VideoOrder
+    {
+        VideoOrder::fromName(&self.default_order)
+            .unwrap_or(VideoOrder::NewFirst)
+    }
+
+    /// Format of thumbnails. This has already been validated when
+    /// the config is loaded.
+    pub fn thumbnailFormat(&self) -> ThumbnailFormat
+    {
+        ThumbnailFormat::fromName(&self.thumbnail_format)
+            .unwrap_or(ThumbnailFormat::WebP)
+    }
+
+    /// Container of transcoded videos. This has already been
+    /// validated when the config is loaded.
+    pub fn transcodeContainer(&self) -> ContainerType
+    {
+        ContainerType::fromExtension(&self.transcode_container)
+            .unwrap_or(ContainerType::Mp4)
+    }
+
+    /// Path of the sqlite database file.
+    pub fn databasePath(&self) -> PathBuf
+    {
+        Path::new(&self.data_dir).join("db.sqlite")
+    }
+
+    fn bundledImagePath(&self) -> PathBuf
+    {
+        Path::new(&self.static_dir).join("film.svg")
+    }
+
+    /// Path of the logo image file, falling back to the bundled one.
+    pub fn logoPath(&self) -> PathBuf
+    {
+        self.site_info.logo_path.as_ref().map(PathBuf::from)
+            .unwrap_or_else(|| self.bundledImagePath())
+    }
+
+    /// Path of the favicon file, falling back to the bundled one.
+    pub fn faviconPath(&self) -> PathBuf
+    {
+        self.site_info.favicon_path.as_ref().map(PathBuf::from)
+            .unwrap_or_else(|| self.bundledImagePath())
+    }
+
+    /// How upload times are shown on the pages.
+    pub fn timeDisplay(&self) -> TimeDisplay
+    {
+        TimeDisplay::new(self.site_info.timezone.as_deref(),
+                         &self.site_info.time_format)
+    }
+
+    /// Path of the placeholder thumbnail file, falling back to the
+    /// bundled image.
+    pub fn placeholderThumbnailPath(&self) -> PathBuf
+    {
+        self.site_info.placeholder_thumbnail_path.as_ref().map(PathBuf::from)
+            .unwrap_or_else(|| self.bundledImagePath())
     }
 }
 
@@ -84,6 +949,15 @@ impl Default for SiteInfo
             site_title: defaultSiteTitle(),
             footnote: defaultFootnote(),
             url_domain: defaultUrlDomain(),
+            logo_path: defaultLogoPath(),
+            favicon_path: defaultFaviconPath(),
+            placeholder_thumbnail_path: defaultPlaceholderThumbnailPath(),
+            nav_links: defaultNavLinks(),
+            custom_css_url: defaultCustomCSS(),
+            custom_css: defaultCustomCSS(),
+            robots: defaultRobots(),
+            timezone: defaultTimezone(),
+            time_format: defaultTimeFormat(),
         }
     }
 }
@@ -100,10 +974,192 @@ impl Default for Configuration
             listen_port: defaultListenPort(),
             serve_under_path: defaultServePath(),
             upload_size_max: defaultUploadSizeMax(),
+            per_file_size_max: defaultPerFileSizeMax(),
             password: defaultPassword(),
             session_life_time_sec: defaultSessionLifeTime(),
             thumbnail_quality: defaultThumbnailQuality(),
+            regenerate_stale_thumbnails: defaultRegenerateStaleThumbnails(),
+            thumbnail_format: defaultThumbnailFormat(),
+            fast_thumbnail_seek: defaultFastThumbnailSeek(),
+            index_page_size: defaultIndexPageSize(),
+            max_page_size: defaultMaxPageSize(),
+            lazy_thumbnails: defaultLazyThumbnails(),
+            normalize_trailing_slash: defaultNormalizeTrailingSlash(),
+            default_order: defaultDefaultOrder(),
+            id_length_bytes: defaultIdLengthBytes(),
+            allowed_origins: defaultAllowedOrigins(),
+            max_duration_sec: defaultMaxDurationSec(),
+            loop_duration_max_sec: defaultLoopDurationMaxSec(),
+            allowed_containers: defaultAllowedContainers(),
+            transcode_on_upload: defaultTranscodeOnUpload(),
+            transcode_container: defaultTranscodeContainer(),
+            transcode_preset: defaultTranscodePreset(),
+            ffmpeg_threads: defaultFFmpegThreads(),
+            enable_compression: defaultEnableCompression(),
+            default_video_disposition: defaultVideoDisposition(),
+            storage: defaultStorage(),
+            processing_workers: defaultProcessingWorkers(),
+            max_concurrent_uploads: defaultMaxConcurrentUploads(),
+            upload_quota_window_sec: defaultUploadQuotaWindowSec(),
+            upload_quota_count: defaultUploadQuota(),
+            upload_quota_bytes: defaultUploadQuota(),
+            pending_upload_life_time_sec: defaultPendingUploadLifeTimeSec(),
+            enable_hls: defaultEnableHLS(),
+            scrub_thumbnails: defaultScrubThumbnails(),
+            scrub_interval_sec: defaultScrubIntervalSec(),
+            scrub_tile_width: defaultScrubTileWidth(),
+            scrub_tile_height: defaultScrubTileHeight(),
+            scrub_columns: defaultScrubColumns(),
+            upload_video_field: defaultUploadVideoField(),
+            default_artist: defaultDefaultArtist(),
+            metrics_enabled: defaultMetricsEnabled(),
+            metrics_listen_port: defaultMetricsListenPort(),
+            retention_days: defaultRetentionDays(),
+            retention_visibilities: defaultRetentionVisibilities(),
+            session_sliding: defaultSessionSliding(),
+            session_max_life_time_sec: defaultSessionMaxLifeTime(),
+            stats_require_auth: defaultStatsRequireAuth(),
+            dev_mode: defaultDevMode(),
+            uploads_enabled: defaultUploadsEnabled(),
+            cookie_http_only: defaultCookieHttpOnly(),
+            cookie_same_site: defaultCookieSameSite(),
+            cookie_secure: defaultCookieSecure(),
+            cookie_domain: defaultCookieDomain(),
+            history_size: defaultHistorySize(),
+            csp_extra_sources: defaultCSPExtraSources(),
+            frame_ancestors: defaultFrameAncestors(),
+            websub_hub: defaultWebSubHub(),
+            referrer_policy: defaultReferrerPolicy(),
             site_info: SiteInfo::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn testDescribeTomlError()
+    {
+        let content = "listen_port = 1\nvideo_dir = \".\"\nstatic_dir = 2\n";
+        let e = toml::from_str::<Configuration>(content).err().unwrap();
+        assert_eq!(describeTomlError(&e, content),
+                   "invalid type: integer `2`, expected a string at line 3");
+        let content = "listen_port = 1\n";
+        let e = toml::from_str::<Configuration>(content).err().unwrap();
+        assert!(describeTomlError(&e, content)
+                .starts_with("missing field `video_dir`"));
+    }
+
+    #[test]
+    fn testApplyOverrides()
+    {
+        let mut config = Configuration::default();
+        config.applyOverrides(|name| match name
+        {
+            "METUBE_LISTEN_PORT" => Some(String::from("9000")),
+            "METUBE_PASSWORD" => Some(String::from("secret")),
+            "METUBE_SITE_TITLE" => Some(String::from("My Tube")),
+            "METUBE_TRANSCODE_PRESET" => Some(String::from("veryfast")),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.listen_port, 9000);
+        assert_eq!(config.transcode_preset.as_deref(), Some("veryfast"));
+        assert_eq!(config.password, "secret");
+        assert_eq!(config.site_info.site_title, "My Tube");
+        assert_eq!(config.video_dir, ".");
+        assert!(config.applyOverrides(|name| match name
+        {
+            "METUBE_LISTEN_PORT" => Some(String::from("99999")),
+            _ => None,
+        }).is_err());
+        config.applyOverrides(|name| match name
+        {
+            "METUBE_TRANSCODE_PRESET" => Some(String::new()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.transcode_preset, None);
+    }
+
+    #[test]
+    fn testValidate()
+    {
+        let mut config = Configuration::default();
+        assert!(config.validate().is_ok());
+        config.serve_under_path = String::from("/metube");
+        assert!(config.validate().is_ok());
+        config.serve_under_path = String::from("/metube/");
+        assert!(config.validate().is_err());
+        config.serve_under_path = String::from("metube");
+        assert!(config.validate().is_err());
+        config.serve_under_path = String::from("/");
+        config.thumbnail_quality = 101;
+        assert!(config.validate().is_err());
+        config.thumbnail_quality = 100;
+        config.listen_address = String::from("localhost");
+        assert!(config.validate().is_err());
+        config.listen_address = String::from("::1");
+        assert!(config.validate().is_ok());
+        config.listen_address = String::from("unix:/run/metube.sock");
+        assert!(config.validate().is_ok());
+        assert_eq!(config.unixSocketPath(), Some("/run/metube.sock"));
+        config.scrub_columns = 200;
+        assert!(config.validate().is_err());
+        config.scrub_columns = 10;
+        config.scrub_tile_height = 20000;
+        assert!(config.validate().is_err());
+        config.scrub_tile_height = 90;
+        config.site_info.custom_css_url = Some(String::from("/extra.css"));
+        assert!(config.validate().is_ok());
+        assert_eq!(config.customCSSOrigin(), None);
+        config.site_info.custom_css_url =
+            Some(String::from("https://example.org/a.css"));
+        assert!(config.validate().is_ok());
+        assert_eq!(config.customCSSOrigin(), Some("https://example.org"));
+        config.site_info.custom_css_url =
+            Some(String::from("http://example.org:8080?v=1"));
+        assert_eq!(config.customCSSOrigin(), Some("http://example.org:8080"));
+        config.site_info.custom_css_url = Some(String::from("https://"));
+        assert!(config.validate().is_err());
+        config.site_info.custom_css_url =
+            Some(String::from("javascript:alert(1)"));
+        assert!(config.validate().is_err());
+        config.site_info.custom_css_url = None;
+        config.site_info.custom_css =
+            Some(String::from("body{}</STYLE><script>"));
+        assert!(config.validate().is_err());
+        config.site_info.custom_css = None;
+        config.cookie_domain = Some(String::from(".example.org"));
+        assert!(config.validate().is_ok());
+        config.cookie_domain = Some(String::from("example.org; Path=/"));
+        assert!(config.validate().is_err());
+        config.cookie_domain = None;
+        config.transcode_preset = Some(String::from("veryfast"));
+        assert!(config.validate().is_ok());
+        config.transcode_preset = Some(String::from("-crf 0"));
+        assert!(config.validate().is_err());
+        config.transcode_preset = None;
+        config.default_video_disposition = String::from("attachment");
+        assert!(config.validate().is_ok());
+        config.default_video_disposition = String::from("download");
+        assert!(config.validate().is_err());
+        config.default_video_disposition = String::from("inline");
+        config.max_page_size = 0;
+        assert!(config.validate().is_err());
+        config.max_page_size = 200;
+        config.index_page_size = 0;
+        assert!(config.validate().is_err());
+        config.index_page_size = 201;
+        assert!(config.validate().is_err());
+        config.index_page_size = 50;
+        config.retention_days = Some(0);
+        assert!(config.validate().is_err());
+        config.retention_days = Some(30);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.pageSize(None), config.index_page_size);
+        assert_eq!(config.pageSize(Some(1000000)), 200);
+        assert_eq!(config.pageSize(Some(0)), 0);
+    }
+}
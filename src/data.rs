@@ -4,16 +4,209 @@ use std::str::FromStr;
 use log::info;
 use rusqlite as sql;
 use rusqlite::OptionalExtension;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use time::OffsetDateTime;
+use warp::http::status::StatusCode;
 
 use crate::error;
 use crate::error::Error as Error;
-use crate::video::{Video, ContainerType};
+use crate::video::{Video, VideoSummary, ContainerType, Visibility, Chapter};
 use crate::sqlite_connection;
 
+/// Columns of the videos table, in the order expected by
+/// `Manager::row2Video`.
+const VIDEO_COLUMNS: &str = "id, path, title, desc, artist, views, upload_time,
+    container_type, original_filename, duration, thumbnail_path, file_size,
+    bitrate, visibility, uploader, sprite_path, sprite_vtt_path, pinned,
+    thumbnail_quality, autoplay, loop";
+
+/// Columns of the videos table for a `VideoSummary`. The title falls
+/// back to the original filename, like `Video::displayTitle`.
+const SUMMARY_COLUMNS: &str = "id,
+    CASE WHEN title = '' THEN original_filename ELSE title END, artist,
+    views, upload_time, duration, thumbnail_path, file_size";
+
+/// Condition of the public videos uploaded between two Unix
+/// timestamps, both inclusive.
+const PUBLIC_IN_RANGE: &str =
+    "visibility = 'public' AND upload_time >= ? AND upload_time <= ?";
+
+/// A type that `Manager::queryVideos` can read from the videos table.
+trait VideoRow: Sized
+{
+    /// The columns to select, in the order expected by `fromRow`.
+    const COLUMNS: &'static str;
+    fn fromRow(row: &sql::Row) -> sql::Result<Self>;
+}
+
+impl VideoRow for Video
+{
+    const COLUMNS: &'static str = VIDEO_COLUMNS;
+    fn fromRow(row: &sql::Row) -> sql::Result<Self>
+    {
+        Manager::row2Video(row)
+    }
+}
+
+impl VideoRow for VideoSummary
+{
+    const COLUMNS: &'static str = SUMMARY_COLUMNS;
+    fn fromRow(row: &sql::Row) -> sql::Result<Self>
+    {
+        let time_value = row.get(4)?;
+        Ok(Self {
+            id: row.get(0)?,
+            title: row.get(1)?,
+            artist: row.get(2)?,
+            views: row.get(3)?,
+            upload_time: OffsetDateTime::from_unix_timestamp(time_value)
+                .map_err(|_| sql::Error::IntegralValueOutOfRange(
+                    4, time_value))?,
+            duration: time::Duration::seconds_f64(row.get(5)?),
+            thumbnail_path: row.get::<_, Option<String>>(6)?.map(
+                |s| PathBuf::from_str(&s).unwrap()),
+            file_size: row.get::<_, Option<i64>>(7)?.map(|x| x as u64),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum VideoOrder
 {
     NewFirst,
+    OldFirst,
+    MostViewed,
+}
+
+impl VideoOrder
+{
+    pub fn fromName(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "new_first" => Some(Self::NewFirst),
+            "old_first" => Some(Self::OldFirst),
+            "most_viewed" => Some(Self::MostViewed),
+            _ => None,
+        }
+    }
+}
+
+/// Aggregated numbers of the whole library.
+#[derive(Serialize)]
+pub struct LibraryStats
+{
+    pub video_count: u64,
+    /// Sum of the file sizes of all videos in bytes. Videos without a
+    /// recorded size are not counted.
+    pub total_size: u64,
+    pub total_views: u64,
+}
+
+/// The fields of a video to change with `Manager::patchVideo`. The
+/// fields that are None are left as they are.
+#[derive(Default)]
+pub struct VideoPatch
+{
+    pub title: Option<String>,
+    pub desc: Option<String>,
+    pub artist: Option<String>,
+    pub visibility: Option<Visibility>,
+    pub autoplay: Option<bool>,
+    pub looping: Option<bool>,
+}
+
+/// What a session has uploaded in a time window, for the upload
+/// quota.
+pub struct UploadUsage
+{
+    pub count: u64,
+    /// Sum of the sizes of the uploads in bytes.
+    pub bytes: u64,
+    /// Time of the earliest upload in the window.
+    pub earliest_time: Option<OffsetDateTime>,
+}
+
+/// What an API token is allowed to do. A scope allows everything
+/// that the scopes before it allow.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+#[cfg_attr(test, derive(Debug))]
+pub enum APITokenScope
+{
+    /// Read the JSON APIs as a logged-in session would, including
+    /// private videos.
+    ReadOnly,
+    /// Upload videos as well.
+    Upload,
+}
+
+impl APITokenScope
+{
+    pub fn fromName(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "read_only" => Some(Self::ReadOnly),
+            "upload" => Some(Self::Upload),
+            _ => None,
+        }
+    }
+
+    pub fn toName(&self) -> &str
+    {
+        match self
+        {
+            Self::ReadOnly => "read_only",
+            Self::Upload => "upload",
+        }
+    }
+}
+
+/// Whether “e” is from a primary key or a unique constraint, which
+/// means the row already exists. Other constraint violations are
+/// not.
+fn isDuplicateError(e: &sql::Error) -> bool
+{
+    matches!(e, sql::Error::SqliteFailure(err, _) if
+             err.extended_code == sql::ffi::SQLITE_CONSTRAINT_PRIMARYKEY ||
+             err.extended_code == sql::ffi::SQLITE_CONSTRAINT_UNIQUE)
+}
+
+/// The hex SHA-256 hash of an API token, which is what the database
+/// stores.
+fn hashToken(token: &str) -> String
+{
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A token for programmatic access, sent as a bearer token.
+pub struct APIToken
+{
+    /// Name of the token, used to list and revoke it.
+    pub name: String,
+    pub scope: APITokenScope,
+    pub create_time: OffsetDateTime,
+}
+
+/// An upload in progress via the resumable upload protocol.
+pub struct PendingUpload
+{
+    pub id: String,
+    /// Path of the temp file that receives the data, accessible from
+    /// the CWD. The current offset of the upload is the size of this
+    /// file.
+    pub temp_path: PathBuf,
+    pub original_filename: String,
+    /// Total number of bytes of the upload.
+    pub length: u64,
+    /// Visibility of the video when the upload completes.
+    pub visibility: Visibility,
+    /// See `Video::uploader`.
+    pub uploader: String,
+    pub create_time: OffsetDateTime,
 }
 
 #[derive(Clone)]
@@ -65,11 +258,41 @@ impl Manager
             sqlite_connection::Source::Memory =>
                 sqlite_connection::Manager::memory(),
         };
-        self.connection = Some(r2d2::Pool::new(manager).map_err(
+        // Every connection to “:memory:” is a separate database, so
+        // the pool can only hold one connection in that case.
+        let max_size = match &self.filename
+        {
+            sqlite_connection::Source::File(_) => 10,
+            sqlite_connection::Source::Memory => 1,
+        };
+        self.connection = Some(r2d2::Pool::builder().max_size(max_size)
+                               .build(manager).map_err(
             |e| rterr!("Failed to create connection pool: {}", e))?);
         Ok(())
     }
 
+    /// Add a column to a table if it does not exist yet. This
+    /// upgrades databases created by older versions.
+    fn addColumnIfMissing(conn: &sql::Connection, table: &str, column: &str,
+                          decl: &str) -> Result<(), Error>
+    {
+        let mut cmd = conn.prepare(&format!("PRAGMA table_info({});", table))
+            .map_err(|e| error!(DataError, "Failed to get table info: {}", e))?;
+        let columns = cmd.query_map([], |row| row.get::<_, String>(1))
+            .map_err(|e| error!(DataError, "Failed to get table info: {}", e))?
+            .collect::<sql::Result<Vec<String>>>()
+            .map_err(|e| error!(DataError, "Failed to get table info: {}", e))?;
+        if columns.iter().any(|c| c == column)
+        {
+            return Ok(());
+        }
+        info!("Adding column {} to table {}...", column, table);
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {};",
+                              table, column, decl), []).map_err(
+            |e| error!(DataError, "Failed to add column {}: {}", column, e))?;
+        Ok(())
+    }
+
     pub fn init(&self) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
@@ -88,12 +311,122 @@ impl Manager
              thumbnail_path TEXT
              );", []).map_err(
             |e| error!(DataError, "Failed to create table: {}", e))?;
+        // Columns added after the initial schema.
+        Self::addColumnIfMissing(&conn, "videos", "file_size", "INTEGER")?;
+        Self::addColumnIfMissing(&conn, "videos", "bitrate", "INTEGER")?;
+        Self::addColumnIfMissing(&conn, "videos", "visibility",
+                                 "TEXT NOT NULL DEFAULT 'public'")?;
+        Self::addColumnIfMissing(&conn, "videos", "uploader",
+                                 "TEXT NOT NULL DEFAULT ''")?;
+        Self::addColumnIfMissing(&conn, "videos", "sprite_path", "TEXT")?;
+        Self::addColumnIfMissing(&conn, "videos", "sprite_vtt_path", "TEXT")?;
+        // “pin_time” is when the video is last pinned, so that pinned
+        // videos are listed in the order they are pinned.
+        Self::addColumnIfMissing(&conn, "videos", "pinned",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        Self::addColumnIfMissing(&conn, "videos", "pin_time", "INTEGER")?;
+        Self::addColumnIfMissing(&conn, "videos", "thumbnail_quality",
+                                 "INTEGER")?;
+        Self::addColumnIfMissing(&conn, "videos", "autoplay",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        Self::addColumnIfMissing(&conn, "videos", "loop",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS uploads (
+             id TEXT PRIMARY KEY,
+             temp_path TEXT,
+             original_filename TEXT,
+             length INTEGER,
+             create_time INTEGER
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        Self::addColumnIfMissing(&conn, "uploads", "visibility",
+                                 "TEXT NOT NULL DEFAULT 'public'")?;
+        Self::addColumnIfMissing(&conn, "uploads", "uploader",
+                                 "TEXT NOT NULL DEFAULT ''")?;
+        // When the upload last received data.
+        Self::addColumnIfMissing(&conn, "uploads", "update_time",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        conn.execute("UPDATE uploads SET update_time = create_time
+                      WHERE update_time = 0;", []).map_err(
+            |e| error!(DataError, "Failed to migrate uploads: {}", e))?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS sessions (
              token TEXT PRIMARY KEY,
              auth_time INTEGER
              );", []).map_err(
             |e| error!(DataError, "Failed to create table: {}", e))?;
+        // “auth_time” is when the session was last used if sessions
+        // are sliding, and “create_time” is when it was created.
+        Self::addColumnIfMissing(&conn, "sessions", "create_time",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        conn.execute("UPDATE sessions SET create_time = auth_time
+                      WHERE create_time = 0;", []).map_err(
+            |e| error!(DataError, "Failed to migrate sessions: {}", e))?;
+        // The user who logged in to the session. This is empty if the
+        // session is created with the site password.
+        Self::addColumnIfMissing(&conn, "sessions", "username",
+                                 "TEXT NOT NULL DEFAULT ''")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS api_tokens (
+             token TEXT PRIMARY KEY,
+             name TEXT NOT NULL UNIQUE,
+             scope TEXT NOT NULL,
+             create_time INTEGER
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        // “token” is the SHA-256 hash of the token from `hashToken`.
+        // Older versions stored the token itself, with “hashed” being
+        // 0.
+        Self::addColumnIfMissing(&conn, "api_tokens", "hashed",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+             username TEXT PRIMARY KEY,
+             password_hash TEXT NOT NULL,
+             create_time INTEGER
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS watch_positions (
+             token TEXT,
+             video_id TEXT,
+             position REAL,
+             update_time INTEGER,
+             PRIMARY KEY (token, video_id)
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS view_history (
+             token TEXT,
+             video_id TEXT,
+             view_time INTEGER,
+             PRIMARY KEY (token, video_id)
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS chapters (
+             video_id TEXT,
+             start_sec REAL,
+             title TEXT
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS session_uploads (
+             token TEXT,
+             upload_time INTEGER,
+             size INTEGER
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        Self::hashAPITokens(&conn)?;
+        // Videos that are admitted but not processed yet. The
+        // processing queue is only in memory, so these are queued
+        // again at startup.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS unprocessed_videos (
+             video_id TEXT PRIMARY KEY
+             );", []).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
         Ok(())
     }
 
@@ -102,6 +435,7 @@ impl Manager
         let time_value = row.get(6)?;
         let path: String = row.get(1)?;
         let ext: String = row.get(7)?;
+        let visibility: String = row.get(13)?;
         Ok(Video {
             id: row.get(0)?,
             path: PathBuf::from_str(&path).unwrap(),
@@ -122,17 +456,36 @@ impl Manager
             duration: time::Duration::seconds_f64(row.get(9)?),
             thumbnail_path: row.get::<_, Option<String>>(10)?.map(
                 |s| PathBuf::from_str(&s).unwrap()),
+            file_size: row.get::<_, Option<i64>>(11)?.map(|x| x as u64),
+            bitrate: row.get::<_, Option<i64>>(12)?.map(|x| x as u64),
+            visibility: Visibility::fromName(&visibility)
+                .ok_or_else(|| sql::Error::FromSqlConversionFailure(
+                    13, sql::types::Type::Text,
+                    Box::new(rterr!("Invalid visibility from database: {}",
+                                    visibility))))?,
+            uploader: row.get(14)?,
+            sprite_path: row.get::<_, Option<String>>(15)?.map(
+                |s| PathBuf::from_str(&s).unwrap()),
+            sprite_vtt_path: row.get::<_, Option<String>>(16)?.map(
+                |s| PathBuf::from_str(&s).unwrap()),
+            pinned: row.get(17)?,
+            thumbnail_quality: row.get(18)?,
+            autoplay: row.get(19)?,
+            looping: row.get(20)?,
+            chapters: Vec::new(),
         })
     }
 
+    /// Insert a new video. If a video with the same ID or path
+    /// exists, this is a `CONFLICT`.
     pub fn addVideo(&self, vid: &Video) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
         let row_count = conn.execute(
-            "INSERT INTO videos (id, path, title, desc, artist, views,
-                                 upload_time, container_type, original_filename,
-                                 duration, thumbnail_path)
-             VALUES (?, ?, ?, ?, ?, 0, ?, ?, ?, ?, ?);", sql::params![
+            &format!("INSERT INTO videos ({})
+                      VALUES (?, ?, ?, ?, ?, 0, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+                              ?, ?, ?, ?, ?);",
+                     VIDEO_COLUMNS), sql::params![
                  &vid.id,
                  &vid.path.to_str().ok_or_else(
                      || rterr!("Invalid video path: {:?}", vid.path))?,
@@ -144,8 +497,25 @@ impl Manager
                  &vid.original_filename,
                  vid.duration.as_seconds_f64(),
                  &vid.thumbnail_path.as_ref().map(|p| p.to_str().unwrap()),
-
-             ]).map_err(|e| error!(DataError, "Failed to add video: {}", e))?;
+                 vid.file_size.map(|x| x as i64),
+                 vid.bitrate.map(|x| x as i64),
+                 vid.visibility.toName(),
+                 &vid.uploader,
+                 &vid.sprite_path.as_ref().map(|p| p.to_str().unwrap()),
+                 &vid.sprite_vtt_path.as_ref().map(|p| p.to_str().unwrap()),
+                 vid.pinned,
+                 vid.thumbnail_quality,
+                 vid.autoplay,
+                 vid.looping,
+             ]).map_err(|e| if isDuplicateError(&e)
+             {
+                 Error::HTTPStatus(StatusCode::CONFLICT, format!(
+                     "Video {} already exists", vid.id))
+             }
+             else
+             {
+                 error!(DataError, "Failed to add video: {}", e)
+             })?;
         if row_count != 1
         {
             return Err(error!(DataError, "Invalid insert happened"));
@@ -153,115 +523,1582 @@ impl Manager
         Ok(())
     }
 
+    /// Insert the video, or overwrite every column of the video with
+    /// the same ID. Unlike `addVideo`, the view count is taken from
+    /// “vid”.
+    pub fn upsertVideo(&self, vid: &Video) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            &format!("INSERT INTO videos ({})
+                      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+                              ?, ?, ?, ?, ?)
+                      ON CONFLICT(id) DO UPDATE SET
+                      path=excluded.path, title=excluded.title,
+                      desc=excluded.desc, artist=excluded.artist,
+                      views=excluded.views, upload_time=excluded.upload_time,
+                      container_type=excluded.container_type,
+                      original_filename=excluded.original_filename,
+                      duration=excluded.duration,
+                      thumbnail_path=excluded.thumbnail_path,
+                      file_size=excluded.file_size, bitrate=excluded.bitrate,
+                      visibility=excluded.visibility,
+                      uploader=excluded.uploader,
+                      sprite_path=excluded.sprite_path,
+                      sprite_vtt_path=excluded.sprite_vtt_path,
+                      pinned=excluded.pinned,
+                      thumbnail_quality=excluded.thumbnail_quality,
+                      autoplay=excluded.autoplay, loop=excluded.loop;",
+                     VIDEO_COLUMNS), sql::params![
+                 &vid.id,
+                 &vid.path.to_str().ok_or_else(
+                     || rterr!("Invalid video path: {:?}", vid.path))?,
+                 &vid.title,
+                 &vid.desc,
+                 &vid.artist,
+                 vid.views,
+                 vid.upload_time.unix_timestamp(),
+                 vid.container_type.toExtension(),
+                 &vid.original_filename,
+                 vid.duration.as_seconds_f64(),
+                 &vid.thumbnail_path.as_ref().map(|p| p.to_str().unwrap()),
+                 vid.file_size.map(|x| x as i64),
+                 vid.bitrate.map(|x| x as i64),
+                 vid.visibility.toName(),
+                 &vid.uploader,
+                 &vid.sprite_path.as_ref().map(|p| p.to_str().unwrap()),
+                 &vid.sprite_vtt_path.as_ref().map(|p| p.to_str().unwrap()),
+                 vid.pinned,
+                 vid.thumbnail_quality,
+                 vid.autoplay,
+                 vid.looping,
+             ]).map_err(|e| error!(DataError, "Failed to upsert video {}: {}",
+                                   vid.id, e))?;
+        Ok(())
+    }
+
     pub fn findVideoByID(&self, id: &str) -> Result<Option<Video>, Error>
     {
         let conn = self.confirmConnection()?;
-        conn.query_row("SELECT id, path, title, desc, artist, views,
-                        upload_time, container_type, original_filename, duration,
-                        thumbnail_path
-                        FROM videos WHERE id=?;",
+        conn.query_row(&format!("SELECT {} FROM videos WHERE id=?;",
+                                VIDEO_COLUMNS),
                        sql::params![id], Self::row2Video)
 
             .optional().map_err(
                 |e| error!(DataError, "Failed to look up video {}: {}", id, e))
     }
 
-    pub fn increaseViewCount(&self, id: &str) -> Result<(), Error>
+    /// Find the video that owns the file at the relative path “path”
+    /// in the video directory, which is its video file, thumbnail,
+    /// or sprite sheet or its WebVTT file.
+    pub fn findVideoByFile(&self, path: &str) -> Result<Option<Video>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.query_row(&format!(
+            "SELECT {} FROM videos WHERE path=?1 OR thumbnail_path=?1 OR
+             sprite_path=?1 OR sprite_vtt_path=?1;", VIDEO_COLUMNS),
+                       sql::params![path], Self::row2Video)
+            .optional().map_err(
+                |e| error!(DataError, "Failed to look up video of {}: {}",
+                           path, e))
+    }
+
+    /// Delete the videos with the given IDs in a single transaction,
+    /// together with their chapters, watch positions and history
+    /// entries.
+    /// Return the deleted videos, so that their files can be removed.
+    /// IDs that are not found are skipped.
+    pub fn deleteVideos(&self, ids: &[String]) -> Result<Vec<Video>, Error>
+    {
+        let mut conn = self.confirmConnection()?;
+        let tx = conn.transaction().map_err(
+            |e| error!(DataError, "Failed to start transaction: {}", e))?;
+        let mut deleted = Vec::new();
+        for id in ids
+        {
+            let video = tx.query_row(
+                &format!("SELECT {} FROM videos WHERE id=?;", VIDEO_COLUMNS),
+                [id], Self::row2Video).optional().map_err(
+                |e| error!(DataError, "Failed to look up video {}: {}", id, e))?;
+            let video = match video
+            {
+                Some(v) => v,
+                None => continue,
+            };
+            for table in ["videos", "chapters", "watch_positions",
+                          "view_history", "unprocessed_videos"]
+            {
+                let column = if table == "videos" { "id" } else { "video_id" };
+                tx.execute(&format!("DELETE FROM {} WHERE {}=?;",
+                                    table, column), [id])
+                    .map_err(|e| error!(DataError,
+                                        "Failed to delete video {}: {}",
+                                        id, e))?;
+            }
+            deleted.push(video);
+        }
+        tx.commit().map_err(
+            |e| error!(DataError, "Failed to commit transaction: {}", e))?;
+        Ok(deleted)
+    }
+
+    /// Update the metadata of an existing video in place. The view
+    /// count and upload time are left untouched.
+    pub fn updateVideo(&self, vid: &Video) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
         let row_count = conn.execute(
-            "UPDATE videos SET views = views + 1 WHERE id=?;",
-            sql::params![id]).map_err(|e| error!(
-                DataError, "Failed to increase view count for video {}: {}",
-                id, e))?;
+            "UPDATE videos SET path=?, title=?, desc=?, artist=?,
+             container_type=?, duration=?, thumbnail_path=?, file_size=?,
+             bitrate=?, visibility=?, sprite_path=?, sprite_vtt_path=?,
+             thumbnail_quality=? WHERE id=?;", sql::params![
+                 &vid.path.to_str().ok_or_else(
+                     || rterr!("Invalid video path: {:?}", vid.path))?,
+                 &vid.title,
+                 &vid.desc,
+                 &vid.artist,
+                 vid.container_type.toExtension(),
+                 vid.duration.as_seconds_f64(),
+                 &vid.thumbnail_path.as_ref().map(|p| p.to_str().unwrap()),
+                 vid.file_size.map(|x| x as i64),
+                 vid.bitrate.map(|x| x as i64),
+                 vid.visibility.toName(),
+                 &vid.sprite_path.as_ref().map(|p| p.to_str().unwrap()),
+                 &vid.sprite_vtt_path.as_ref().map(|p| p.to_str().unwrap()),
+                 vid.thumbnail_quality,
+                 &vid.id,
+             ]).map_err(|e| error!(DataError, "Failed to update video {}: {}",
+                                   vid.id, e))?;
         if row_count != 1
         {
-            return Err(error!(
-                DataError,
-                "Failed to increase view count for video {}: \
-                 number of affected rows: {} != 1.", id, row_count));
+            return Err(error!(DataError, "Video {} not found", vid.id));
         }
         Ok(())
     }
 
-    /// Retrieve “count” number of videos, starting from the entry at
-    /// index “start_index”. Index is 0-based. Returned entries are
-    /// sorted from new to old.
-    pub fn getVideos(&self, start_index: u64, count: u64, order: VideoOrder) ->
-        Result<Vec<Video>, Error>
+    /// Update the columns of an existing video that are set by
+    /// `Video::process`: its file and its properties, and the
+    /// thumbnails. The other metadata may have been edited while the
+    /// video was waiting to be processed, and are left untouched.
+    pub fn updateProcessedVideo(&self, vid: &Video) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
-
-        let order_expr = match order
+        let row_count = conn.execute(
+            "UPDATE videos SET path=?, container_type=?, duration=?,
+             thumbnail_path=?, file_size=?, bitrate=?, sprite_path=?,
+             sprite_vtt_path=?, thumbnail_quality=? WHERE id=?;",
+            sql::params![
+                &vid.path.to_str().ok_or_else(
+                    || rterr!("Invalid video path: {:?}", vid.path))?,
+                vid.container_type.toExtension(),
+                vid.duration.as_seconds_f64(),
+                &vid.thumbnail_path.as_ref().map(|p| p.to_str().unwrap()),
+                vid.file_size.map(|x| x as i64),
+                vid.bitrate.map(|x| x as i64),
+                &vid.sprite_path.as_ref().map(|p| p.to_str().unwrap()),
+                &vid.sprite_vtt_path.as_ref().map(|p| p.to_str().unwrap()),
+                vid.thumbnail_quality,
+                &vid.id,
+            ]).map_err(|e| error!(DataError, "Failed to update video {}: {}",
+                                  vid.id, e))?;
+        if row_count != 1
         {
-            VideoOrder::NewFirst => "ORDER BY upload_time DESC",
-        };
+            return Err(error!(DataError, "Video {} not found", vid.id));
+        }
+        Ok(())
+    }
 
-        let mut cmd = conn.prepare(
-            &format!("SELECT id, path, title, desc, artist, views, upload_time,
-                      container_type, original_filename, duration,
-                      thumbnail_path
-                      FROM videos {} LIMIT ? OFFSET ?;", order_expr))
-            .map_err(|e| error!(
-                DataError,
-                "Failed to compare statement to get videos: {}", e))?;
-        let rows = cmd.query_map([count, start_index], Self::row2Video).map_err(
-            |e| error!(DataError, "Failed to retrieve videos: {}", e))?.map(
-            |row| row.map_err(|e| error!(DataError, "{}", e)));
-        rows.collect()
+    /// Mark video “id” as waiting to be processed.
+    pub fn addUnprocessedVideo(&self, id: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("INSERT OR IGNORE INTO unprocessed_videos (video_id)
+                      VALUES (?);", [id]).map_err(
+            |e| error!(DataError, "Failed to mark video {} as unprocessed: {}",
+                       id, e))?;
+        Ok(())
+    }
+
+    /// Mark video “id” as no longer waiting to be processed, whether
+    /// processing succeeded or not.
+    pub fn removeUnprocessedVideo(&self, id: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("DELETE FROM unprocessed_videos WHERE video_id=?;", [id])
+            .map_err(|e| error!(DataError,
+                                "Failed to mark video {} as processed: {}",
+                                id, e))?;
+        Ok(())
     }
 
-    pub fn createSession(&self, token: &str) -> Result<(), Error>
+    /// Retrieve the videos waiting to be processed, from old to new.
+    pub fn getUnprocessedVideos(&self) -> Result<Vec<Video>, Error>
+    {
+        self.queryVideos("id IN (SELECT video_id FROM unprocessed_videos)",
+                         &[], 0, i64::MAX as u64, VideoOrder::OldFirst)
+    }
+
+    /// Point video “id” to the file at “path”, relative to the video
+    /// directory, such as after the file is renamed outside of
+    /// MeTube. Nothing else about the video is changed.
+    pub fn updateVideoPath(&self, id: &str, path: &Path) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
         let row_count = conn.execute(
-            "INSERT INTO sessions (token, auth_time)
-             VALUES (?, ?);", sql::params![
-                 token,
-                 OffsetDateTime::now_utc().unix_timestamp(),
-             ]).map_err(|e| error!(DataError, "Failed to create session: {}", e))?;
+            "UPDATE videos SET path=? WHERE id=?;", sql::params![
+                path.to_str().ok_or_else(
+                    || rterr!("Invalid video path: {:?}", path))?,
+                id,
+            ]).map_err(|e| if isDuplicateError(&e)
+            {
+                Error::HTTPStatus(StatusCode::CONFLICT, format!(
+                    "Path {:?} already belongs to another video", path))
+            }
+            else
+            {
+                error!(DataError, "Failed to update path of video {}: {}", id, e)
+            })?;
         if row_count != 1
         {
-            return Err(error!(DataError, "Invalid insert happened"));
+            return Err(error!(DataError, "Video {} not found", id));
         }
         Ok(())
     }
 
-    /// Return time of authentication of the token.
-    pub fn hasSession(&self, token: &str) -> Result<OffsetDateTime, Error>
+    /// Replace the chapters of video “id” with “chapters”.
+    pub fn setChapters(&self, id: &str, chapters: &[Chapter]) ->
+        Result<(), Error>
+    {
+        let mut conn = self.confirmConnection()?;
+        let tx = conn.transaction().map_err(
+            |e| error!(DataError, "Failed to start transaction: {}", e))?;
+        tx.execute("DELETE FROM chapters WHERE video_id=?;", [id]).map_err(
+            |e| error!(DataError, "Failed to delete chapters of video {}: {}",
+                       id, e))?;
+        for chapter in chapters
+        {
+            tx.execute("INSERT INTO chapters (video_id, start_sec, title)
+                        VALUES (?, ?, ?);",
+                       sql::params![id, chapter.start.as_seconds_f64(),
+                                    &chapter.title])
+                .map_err(|e| error!(DataError,
+                                    "Failed to add chapter of video {}: {}",
+                                    id, e))?;
+        }
+        tx.commit().map_err(
+            |e| error!(DataError, "Failed to commit transaction: {}", e))
+    }
+
+    /// Return the chapters of video “id”, ordered by their start
+    /// time.
+    pub fn getChapters(&self, id: &str) -> Result<Vec<Chapter>, Error>
     {
         let conn = self.confirmConnection()?;
         let mut cmd = conn.prepare(
-            "SELECT auth_time FROM sessions WHERE token=?;")
+            "SELECT start_sec, title FROM chapters WHERE video_id=?
+             ORDER BY start_sec, rowid;")
             .map_err(|e| error!(
                 DataError,
-                "Failed to prepare statement to lookup session: {}", e))?;
-        if let Some(auth_time_sec) = cmd.query_row([token,], |row| row.get(0))
-            .optional().map_err(
-                |e| error!(DataError, "Failed to look up session: {}", e))?
+                "Failed to prepare statement to get chapters: {}", e))?;
+        let rows = cmd.query_map([id], |row| Ok(Chapter {
+            start: time::Duration::seconds_f64(row.get(0)?),
+            title: row.get(1)?,
+        })).map_err(|e| error!(DataError, "Failed to retrieve chapters: {}", e))?
+            .map(|row| row.map_err(|e| error!(DataError, "{}", e)));
+        rows.collect()
+    }
+
+    /// Change only the fields of video “id” that are set in “patch”.
+    pub fn patchVideo(&self, id: &str, patch: &VideoPatch) ->
+        Result<(), Error>
+    {
+        use sql::types::Value;
+        let text = |s: &str| Value::Text(s.to_owned());
+        let fields: Vec<(&str, Value)> = [
+            ("title", patch.title.as_deref().map(text)),
+            ("desc", patch.desc.as_deref().map(text)),
+            ("artist", patch.artist.as_deref().map(text)),
+            ("visibility", patch.visibility.as_ref().map(|v| text(v.toName()))),
+            ("autoplay", patch.autoplay.map(|b| Value::Integer(b as i64))),
+            ("loop", patch.looping.map(|b| Value::Integer(b as i64))),
+        ].into_iter().filter_map(|(column, value)| value.map(|v| (column, v)))
+            .collect();
+        if fields.is_empty()
         {
-            OffsetDateTime::from_unix_timestamp(auth_time_sec).map_err(
-                |_| rterr!("Invalid auth time"))
+            return Ok(());
         }
-        else
+        let assignments: Vec<String> = fields.iter()
+            .map(|(column, _)| format!("{}=?", column)).collect();
+        let mut params: Vec<&dyn sql::ToSql> = fields.iter()
+            .map(|(_, value)| value as &dyn sql::ToSql).collect();
+        params.push(&id);
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            &format!("UPDATE videos SET {} WHERE id=?;",
+                     assignments.join(", ")), params.as_slice())
+            .map_err(|e| error!(DataError, "Failed to update video {}: {}",
+                                id, e))?;
+        if row_count != 1
         {
-            Err(rterr!("Session not found"))
+            return Err(error!(DataError, "Video {} not found", id));
         }
+        Ok(())
     }
 
-    pub fn expireSessions(&self, life_time_sec: u64) -> Result<(), Error>
+    /// Pin or unpin a video to the top of the index.
+    pub fn setPinned(&self, id: &str, pinned: bool) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
-        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let pin_time = if pinned
+        {
+            Some(OffsetDateTime::now_utc().unix_timestamp())
+        }
+        else
+        {
+            None
+        };
         let row_count = conn.execute(
-            "DELETE FROM sessions WHERE auth_time < ?;",
-            sql::params![now as u64 - life_time_sec])
-            .map_err(|e| error!(DataError, "Failed to expire sessions: {}", e))?;
-        if row_count > 0
+            "UPDATE videos SET pinned=?, pin_time=? WHERE id=?;",
+            sql::params![pinned, pin_time, id]).map_err(
+            |e| error!(DataError, "Failed to pin video {}: {}", id, e))?;
+        if row_count != 1
         {
-            info!("Expired {} sessions.", row_count);
+            return Err(error!(DataError, "Video {} not found", id));
         }
         Ok(())
     }
 
+    /// Retrieve the pinned public videos, the most recently pinned
+    /// first.
+    pub fn getPinnedVideos(&self) -> Result<Vec<Video>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let mut cmd = conn.prepare(
+            &format!("SELECT {} FROM videos
+                      WHERE pinned AND visibility = 'public'
+                      ORDER BY pin_time DESC, rowid DESC;", VIDEO_COLUMNS))
+            .map_err(|e| error!(
+                DataError,
+                "Failed to prepare statement to get pinned videos: {}", e))?;
+        let rows = cmd.query_map([], Self::row2Video)
+            .map_err(|e| error!(DataError,
+                                "Failed to retrieve pinned videos: {}", e))?
+            .map(|row| row.map_err(|e| error!(DataError, "{}", e)));
+        rows.collect()
+    }
+
+    /// Increase the view count of a video by one. The increment is
+    /// done in a single UPDATE, so concurrent calls do not lose
+    /// counts. It is not an error if the video does not exist, which
+    /// happens if it is deleted while being viewed.
+    pub fn increaseViewCount(&self, id: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "UPDATE videos SET views = views + 1 WHERE id=?;",
+            sql::params![id]).map_err(|e| error!(
+                DataError, "Failed to increase view count for video {}: {}",
+                id, e))?;
+        Ok(())
+    }
+
+    /// Retrieve videos matching the SQL condition “filter”, which is
+    /// put in the WHERE clause. “params” are bound to the parameters
+    /// in “filter”.
+    fn queryVideos<T: VideoRow>(&self, filter: &str,
+                                params: &[&dyn sql::ToSql], start_index: u64,
+                                count: u64, order: VideoOrder) ->
+        Result<Vec<T>, Error>
+    {
+        let conn = self.confirmConnection()?;
+
+        let order_expr = match order
+        {
+            VideoOrder::NewFirst => "ORDER BY upload_time DESC",
+            VideoOrder::OldFirst => "ORDER BY upload_time ASC",
+            VideoOrder::MostViewed => "ORDER BY views DESC, upload_time DESC",
+        };
+
+        let mut cmd = conn.prepare(
+            &format!("SELECT {} FROM videos WHERE {} {} LIMIT ? OFFSET ?;",
+                     T::COLUMNS, filter, order_expr))
+            .map_err(|e| error!(
+                DataError,
+                "Failed to compare statement to get videos: {}", e))?;
+        let count = count.min(i64::MAX as u64) as i64;
+        let start_index = start_index.min(i64::MAX as u64) as i64;
+        let mut all_params: Vec<&dyn sql::ToSql> = params.to_vec();
+        all_params.push(&count);
+        all_params.push(&start_index);
+        let rows = cmd.query_map(all_params.as_slice(), T::fromRow)
+            .map_err(|e| error!(DataError, "Failed to retrieve videos: {}", e))?
+            .map(|row| row.map_err(|e| error!(DataError, "{}", e)));
+        rows.collect()
+    }
+
+    /// Retrieve “count” number of public videos, starting from the
+    /// entry at index “start_index”. Index is 0-based. Returned
+    /// entries are sorted according to “order”.
+    pub fn getVideos(&self, start_index: u64, count: u64, order: VideoOrder) ->
+        Result<Vec<Video>, Error>
+    {
+        self.getVideosInRange(start_index, count, order, None, None)
+    }
+
+    /// Like `getVideos`, but only return videos uploaded between
+    /// “from” and “to” (both inclusive, as Unix timestamps). A bound
+    /// of None is unlimited.
+    pub fn getVideosInRange(&self, start_index: u64, count: u64,
+                            order: VideoOrder, from: Option<i64>,
+                            to: Option<i64>) -> Result<Vec<Video>, Error>
+    {
+        let from = from.unwrap_or(i64::MIN);
+        let to = to.unwrap_or(i64::MAX);
+        self.queryVideos(PUBLIC_IN_RANGE, &[&from, &to], start_index, count,
+                         order)
+    }
+
+    /// Count the public videos uploaded between “from” and “to”, like
+    /// in `getVideosInRange`.
+    pub fn countVideos(&self, from: Option<i64>, to: Option<i64>) ->
+        Result<u64, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let from = from.unwrap_or(i64::MIN);
+        let to = to.unwrap_or(i64::MAX);
+        conn.query_row(
+            &format!("SELECT COUNT(*) FROM videos WHERE {};", PUBLIC_IN_RANGE),
+            sql::params![from, to], |row| row.get::<_, i64>(0))
+            .map(|n| n as u64)
+            .map_err(|e| error!(DataError, "Failed to count videos: {}", e))
+    }
+
+    /// Like `getVideosInRange`, but only load what the listings show.
+    pub fn getVideoSummaries(&self, start_index: u64, count: u64,
+                             order: VideoOrder, from: Option<i64>,
+                             to: Option<i64>) ->
+        Result<Vec<VideoSummary>, Error>
+    {
+        let from = from.unwrap_or(i64::MIN);
+        let to = to.unwrap_or(i64::MAX);
+        self.queryVideos(PUBLIC_IN_RANGE, &[&from, &to], start_index, count,
+                         order)
+    }
+
+    /// Retrieve public videos by “artist”, from new to old. An empty
+    /// “artist” matches the videos without an artist.
+    pub fn getVideosByArtist(&self, artist: &str, start_index: u64,
+                             count: u64) -> Result<Vec<VideoSummary>, Error>
+    {
+        self.queryVideos("visibility = 'public' AND artist = ?", &[&artist],
+                         start_index, count, VideoOrder::NewFirst)
+    }
+
+    /// Return the IDs of the public videos right before and after
+    /// “video” in the listing sorted by “order”. An ID is None if
+    /// “video” is at that end of the listing. Videos with the same
+    /// sort key are ordered by ID.
+    pub fn getAdjacentVideos(&self, video: &Video, order: VideoOrder) ->
+        Result<(Option<String>, Option<String>), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let upload_time = video.upload_time.unix_timestamp();
+        let views = video.views as i64;
+        let (columns, values, descending): (&str, Vec<&dyn sql::ToSql>, bool) =
+            match order
+        {
+            VideoOrder::NewFirst =>
+                ("upload_time, id", vec![&upload_time, &video.id], true),
+            VideoOrder::OldFirst =>
+                ("upload_time, id", vec![&upload_time, &video.id], false),
+            VideoOrder::MostViewed =>
+                ("views, upload_time, id",
+                 vec![&views, &upload_time, &video.id], true),
+        };
+        let placeholders = vec!["?"; values.len()].join(", ");
+        // Find the closest video whose key is greater or less than
+        // that of “video”.
+        let closest = |greater: bool| -> Result<Option<String>, Error> {
+            let (op, dir) = if greater { (">", "ASC") } else { ("<", "DESC") };
+            let order_expr = columns.split(", ")
+                .map(|c| format!("{} {}", c, dir))
+                .collect::<Vec<_>>().join(", ");
+            conn.query_row(
+                &format!("SELECT id FROM videos WHERE visibility = 'public'
+                          AND ({}) {} ({}) ORDER BY {} LIMIT 1;",
+                         columns, op, placeholders, order_expr),
+                values.as_slice(), |row| row.get(0)).optional()
+                .map_err(|e| error!(DataError,
+                                    "Failed to find adjacent video: {}", e))
+        };
+        if descending
+        {
+            Ok((closest(true)?, closest(false)?))
+        }
+        else
+        {
+            Ok((closest(false)?, closest(true)?))
+        }
+    }
+
+    /// Retrieve the videos uploaded before “time” (a Unix timestamp)
+    /// regardless of visibility, from old to new.
+    pub fn getVideosUploadedBefore(&self, time: i64) ->
+        Result<Vec<Video>, Error>
+    {
+        self.queryVideos("upload_time < ?", &[&time], 0, i64::MAX as u64,
+                         VideoOrder::OldFirst)
+    }
+
+    /// Return the IDs of all videos regardless of visibility.
+    pub fn allVideoIds(&self) -> Result<Vec<String>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let mut cmd = conn.prepare("SELECT id FROM videos;").map_err(
+            |e| error!(DataError, "Failed to prepare statement: {}", e))?;
+        let rows = cmd.query_map([], |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to retrieve IDs: {}", e))?
+            .map(|row| row.map_err(|e| error!(DataError, "{}", e)));
+        rows.collect()
+    }
+
+    /// Retrieve all videos regardless of visibility, from old to new.
+    pub fn getAllVideos(&self) -> Result<Vec<Video>, Error>
+    {
+        self.queryVideos("1", &[], 0, i64::MAX as u64, VideoOrder::OldFirst)
+    }
+
+    /// Retrieve the videos whose thumbnail is generated with a
+    /// quality other than “quality”. Videos whose thumbnail quality
+    /// is unknown are left out.
+    pub fn getVideosWithStaleThumbnail(&self, quality: u8) ->
+        Result<Vec<Video>, Error>
+    {
+        self.queryVideos(
+            "thumbnail_quality IS NOT NULL AND thumbnail_quality != ?",
+            &[&quality], 0, i64::MAX as u64, VideoOrder::OldFirst)
+    }
+
+    pub fn getLibraryStats(&self) -> Result<LibraryStats, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(file_size), 0),
+             COALESCE(SUM(views), 0) FROM videos;", [], |row| {
+                 Ok(LibraryStats {
+                     video_count: row.get::<_, i64>(0)? as u64,
+                     total_size: row.get::<_, i64>(1)? as u64,
+                     total_views: row.get::<_, i64>(2)? as u64,
+                 })
+             }).map_err(|e| error!(DataError, "Failed to get stats: {}", e))
+    }
+
+    fn row2PendingUpload(row: &sql::Row) -> sql::Result<PendingUpload>
+    {
+        let path: String = row.get(1)?;
+        let visibility: String = row.get(4)?;
+        let time_value = row.get(6)?;
+        Ok(PendingUpload {
+            id: row.get(0)?,
+            temp_path: PathBuf::from(path),
+            original_filename: row.get(2)?,
+            length: row.get::<_, i64>(3)? as u64,
+            visibility: Visibility::fromName(&visibility)
+                .unwrap_or(Visibility::Public),
+            uploader: row.get(5)?,
+            create_time: OffsetDateTime::from_unix_timestamp(time_value)
+                .map_err(|_| sql::Error::IntegralValueOutOfRange(
+                    6, time_value))?,
+        })
+    }
+
+    pub fn addPendingUpload(&self, upload: &PendingUpload) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "INSERT INTO uploads (id, temp_path, original_filename, length,
+                                  visibility, uploader, create_time,
+                                  update_time)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?);", sql::params![
+                 &upload.id,
+                 &upload.temp_path.to_str().ok_or_else(
+                     || rterr!("Invalid temp path: {:?}", upload.temp_path))?,
+                 &upload.original_filename,
+                 upload.length as i64,
+                 upload.visibility.toName(),
+                 &upload.uploader,
+                 upload.create_time.unix_timestamp(),
+                 upload.create_time.unix_timestamp(),
+             ]).map_err(|e| error!(DataError, "Failed to add upload: {}", e))?;
+        if row_count != 1
+        {
+            return Err(error!(DataError, "Invalid insert happened"));
+        }
+        Ok(())
+    }
+
+    pub fn findPendingUpload(&self, id: &str) ->
+        Result<Option<PendingUpload>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.query_row(
+            "SELECT id, temp_path, original_filename, length, visibility,
+             uploader, create_time FROM uploads WHERE id=?;",
+            sql::params![id], Self::row2PendingUpload).optional().map_err(
+            |e| error!(DataError, "Failed to look up upload {}: {}", id, e))
+    }
+
+    /// Record that upload “id” received data just now.
+    pub fn touchPendingUpload(&self, id: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("UPDATE uploads SET update_time = ? WHERE id = ?;",
+                     sql::params![OffsetDateTime::now_utc().unix_timestamp(),
+                                  id])
+            .map_err(|e| error!(DataError, "Failed to touch upload {}: {}",
+                                id, e))?;
+        Ok(())
+    }
+
+    pub fn removePendingUpload(&self, id: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("DELETE FROM uploads WHERE id=?;", sql::params![id])
+            .map_err(|e| error!(DataError, "Failed to remove upload {}: {}",
+                                id, e))?;
+        Ok(())
+    }
+
+    /// Remove the uploads that have received no data in the last
+    /// “life_time_sec” seconds, and return them, so that their temp
+    /// files can be removed.
+    pub fn removeExpiredUploads(&self, life_time_sec: u64) ->
+        Result<Vec<PendingUpload>, Error>
+    {
+        let mut conn = self.confirmConnection()?;
+        let tx = conn.transaction().map_err(
+            |e| error!(DataError, "Failed to start transaction: {}", e))?;
+        let cutoff = OffsetDateTime::now_utc().unix_timestamp()
+            .saturating_sub(life_time_sec.min(i64::MAX as u64) as i64);
+        let expired = {
+            let mut cmd = tx.prepare(
+                "SELECT id, temp_path, original_filename, length, visibility,
+                 uploader, create_time FROM uploads WHERE update_time < ?;")
+                .map_err(|e| error!(DataError,
+                                    "Failed to prepare statement: {}", e))?;
+            let rows = cmd.query_map([cutoff], Self::row2PendingUpload)
+                .map_err(|e| error!(DataError,
+                                    "Failed to retrieve uploads: {}", e))?;
+            rows.collect::<sql::Result<Vec<_>>>().map_err(
+                |e| error!(DataError, "Failed to retrieve uploads: {}", e))?
+        };
+        tx.execute("DELETE FROM uploads WHERE update_time < ?;", [cutoff])
+            .map_err(|e| error!(DataError, "Failed to remove uploads: {}", e))?;
+        tx.commit().map_err(
+            |e| error!(DataError, "Failed to commit transaction: {}", e))?;
+        Ok(expired)
+    }
+
+    /// Create a session logged in by “username”, which is empty if
+    /// the site password is used.
+    pub fn createSession(&self, token: &str, username: &str) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "INSERT INTO sessions (token, auth_time, create_time, username)
+             VALUES (?, ?, ?, ?);", sql::params![
+                 token,
+                 OffsetDateTime::now_utc().unix_timestamp(),
+                 OffsetDateTime::now_utc().unix_timestamp(),
+                 username,
+             ]).map_err(|e| error!(DataError, "Failed to create session: {}", e))?;
+        if row_count != 1
+        {
+            return Err(error!(DataError, "Invalid insert happened"));
+        }
+        Ok(())
+    }
+
+    /// Return time of authentication of the token. Once there are
+    /// user accounts, sessions created with the site password are no
+    /// longer valid.
+    pub fn hasSession(&self, token: &str) -> Result<OffsetDateTime, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let mut cmd = conn.prepare(
+            "SELECT auth_time FROM sessions WHERE token=? AND
+             (username != '' OR NOT EXISTS (SELECT 1 FROM users));")
+            .map_err(|e| error!(
+                DataError,
+                "Failed to prepare statement to lookup session: {}", e))?;
+        if let Some(auth_time_sec) = cmd.query_row([token,], |row| row.get(0))
+            .optional().map_err(
+                |e| error!(DataError, "Failed to look up session: {}", e))?
+        {
+            OffsetDateTime::from_unix_timestamp(auth_time_sec).map_err(
+                |_| rterr!("Invalid auth time"))
+        }
+        else
+        {
+            Err(rterr!("Session not found"))
+        }
+    }
+
+    /// Return the user who logged in to a session, or None if the
+    /// session does not exist or is created with the site password.
+    pub fn sessionUser(&self, token: &str) -> Result<Option<String>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let username: Option<String> = conn.query_row(
+            "SELECT username FROM sessions WHERE token=?;", [token],
+            |row| row.get(0)).optional()
+            .map_err(|e| error!(DataError, "Failed to look up session: {}", e))?;
+        Ok(username.filter(|name| !name.is_empty()))
+    }
+
+    /// Add a user, or change the password hash of an existing user.
+    pub fn setUser(&self, username: &str, password_hash: &str) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT INTO users (username, password_hash, create_time)
+             VALUES (?, ?, ?) ON CONFLICT(username)
+             DO UPDATE SET password_hash = excluded.password_hash;",
+            sql::params![username, password_hash,
+                         OffsetDateTime::now_utc().unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to set user {}: {}",
+                                username, e))?;
+        Ok(())
+    }
+
+    /// Return the password hash of a user, or None if the user does
+    /// not exist.
+    pub fn userPasswordHash(&self, username: &str) ->
+        Result<Option<String>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.query_row("SELECT password_hash FROM users WHERE username=?;",
+                       [username], |row| row.get(0)).optional()
+            .map_err(|e| error!(DataError, "Failed to look up user {}: {}",
+                                username, e))
+    }
+
+    /// Whether any user account exists. Without one, logging in uses
+    /// the site password.
+    pub fn hasUsers(&self) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.query_row("SELECT EXISTS (SELECT 1 FROM users);", [],
+                       |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to look up users: {}", e))
+    }
+
+    /// Replace the plain tokens stored by older versions with their
+    /// hashes, including the ones in the upload records.
+    fn hashAPITokens(conn: &sql::Connection) -> Result<(), Error>
+    {
+        let mut cmd = conn.prepare("SELECT token FROM api_tokens WHERE hashed = 0;")
+            .map_err(|e| error!(DataError, "Failed to get API tokens: {}", e))?;
+        let tokens = cmd.query_map([], |row| row.get::<_, String>(0))
+            .map_err(|e| error!(DataError, "Failed to get API tokens: {}", e))?
+            .collect::<sql::Result<Vec<String>>>()
+            .map_err(|e| error!(DataError, "Failed to get API tokens: {}", e))?;
+        for token in tokens
+        {
+            let hash = hashToken(&token);
+            conn.execute("UPDATE api_tokens SET token = ?, hashed = 1
+                          WHERE token = ?;", [&hash, &token])
+                .and_then(|_| conn.execute(
+                    "UPDATE session_uploads SET token = ? WHERE token = ?;",
+                    [&hash, &token]))
+                .map_err(|e| error!(DataError,
+                                    "Failed to migrate API tokens: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Add an API token. Only the hash of “token” is stored.
+    pub fn addAPIToken(&self, token: &str, name: &str, scope: APITokenScope) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT INTO api_tokens (token, name, scope, create_time, hashed)
+             VALUES (?, ?, ?, ?, 1);",
+            sql::params![hashToken(token), name, scope.toName(),
+                         OffsetDateTime::now_utc().unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to add API token {}: {}",
+                                name, e))?;
+        Ok(())
+    }
+
+    /// Return the API token with the token string “token”, or None if
+    /// there is no such token.
+    pub fn findAPIToken(&self, token: &str) -> Result<Option<APIToken>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.query_row(
+            "SELECT name, scope, create_time FROM api_tokens WHERE token=?;",
+            [hashToken(token)], Self::row2APIToken).optional()
+            .map_err(|e| error!(DataError, "Failed to look up API token: {}", e))
+    }
+
+    /// Return all API tokens, in the order of creation.
+    pub fn getAPITokens(&self) -> Result<Vec<APIToken>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let mut cmd = conn.prepare(
+            "SELECT name, scope, create_time FROM api_tokens
+             ORDER BY create_time, rowid;")
+            .map_err(|e| error!(DataError,
+                                "Failed to prepare statement to get API tokens: {}",
+                                e))?;
+        let rows = cmd.query_map([], Self::row2APIToken)
+            .map_err(|e| error!(DataError, "Failed to get API tokens: {}", e))?
+            .map(|row| row.map_err(|e| error!(DataError, "{}", e)));
+        rows.collect()
+    }
+
+    /// Delete the API token named “name”. Return whether it existed.
+    pub fn removeAPIToken(&self, name: &str) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute("DELETE FROM api_tokens WHERE name=?;",
+                                     [name])
+            .map_err(|e| error!(DataError, "Failed to remove API token {}: {}",
+                                name, e))?;
+        Ok(row_count > 0)
+    }
+
+    fn row2APIToken(row: &sql::Row) -> sql::Result<APIToken>
+    {
+        let scope: String = row.get(1)?;
+        let time: i64 = row.get(2)?;
+        Ok(APIToken {
+            name: row.get(0)?,
+            scope: APITokenScope::fromName(&scope)
+                .ok_or_else(|| sql::Error::FromSqlConversionFailure(
+                    1, sql::types::Type::Text,
+                    Box::new(rterr!("Invalid API token scope from database: {}",
+                                    scope))))?,
+            create_time: OffsetDateTime::from_unix_timestamp(time).map_err(
+                |_| sql::Error::IntegralValueOutOfRange(2, time))?,
+        })
+    }
+
+    /// Mark a session as used now, so that it expires later.
+    pub fn touchSession(&self, token: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("UPDATE sessions SET auth_time = ? WHERE token = ?;",
+                     sql::params![OffsetDateTime::now_utc().unix_timestamp(),
+                                  token])
+            .map_err(|e| error!(DataError, "Failed to touch session: {}", e))?;
+        Ok(())
+    }
+
+    /// Delete the sessions not used in the last “life_time_sec”
+    /// seconds, and if “max_life_time_sec” is set, the sessions
+    /// created before that many seconds ago. A “life_time_sec” of 0
+    /// means sessions never expire. Lifetimes longer than the current
+    /// Unix time expire nothing.
+    pub fn expireSessions(&self, life_time_sec: u64,
+                          max_life_time_sec: Option<u64>) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let now = OffsetDateTime::now_utc().unix_timestamp().max(0) as u64;
+        // Sessions with a time before the cutoff are deleted. A
+        // cutoff of 0 deletes nothing.
+        let cutoff = |life_time: u64| -> i64 {
+            if life_time == 0
+            {
+                0
+            }
+            else
+            {
+                now.saturating_sub(life_time) as i64
+            }
+        };
+        let row_count = conn.execute(
+            "DELETE FROM sessions WHERE auth_time < ? OR create_time < ?;",
+            sql::params![cutoff(life_time_sec),
+                         cutoff(max_life_time_sec.unwrap_or(0))])
+            .map_err(|e| error!(DataError, "Failed to expire sessions: {}", e))?;
+        if row_count > 0
+        {
+            info!("Expired {} sessions.", row_count);
+        }
+        conn.execute(
+            "DELETE FROM watch_positions WHERE token NOT IN
+             (SELECT token FROM sessions);", [])
+            .map_err(|e| error!(DataError,
+                                "Failed to expire watch positions: {}", e))?;
+        conn.execute(
+            "DELETE FROM view_history WHERE token NOT IN
+             (SELECT token FROM sessions);", [])
+            .map_err(|e| error!(DataError,
+                                "Failed to expire view history: {}", e))?;
+        conn.execute(
+            "DELETE FROM session_uploads WHERE token NOT IN
+             (SELECT token FROM sessions UNION SELECT token FROM api_tokens);",
+            [])
+            .map_err(|e| error!(DataError,
+                                "Failed to expire upload records: {}", e))?;
+        Ok(())
+    }
+
+    /// The key of the upload records of “token”. This is the token
+    /// itself for a session, and the stored hash for an API token, so
+    /// that the records go away with either.
+    fn uploadKey(conn: &sql::Connection, token: &str) -> Result<String, Error>
+    {
+        let hash = hashToken(token);
+        let is_api_token: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM api_tokens WHERE token = ?);",
+            [&hash], |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to look up API token: {}", e))?;
+        Ok(if is_api_token { hash } else { token.to_owned() })
+    }
+
+    /// Record an upload of “size” bytes by a session, for the upload
+    /// quota.
+    pub fn recordUpload(&self, token: &str, size: u64) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let token = Self::uploadKey(&conn, token)?;
+        conn.execute(
+            "INSERT INTO session_uploads (token, upload_time, size)
+             VALUES (?, ?, ?);",
+            sql::params![token, OffsetDateTime::now_utc().unix_timestamp(),
+                         size as i64])
+            .map_err(|e| error!(DataError, "Failed to record upload: {}", e))?;
+        Ok(())
+    }
+
+    /// Sum up the uploads of a session since “since”. Records before
+    /// that are deleted, since they no longer count.
+    pub fn uploadUsageSince(&self, token: &str, since: OffsetDateTime) ->
+        Result<UploadUsage, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let token = Self::uploadKey(&conn, token)?;
+        let token = token.as_str();
+        conn.execute(
+            "DELETE FROM session_uploads WHERE token = ? AND upload_time < ?;",
+            sql::params![token, since.unix_timestamp()])
+            .map_err(|e| error!(DataError,
+                                "Failed to expire upload records: {}", e))?;
+        let (count, bytes, earliest): (i64, i64, Option<i64>) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0), MIN(upload_time)
+             FROM session_uploads WHERE token = ?;", [token],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| error!(DataError,
+                                "Failed to query upload usage: {}", e))?;
+        Ok(UploadUsage {
+            count: count as u64,
+            bytes: bytes as u64,
+            earliest_time: earliest.map(
+                |t| OffsetDateTime::from_unix_timestamp(t).map_err(
+                    |_| rterr!("Invalid upload time: {}", t)))
+                .transpose()?,
+        })
+    }
+
+    /// Record a view of a video in the history of a session. Only
+    /// the latest “max_size” videos are kept in the history.
+    pub fn addToHistory(&self, token: &str, video_id: &str, max_size: u64) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO view_history (token, video_id, view_time)
+             VALUES (?, ?, ?);",
+            sql::params![token, video_id,
+                         OffsetDateTime::now_utc().unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to add to history: {}", e))?;
+        // A replaced row gets a new rowid, so rowid breaks ties
+        // between views in the same second.
+        conn.execute(
+            "DELETE FROM view_history WHERE token = ? AND rowid NOT IN
+             (SELECT rowid FROM view_history WHERE token = ?
+              ORDER BY view_time DESC, rowid DESC LIMIT ?);",
+            sql::params![token, token, max_size.min(i64::MAX as u64) as i64])
+            .map_err(|e| error!(DataError, "Failed to trim history: {}", e))?;
+        Ok(())
+    }
+
+    /// Retrieve the videos in the history of a session, from the most
+    /// recently viewed.
+    pub fn getHistory(&self, token: &str) -> Result<Vec<Video>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let mut cmd = conn.prepare(
+            &format!("SELECT {} FROM videos JOIN view_history
+                      ON videos.id = view_history.video_id
+                      WHERE view_history.token = ?
+                      ORDER BY view_history.view_time DESC,
+                      view_history.rowid DESC;", VIDEO_COLUMNS))
+            .map_err(|e| error!(
+                DataError,
+                "Failed to compare statement to get history: {}", e))?;
+        let rows = cmd.query_map([token], Self::row2Video)
+            .map_err(|e| error!(DataError, "Failed to retrieve history: {}", e))?
+            .map(|row| row.map_err(|e| error!(DataError, "{}", e)));
+        rows.collect()
+    }
+
+    /// Remove all videos from the history of a session.
+    pub fn clearHistory(&self, token: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("DELETE FROM view_history WHERE token = ?;", [token])
+            .map_err(|e| error!(DataError, "Failed to clear history: {}", e))?;
+        Ok(())
+    }
+
+    /// Record the playback position (in seconds) of a video in a
+    /// session.
+    pub fn setWatchPosition(&self, token: &str, video_id: &str,
+                            position: f64) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO watch_positions
+             (token, video_id, position, update_time) VALUES (?, ?, ?, ?);",
+            sql::params![token, video_id, position,
+                         OffsetDateTime::now_utc().unix_timestamp()])
+            .map_err(|e| error!(DataError,
+                                "Failed to set watch position: {}", e))?;
+        Ok(())
+    }
+
+    /// Return the last recorded playback position of a video in a
+    /// session.
+    pub fn getWatchPosition(&self, token: &str, video_id: &str) ->
+        Result<Option<f64>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.query_row(
+            "SELECT position FROM watch_positions
+             WHERE token=? AND video_id=?;", [token, video_id],
+            |row| row.get(0)).optional()
+            .map_err(|e| error!(DataError,
+                                "Failed to get watch position: {}", e))
+    }
+
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::video_processing::randomTempFilename;
+
+    fn newTestManager() -> Result<Manager, Error>
+    {
+        let mut manager = Manager::new(sqlite_connection::Source::Memory);
+        manager.connect()?;
+        manager.init()?;
+        Ok(manager)
+    }
+
+    fn newTestVideo(id: &str, upload_time: i64) -> Video
+    {
+        let mut video = Video::new(id.to_owned(), format!("{}.mp4", id));
+        video.upload_time = OffsetDateTime::from_unix_timestamp(upload_time)
+            .unwrap();
+        video
+    }
+
+    #[test]
+    fn testPinned() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        manager.addVideo(&newTestVideo("a", 1))?;
+        manager.addVideo(&newTestVideo("b", 2))?;
+        let mut private = newTestVideo("private", 3);
+        private.visibility = Visibility::Private;
+        manager.addVideo(&private)?;
+        assert!(manager.getPinnedVideos()?.is_empty());
+
+        manager.setPinned("a", true)?;
+        manager.setPinned("b", true)?;
+        manager.setPinned("private", true)?;
+        let pinned: Vec<String> = manager.getPinnedVideos()?.into_iter()
+            .map(|v| v.id).collect();
+        assert_eq!(pinned, vec!["b", "a"]);
+        assert!(manager.findVideoByID("a")?.unwrap().pinned);
+
+        manager.setPinned("b", false)?;
+        assert_eq!(manager.getPinnedVideos()?.len(), 1);
+        assert!(manager.setPinned("missing", true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn testDuplicateVideo() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        manager.addVideo(&newTestVideo("a", 1))?;
+        match manager.addVideo(&newTestVideo("a", 2))
+        {
+            Err(Error::HTTPStatus(code, _)) =>
+                assert_eq!(code, StatusCode::CONFLICT),
+            _ => panic!("Duplicate video is not a conflict"),
+        }
+        manager.addVideo(&newTestVideo("b", 2))?;
+        match manager.updateVideoPath("b", Path::new("a.mp4"))
+        {
+            Err(Error::HTTPStatus(code, _)) =>
+                assert_eq!(code, StatusCode::CONFLICT),
+            _ => panic!("Duplicate path is not a conflict"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn testStaleThumbnails() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        for (id, quality) in [("old", Some(70)), ("current", Some(85)),
+                              ("uploaded", None)]
+        {
+            let mut video = newTestVideo(id, 1);
+            video.thumbnail_quality = quality;
+            manager.addVideo(&video)?;
+        }
+        let stale: Vec<String> = manager.getVideosWithStaleThumbnail(85)?
+            .into_iter().map(|v| v.id).collect();
+        assert_eq!(stale, vec!["old"]);
+        Ok(())
+    }
+
+    #[test]
+    fn testPatchVideo() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        let mut video = newTestVideo("a", 1);
+        video.title = String::from("Title");
+        video.desc = String::from("Description");
+        manager.addVideo(&video)?;
+        manager.patchVideo("a", &VideoPatch {
+            title: Some(String::from("New title")),
+            visibility: Some(Visibility::Unlisted),
+            looping: Some(true),
+            ..Default::default()
+        })?;
+        let video = manager.findVideoByID("a")?.unwrap();
+        assert_eq!(&video.title, "New title");
+        assert_eq!(&video.desc, "Description");
+        assert_eq!(video.visibility, Visibility::Unlisted);
+        assert!(video.looping);
+        assert!(!video.autoplay);
+        manager.patchVideo("a", &VideoPatch::default())?;
+        assert!(manager.patchVideo("missing", &VideoPatch {
+            artist: Some(String::new()), ..Default::default()
+        }).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn testUnprocessedVideo() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        let mut video = newTestVideo("a", 1);
+        manager.addVideo(&video)?;
+        manager.addVideo(&newTestVideo("b", 2))?;
+        manager.addUnprocessedVideo("a")?;
+        let unprocessed = manager.getUnprocessedVideos()?;
+        assert_eq!(unprocessed.len(), 1);
+        assert_eq!(unprocessed[0].id, "a");
+
+        // Edits while the video waits are kept after processing.
+        manager.patchVideo("a", &VideoPatch {
+            title: Some(String::from("Edited")),
+            visibility: Some(Visibility::Private),
+            ..Default::default()
+        })?;
+        video.path = PathBuf::from("a.webm");
+        video.container_type = ContainerType::WebM;
+        video.thumbnail_path = Some(PathBuf::from("a.webp"));
+        manager.updateProcessedVideo(&video)?;
+        manager.removeUnprocessedVideo("a")?;
+        let video = manager.findVideoByID("a")?.unwrap();
+        assert_eq!(&video.title, "Edited");
+        assert_eq!(video.visibility, Visibility::Private);
+        assert_eq!(video.path, PathBuf::from("a.webm"));
+        assert_eq!(video.thumbnail_path, Some(PathBuf::from("a.webp")));
+        assert!(manager.getUnprocessedVideos()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn testVisibility() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        let public = newTestVideo("public", 1);
+        let mut unlisted = newTestVideo("unlisted", 2);
+        unlisted.visibility = Visibility::Unlisted;
+        let mut private = newTestVideo("private", 3);
+        private.visibility = Visibility::Private;
+        private.thumbnail_path = Some(PathBuf::from("private.webp"));
+        manager.addVideo(&public)?;
+        manager.addVideo(&unlisted)?;
+        manager.addVideo(&private)?;
+
+        // Only public videos are listed.
+        let listed = manager.getVideos(0, 10, VideoOrder::NewFirst)?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, "public");
+
+        // All videos can be found directly.
+        assert_eq!(manager.findVideoByID("public")?.unwrap().visibility,
+                   Visibility::Public);
+        assert_eq!(manager.findVideoByID("unlisted")?.unwrap().visibility,
+                   Visibility::Unlisted);
+        assert_eq!(manager.findVideoByID("private")?.unwrap().visibility,
+                   Visibility::Private);
+        // So are the videos that own files, whose visibility applies
+        // to the files.
+        for file in ["private.mp4", "private.webp"]
+        {
+            assert_eq!(manager.findVideoByFile(file)?.unwrap().visibility,
+                       Visibility::Private);
+        }
+        assert!(manager.findVideoByFile("other.webp")?.is_none());
+
+        assert_eq!(manager.getAllVideos()?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn testVideosByArtist() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        for (id, t, artist) in [("a", 1, "Alice"), ("b", 2, ""),
+                                ("c", 3, "Alice"), ("d", 4, "Bob")]
+        {
+            let mut video = newTestVideo(id, t);
+            video.artist = artist.to_owned();
+            manager.addVideo(&video)?;
+        }
+        let ids = |videos: Vec<VideoSummary>| -> Vec<String> {
+            videos.into_iter().map(|v| v.id).collect()
+        };
+        assert_eq!(ids(manager.getVideosByArtist("Alice", 0, 10)?),
+                   vec!["c", "a"]);
+        assert_eq!(ids(manager.getVideosByArtist("Alice", 1, 10)?),
+                   vec!["a"]);
+        assert_eq!(ids(manager.getVideosByArtist("", 0, 10)?), vec!["b"]);
+        assert!(manager.getVideosByArtist("Carol", 0, 10)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn testVideoSummaries() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        let mut untitled = newTestVideo("a", 1);
+        untitled.original_filename = String::from("a.mp4");
+        untitled.duration = time::Duration::seconds(90);
+        manager.addVideo(&untitled)?;
+        let mut titled = newTestVideo("b", 2);
+        titled.title = String::from("B");
+        titled.thumbnail_path = Some(PathBuf::from("b.webp"));
+        manager.addVideo(&titled)?;
+
+        let summaries = manager.getVideoSummaries(
+            0, 10, VideoOrder::NewFirst, None, None)?;
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].title, "B");
+        assert_eq!(summaries[0].thumbnail_path, Some(PathBuf::from("b.webp")));
+        assert_eq!(summaries[1].title, "a.mp4");
+        assert_eq!(summaries[1].duration, time::Duration::seconds(90));
+        assert_eq!(manager.getVideoSummaries(
+            0, 10, VideoOrder::NewFirst, Some(2), None)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn testExpiredUploads() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        let now = OffsetDateTime::now_utc();
+        for (id, age) in [("old", 7200), ("touched", 7200), ("new", 60)]
+        {
+            manager.addPendingUpload(&PendingUpload {
+                id: id.to_owned(),
+                temp_path: PathBuf::from(format!("temp-{}.mp4", id)),
+                original_filename: String::from("a.mp4"),
+                length: 10,
+                visibility: Visibility::Public,
+                uploader: String::new(),
+                create_time: now - time::Duration::seconds(age),
+            })?;
+        }
+        manager.touchPendingUpload("touched")?;
+        let expired = manager.removeExpiredUploads(3600)?;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, "old");
+        assert_eq!(expired[0].temp_path, PathBuf::from("temp-old.mp4"));
+        assert!(manager.findPendingUpload("old")?.is_none());
+        assert!(manager.findPendingUpload("touched")?.is_some());
+        assert!(manager.findPendingUpload("new")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn testUploadUsage() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        manager.recordUpload("a", 100)?;
+        manager.recordUpload("a", 200)?;
+        manager.recordUpload("b", 400)?;
+        let hour_ago = OffsetDateTime::now_utc() - time::Duration::hours(1);
+        let usage = manager.uploadUsageSince("a", hour_ago)?;
+        assert_eq!(usage.count, 2);
+        assert_eq!(usage.bytes, 300);
+        assert!(usage.earliest_time.is_some());
+        // Old uploads no longer count.
+        let later = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let usage = manager.uploadUsageSince("a", later)?;
+        assert_eq!(usage.count, 0);
+        assert_eq!(usage.bytes, 0);
+        assert!(usage.earliest_time.is_none());
+        assert_eq!(manager.uploadUsageSince("b", hour_ago)?.bytes, 400);
+        Ok(())
+    }
+
+    #[test]
+    fn testDeleteVideos() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        for (id, t) in [("a", 1), ("b", 2), ("c", 3)]
+        {
+            manager.addVideo(&newTestVideo(id, t))?;
+        }
+        manager.addToHistory("t", "a", 10)?;
+        let deleted = manager.deleteVideos(
+            &[String::from("a"), String::from("x"), String::from("c")])?;
+        assert_eq!(deleted.into_iter().map(|v| v.id).collect::<Vec<_>>(),
+                   vec!["a", "c"]);
+        assert!(manager.findVideoByID("a")?.is_none());
+        assert!(manager.findVideoByID("b")?.is_some());
+        assert!(manager.getHistory("t")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn testChapters() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        manager.addVideo(&newTestVideo("a", 1))?;
+        assert!(manager.getChapters("a")?.is_empty());
+        let chapter = |start, title: &str| Chapter {
+            start: time::Duration::seconds(start),
+            title: title.to_owned(),
+        };
+        manager.setChapters("a", &[chapter(60, "Two"), chapter(0, "One")])?;
+        let titles = |chapters: Vec<Chapter>| chapters.into_iter()
+            .map(|c| c.title).collect::<Vec<_>>();
+        assert_eq!(titles(manager.getChapters("a")?), vec!["One", "Two"]);
+        manager.setChapters("a", &[chapter(10, "Only")])?;
+        assert_eq!(titles(manager.getChapters("a")?), vec!["Only"]);
+        manager.deleteVideos(&[String::from("a")])?;
+        assert!(manager.getChapters("a")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn testAdjacentVideos() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        for (id, t) in [("a", 1), ("b", 2), ("c", 2), ("d", 3)]
+        {
+            manager.addVideo(&newTestVideo(id, t))?;
+        }
+        let mut private = newTestVideo("p", 4);
+        private.visibility = Visibility::Private;
+        manager.addVideo(&private)?;
+        let adjacent = |id: &str, order| -> Result<_, Error> {
+            let video = manager.findVideoByID(id)?.unwrap();
+            let (prev, next) = manager.getAdjacentVideos(&video, order)?;
+            Ok((prev.unwrap_or_default(), next.unwrap_or_default()))
+        };
+        assert_eq!(adjacent("d", VideoOrder::NewFirst)?,
+                   (String::new(), String::from("c")));
+        assert_eq!(adjacent("c", VideoOrder::NewFirst)?,
+                   (String::from("d"), String::from("b")));
+        assert_eq!(adjacent("b", VideoOrder::OldFirst)?,
+                   (String::from("a"), String::from("c")));
+        assert_eq!(adjacent("a", VideoOrder::OldFirst)?,
+                   (String::new(), String::from("b")));
+        manager.increaseViewCount("a")?;
+        assert_eq!(adjacent("a", VideoOrder::MostViewed)?,
+                   (String::new(), String::from("d")));
+        Ok(())
+    }
+
+    #[test]
+    fn testExpireSessions() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        manager.createSession("new", "")?;
+        manager.createSession("old", "")?;
+        manager.confirmConnection()?.execute(
+            "UPDATE sessions SET auth_time = auth_time - 100,
+             create_time = create_time - 100 WHERE token = 'old';", [])
+            .unwrap();
+        // Lifetimes longer than the Unix time must not underflow.
+        manager.expireSessions(u64::MAX, Some(u64::MAX))?;
+        manager.expireSessions(0, None)?;
+        assert!(manager.hasSession("old").is_ok());
+        manager.expireSessions(1000, Some(50))?;
+        assert!(manager.hasSession("old").is_err());
+        manager.expireSessions(50, None)?;
+        assert!(manager.hasSession("new").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn testAPITokens() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        manager.addAPIToken("abc", "script", APITokenScope::Upload)?;
+        manager.addAPIToken("def", "reader", APITokenScope::ReadOnly)?;
+        assert!(manager.addAPIToken("ghi", "script",
+                                    APITokenScope::ReadOnly).is_err());
+        assert_eq!(manager.findAPIToken("abc")?.map(|t| t.scope),
+                   Some(APITokenScope::Upload));
+        assert!(manager.findAPIToken("script")?.is_none());
+        let names: Vec<String> = manager.getAPITokens()?.into_iter()
+            .map(|t| t.name).collect();
+        assert_eq!(names, vec!["script", "reader"]);
+        // Only the hash is stored.
+        assert!(manager.findAPIToken(&hashToken("abc"))?.is_none());
+        manager.recordUpload("abc", 100)?;
+        manager.expireSessions(1000, None)?;
+        assert_eq!(manager.uploadUsageSince("abc", OffsetDateTime::UNIX_EPOCH)?
+                   .bytes, 100);
+        assert!(manager.removeAPIToken("script")?);
+        assert!(!manager.removeAPIToken("script")?);
+        assert!(manager.findAPIToken("abc")?.is_none());
+
+        // Plain tokens from older versions are hashed on init.
+        manager.confirmConnection()?.execute(
+            "INSERT INTO api_tokens (token, name, scope, create_time)
+             VALUES ('old', 'old', 'upload', 0);", [])
+            .map_err(|e| error!(DataError, "{}", e))?;
+        manager.init()?;
+        assert_eq!(manager.findAPIToken("old")?.map(|t| t.name),
+                   Some("old".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn testUsers() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        assert!(!manager.hasUsers()?);
+        manager.createSession("site", "")?;
+        assert!(manager.hasSession("site").is_ok());
+        manager.setUser("alice", "hash1")?;
+        // The site password no longer logs in.
+        assert!(manager.hasSession("site").is_err());
+        manager.setUser("alice", "hash2")?;
+        assert!(manager.hasUsers()?);
+        assert_eq!(manager.userPasswordHash("alice")?, Some("hash2".to_owned()));
+        assert_eq!(manager.userPasswordHash("bob")?, None);
+
+        manager.createSession("a", "alice")?;
+        manager.createSession("b", "")?;
+        assert_eq!(manager.sessionUser("a")?, Some("alice".to_owned()));
+        assert_eq!(manager.sessionUser("b")?, None);
+        assert_eq!(manager.sessionUser("c")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn testHistory() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        for (id, t) in [("a", 1), ("b", 2), ("c", 3)]
+        {
+            manager.addVideo(&newTestVideo(id, t))?;
+        }
+        let ids = |videos: Vec<Video>| -> Vec<String> {
+            videos.into_iter().map(|v| v.id).collect()
+        };
+        manager.addToHistory("t1", "a", 2)?;
+        manager.addToHistory("t1", "b", 2)?;
+        manager.addToHistory("t1", "a", 2)?;
+        manager.addToHistory("t2", "c", 2)?;
+        assert_eq!(ids(manager.getHistory("t1")?), vec!["a", "b"]);
+        // The oldest entry is dropped.
+        manager.addToHistory("t1", "c", 2)?;
+        assert_eq!(ids(manager.getHistory("t1")?), vec!["c", "a"]);
+        manager.clearHistory("t1")?;
+        assert!(manager.getHistory("t1")?.is_empty());
+        assert_eq!(ids(manager.getHistory("t2")?), vec!["c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn testTimeRange() -> Result<(), Error>
+    {
+        let manager = newTestManager()?;
+        for (id, t) in [("a", 100), ("b", 200), ("c", 300), ("d", 400)]
+        {
+            manager.addVideo(&newTestVideo(id, t))?;
+        }
+        let ids = |videos: Vec<Video>| -> Vec<String> {
+            videos.into_iter().map(|v| v.id).collect()
+        };
+        assert_eq!(ids(manager.getVideosInRange(
+            0, 10, VideoOrder::OldFirst, Some(200), Some(300))?),
+                   vec!["b", "c"]);
+        assert_eq!(ids(manager.getVideosInRange(
+            0, 10, VideoOrder::NewFirst, Some(200), None)?),
+                   vec!["d", "c", "b"]);
+        // Range composes with pagination.
+        assert_eq!(ids(manager.getVideosInRange(
+            1, 1, VideoOrder::NewFirst, None, Some(300))?),
+                   vec!["b"]);
+        assert_eq!(manager.countVideos(None, None)?, 4);
+        assert_eq!(manager.countVideos(Some(200), Some(300))?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn testConcurrentViewCount() -> Result<(), Error>
+    {
+        let path = randomTempFilename(std::env::temp_dir())
+            .with_extension("sqlite");
+        let mut manager = Manager::newWithFilename(&path);
+        manager.connect()?;
+        manager.init()?;
+        manager.addVideo(&newTestVideo("a", 1))?;
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let manager = manager.clone();
+            std::thread::spawn(move || -> Result<(), Error> {
+                for _ in 0..25
+                {
+                    manager.increaseViewCount("a")?;
+                }
+                Ok(())
+            })
+        }).collect();
+        for t in threads
+        {
+            t.join().unwrap()?;
+        }
+        assert_eq!(manager.findVideoByID("a")?.unwrap().views, 200);
+        // A missing video is not an error.
+        manager.increaseViewCount("missing")?;
+        std::fs::remove_file(&path).ok();
+        Ok(())
+    }
 }
@@ -0,0 +1,20 @@
+// The bundled templates and static files, embedded in the binary by
+// build.rs. Files in “data_dir/templates” and “static_dir” take
+// precedence over these.
+
+include!(concat!(env!("OUT_DIR"), "/embedded.rs"));
+
+/// The embedded templates, as (name, content) pairs.
+pub fn templates() -> Vec<(&'static str, &'static str)>
+{
+    TEMPLATES.iter().filter_map(
+        |(name, content)| std::str::from_utf8(content).ok()
+            .map(|content| (*name, content))).collect()
+}
+
+/// Content of the embedded static file at “path”, relative to the
+/// static dir.
+pub fn staticFile(path: &str) -> Option<&'static [u8]>
+{
+    STATICS.iter().find(|(p, _)| *p == path).map(|(_, content)| *content)
+}
@@ -1,8 +1,8 @@
 use std::error::Error as StdError;
 use std::fmt;
 
-use log::error as log_error;
 use warp::http::status::StatusCode;
+use warp::Reply;
 
 #[macro_export]
 macro_rules! error
@@ -44,12 +44,6 @@ pub enum Error
     HTTPStatus(StatusCode, String),
 }
 
-pub fn reject(e: Error) -> warp::Rejection
-{
-    log_error!("{}", e);
-    warp::reject::reject()
-}
-
 impl fmt::Display for Error
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
@@ -69,20 +63,49 @@ impl StdError for Error
     fn source(&self) -> Option<&(dyn StdError + 'static)> {None}
 }
 
-impl warp::reply::Reply for Error
+impl Error
 {
-    fn into_response(self) -> warp::reply::Response
+    /// The HTTP status code to respond with for this error.
+    pub fn statusCode(&self) -> StatusCode
     {
         match self
         {
-            Error::DataError(_) => warp::reply::with_status(
-                self.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
-                .into_response(),
-            Error::RuntimeError(_) => warp::reply::with_status(
-                self.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
-                .into_response(),
-            Error::HTTPStatus(c, _) => warp::reply::with_status(
-                self.to_string(), c).into_response(),
+            Error::DataError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::RuntimeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::HTTPStatus(c, _) => *c,
+        }
+    }
+
+    /// Render this error as a JSON object like `{"error": "Video
+    /// not found", "code": 404}`, for API clients.
+    pub fn toJSONResponse(&self) -> warp::reply::Response
+    {
+        #[derive(serde::Serialize)]
+        struct ErrorBody<'a>
+        {
+            error: &'a str,
+            code: u16,
         }
+
+        let code = self.statusCode();
+        let msg = self.to_string();
+        let error = match self
+        {
+            Error::HTTPStatus(_, msg) if !msg.is_empty() => msg.as_str(),
+            Error::HTTPStatus(c, _) => c.canonical_reason().unwrap_or(""),
+            _ => msg.as_str(),
+        };
+        warp::reply::with_status(
+            warp::reply::json(&ErrorBody { error, code: code.as_u16() }), code)
+            .into_response()
+    }
+}
+
+impl warp::reply::Reply for Error
+{
+    fn into_response(self) -> warp::reply::Response
+    {
+        let code = self.statusCode();
+        warp::reply::with_status(self.to_string(), code).into_response()
     }
 }
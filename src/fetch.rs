@@ -0,0 +1,280 @@
+use std::fmt;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::Duration;
+
+use log::info;
+use log::error as log_error;
+use sha2::Digest;
+use ureq::unversioned::resolver::{DefaultResolver, ResolvedSocketAddrs,
+                                  Resolver};
+use ureq::unversioned::transport::{DefaultConnector, NextTimeout};
+use warp::http::status::StatusCode;
+
+use crate::config::Configuration;
+use crate::error::Error;
+use crate::progress::ProgressHandle;
+use crate::video_processing::{RawVideo, hashToID, randomTempFilename};
+
+/// How many redirects are followed when fetching a video.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Whether “ip” is an address on the public internet. Loopback,
+/// private, link-local, and other special-purpose addresses are not.
+pub fn isPublicAddress(ip: &IpAddr) -> bool
+{
+    match ip
+    {
+        IpAddr::V4(ip) => isPublicIPv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped()
+        {
+            Some(ip) => isPublicIPv4(&ip),
+            None => isPublicIPv6(ip),
+        },
+    }
+}
+
+fn isPublicIPv4(ip: &Ipv4Addr) -> bool
+{
+    let [a, b, _, _] = ip.octets();
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() ||
+      ip.is_link_local() || ip.is_broadcast() || ip.is_documentation() ||
+      ip.is_multicast() ||
+      // “This network”, shared address space, and reserved.
+      a == 0 || (a == 100 && (64..128).contains(&b)) || a >= 240)
+}
+
+/// The IPv4 address embedded in “ip” if it is an IPv4-compatible,
+/// NAT64 (64:ff9b::/96), or 6to4 (2002::/16) address. Traffic to
+/// these can reach the IPv4 address.
+fn embeddedIPv4(ip: &Ipv6Addr) -> Option<Ipv4Addr>
+{
+    let s = ip.segments();
+    let [.., a, b, c, d] = ip.octets();
+    if s[..6] == [0; 6] || s[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+    {
+        Some(Ipv4Addr::new(a, b, c, d))
+    }
+    else if s[0] == 0x2002
+    {
+        let [a, b] = s[1].to_be_bytes();
+        let [c, d] = s[2].to_be_bytes();
+        Some(Ipv4Addr::new(a, b, c, d))
+    }
+    else
+    {
+        None
+    }
+}
+
+fn isPublicIPv6(ip: &Ipv6Addr) -> bool
+{
+    if let Some(ip) = embeddedIPv4(ip)
+    {
+        return isPublicIPv4(&ip);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() ||
+      // Unique local and link-local.
+      (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80 ||
+      // Documentation.
+      first == 0x2001 && ip.segments()[1] == 0x0db8)
+}
+
+/// Resolves host names like the default resolver, but only to public
+/// addresses. Every connection of a request, including the ones of
+/// redirects, goes through this, so a URL cannot reach the private
+/// network by redirecting or by resolving to different addresses
+/// later.
+#[derive(Default)]
+struct PublicResolver
+{
+    resolver: DefaultResolver,
+}
+
+impl fmt::Debug for PublicResolver
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.write_str("PublicResolver")
+    }
+}
+
+impl Resolver for PublicResolver
+{
+    fn resolve(&self, uri: &ureq::http::Uri, config: &ureq::config::Config,
+               timeout: NextTimeout) ->
+        Result<ResolvedSocketAddrs, ureq::Error>
+    {
+        let addrs = self.resolver.resolve(uri, config, timeout)?;
+        let mut result = self.empty();
+        for addr in addrs.iter().filter(|a| isPublicAddress(&a.ip()))
+        {
+            result.push(*addr);
+        }
+        if result.is_empty()
+        {
+            return Err(ureq::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} is not a public address",
+                        uri.host().unwrap_or_default()))));
+        }
+        Ok(result)
+    }
+}
+
+/// Download the video at “url” into a temp file in the video
+/// directory, like `UploadingVideo::saveToTemp`. Only HTTPS URLs on
+/// the public internet are fetched. The download is aborted if it
+/// grows larger than “max_size”.
+pub fn fetchToTemp(url: &str, max_size: u64, progress: Option<ProgressHandle>,
+                   config: &Configuration) -> Result<RawVideo, Error>
+{
+    let uri: ureq::http::Uri = url.parse().map_err(
+        |_| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                              format!("Invalid URL: {}", url)))?;
+    if uri.scheme_str() != Some("https") || uri.host().is_none()
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::BAD_REQUEST,
+            format!("Only HTTPS URLs can be fetched: {}", url)));
+    }
+    let agent_config = ureq::Agent::config_builder()
+        .https_only(true)
+        .proxy(None)
+        .max_redirects(MAX_REDIRECTS)
+        .http_status_as_error(false)
+        .timeout_connect(Some(Duration::from_secs(30)))
+        .timeout_recv_response(Some(Duration::from_secs(60)))
+        .build();
+    let agent = ureq::Agent::with_parts(
+        agent_config, DefaultConnector::default(), PublicResolver::default());
+    let response = agent.get(&uri).call().map_err(|e| {
+        // URLs that resolve to the private network are rejected by
+        // `PublicResolver`.
+        let code = match &e
+        {
+            ureq::Error::Io(e) if e.kind() ==
+                std::io::ErrorKind::PermissionDenied => StatusCode::BAD_REQUEST,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        Error::HTTPStatus(code, format!("Failed to fetch {}: {}", url, e))
+    })?;
+    if !response.status().is_success()
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to fetch {}: {}", url, response.status())));
+    }
+    let length: Option<u64> = response.headers().get("content-length")
+        .and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok());
+    if length.is_some_and(|l| l > max_size)
+    {
+        return Err(Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE,
+                                     String::from("Video file is too large")));
+    }
+    if let Some(progress) = &progress
+    {
+        progress.setTotal(length);
+    }
+
+    let filename = uri.path().rsplit('/').next()
+        .and_then(|name| urlencoding::decode(name).ok())
+        .map(|name| name.into_owned()).unwrap_or_default();
+    let temp_file = randomTempFilename(&config.video_dir).with_extension(
+        Path::new(&filename).extension().unwrap_or_default());
+    let result = (|| {
+        let mut f = BufWriter::new(File::create(&temp_file).map_err(
+            |e| rterr!("Failed to open temp file: {}", e))?);
+        let mut reader = response.into_body().into_reader();
+        let mut hasher = sha2::Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut size: u64 = 0;
+        loop
+        {
+            let n = reader.read(&mut buffer).map_err(
+                |e| Error::HTTPStatus(StatusCode::BAD_GATEWAY,
+                                      format!("Failed to fetch {}: {}", url, e)))?;
+            if n == 0
+            {
+                break;
+            }
+            size += n as u64;
+            if size > max_size
+            {
+                return Err(Error::HTTPStatus(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    String::from("Video file is too large")));
+            }
+            hasher.update(&buffer[..n]);
+            f.write_all(&buffer[..n]).map_err(
+                |e| rterr!("Failed to write temp file: {}", e))?;
+            if let Some(progress) = &progress
+            {
+                progress.add(n as u64);
+            }
+        }
+        f.flush().map_err(|e| rterr!("Failed to write temp file: {}", e))?;
+        info!("Fetched {} bytes from {}.", size, url);
+        Ok(hasher.finalize())
+    })();
+    match result
+    {
+        Ok(hash) => Ok(RawVideo {
+            path: temp_file,
+            hash: hashToID(&hash, config),
+            original_filename: filename,
+        }),
+        Err(e) =>
+        {
+            if temp_file.exists() && std::fs::remove_file(&temp_file).is_err()
+            {
+                log_error!("Failed to remove temp file at {:?}.", temp_file);
+            }
+            Err(e)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn testIsPublicAddress()
+    {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"]
+        {
+            assert!(isPublicAddress(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1",
+                   "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1",
+                   "fd00::1", "fe80::1", "::ffff:192.168.1.1",
+                   "64:ff9b::7f00:1", "64:ff9b::a9fe:a9fe", "2002:a00:1::1",
+                   "2002:7f00:1::", "::10.0.0.1"]
+        {
+            assert!(!isPublicAddress(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["64:ff9b::5db8:d70e", "2002:5db8:d70e::1"]
+        {
+            assert!(isPublicAddress(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn testFetchRejectsURL()
+    {
+        let config = Configuration::default();
+        for url in ["http://example.com/a.mp4", "ftp://example.com/a.mp4",
+                    "/a.mp4", "https:///a.mp4", "not a url"]
+        {
+            assert!(matches!(fetchToTemp(url, 1024, None, &config),
+                             Err(Error::HTTPStatus(StatusCode::BAD_REQUEST, _))),
+                    "{}", url);
+        }
+    }
+}
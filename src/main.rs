@@ -8,6 +8,16 @@ mod sqlite_connection;
 mod data;
 mod app;
 mod config;
+mod embedded;
+mod fetch;
+mod maintenance;
+mod metrics;
+mod password;
+mod progress;
+mod queue;
+mod storage;
+mod urls;
+mod websub;
 
 use std::path::Path;
 
@@ -27,10 +37,81 @@ fn main() -> Result<(), Error>
              .value_name("FILE")
              .default_value("/etc/metube.toml")
              .help("Path of config file."))
+        .subcommand(
+            clap::Command::new("rebuild")
+                .about("Re-probe metadata and regenerate thumbnails of all \
+                        videos")
+                .arg(clap::Arg::new("thumbnails-only")
+                     .long("thumbnails-only")
+                     .action(clap::ArgAction::SetTrue)
+                     .help("Only regenerate thumbnails."))
+                .arg(clap::Arg::new("stale-thumbnails")
+                     .long("stale-thumbnails")
+                     .action(clap::ArgAction::SetTrue)
+                     .conflicts_with("thumbnails-only")
+                     .help("Only regenerate the thumbnails generated with \
+                            a quality other than thumbnail_quality.")))
+        .subcommand(
+            clap::Command::new("import")
+                .about("Add the videos in a directory to the library")
+                .arg(clap::Arg::new("dir")
+                     .value_name("DIR")
+                     .required(true)
+                     .help("Directory to import videos from, recursively.")))
+        .subcommand(
+            clap::Command::new("export-metadata")
+                .about("Write the metadata of all videos to a JSON file")
+                .arg(clap::Arg::new("file")
+                     .value_name("FILE")
+                     .required(true)
+                     .help("Path of the JSON file to write.")))
+        .subcommand(
+            clap::Command::new("import-metadata")
+                .about("Add or overwrite videos from a file written by \
+                        export-metadata. The video files must already be \
+                        in the library.")
+                .arg(clap::Arg::new("file")
+                     .value_name("FILE")
+                     .required(true)
+                     .help("Path of the JSON file to read.")))
+        .subcommand(
+            clap::Command::new("useradd")
+                .about("Add a user, or change the password of a user. The \
+                        password is read from the standard input.")
+                .arg(clap::Arg::new("username")
+                     .value_name("USERNAME")
+                     .required(true)
+                     .help("Name of the user.")))
+        .subcommand(
+            clap::Command::new("token")
+                .about("Manage API tokens")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("create")
+                        .about("Create an API token and print it")
+                        .arg(clap::Arg::new("name")
+                             .value_name("NAME")
+                             .required(true)
+                             .help("Name of the token, used to revoke it."))
+                        .arg(clap::Arg::new("read-only")
+                             .long("read-only")
+                             .action(clap::ArgAction::SetTrue)
+                             .help("Only allow reading the JSON APIs, not \
+                                    uploading.")))
+                .subcommand(
+                    clap::Command::new("revoke")
+                        .about("Revoke an API token")
+                        .arg(clap::Arg::new("name")
+                             .value_name("NAME")
+                             .required(true)
+                             .help("Name of the token.")))
+                .subcommand(
+                    clap::Command::new("list")
+                        .about("List the API tokens")))
         .get_matches();
 
     let config_path = opts.get_one::<String>("config").unwrap();
-    let config = if Path::new(&config_path).exists()
+    let mut config = if Path::new(&config_path).exists()
     {
         Configuration::fromFile(&config_path)?
     }
@@ -39,6 +120,51 @@ fn main() -> Result<(), Error>
         warn!("Config file not found. Using default config...");
         Configuration::default()
     };
+    config.applyEnv()?;
+    config.validate()?;
+
+    match opts.subcommand()
+    {
+        Some(("rebuild", sub_opts)) if sub_opts.get_flag("stale-thumbnails") =>
+            return maintenance::rebuildStaleThumbnails(&config),
+        Some(("rebuild", sub_opts)) =>
+            return maintenance::rebuild(&config,
+                                        sub_opts.get_flag("thumbnails-only")),
+        Some(("import", sub_opts)) =>
+            return maintenance::importDirectory(
+                &config, Path::new(sub_opts.get_one::<String>("dir").unwrap())),
+        Some(("export-metadata", sub_opts)) =>
+            return maintenance::exportMetadata(
+                &config, Path::new(sub_opts.get_one::<String>("file").unwrap())),
+        Some(("import-metadata", sub_opts)) =>
+            return maintenance::importMetadata(
+                &config, Path::new(sub_opts.get_one::<String>("file").unwrap())),
+        Some(("useradd", sub_opts)) =>
+            return maintenance::addUser(
+                &config, sub_opts.get_one::<String>("username").unwrap()),
+        Some(("token", sub_opts)) => return match sub_opts.subcommand()
+        {
+            Some(("create", token_opts)) =>
+            {
+                let scope = if token_opts.get_flag("read-only")
+                {
+                    data::APITokenScope::ReadOnly
+                }
+                else
+                {
+                    data::APITokenScope::Upload
+                };
+                maintenance::createAPIToken(
+                    &config, token_opts.get_one::<String>("name").unwrap(),
+                    scope)
+            },
+            Some(("revoke", token_opts)) =>
+                maintenance::revokeAPIToken(
+                    &config, token_opts.get_one::<String>("name").unwrap()),
+            _ => maintenance::listAPITokens(&config),
+        },
+        _ => {},
+    }
 
     let a = app::App::new(config)?;
     tokio::runtime::Runtime::new().unwrap().block_on(a.serve())?;
@@ -0,0 +1,892 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use log::error as log_error;
+
+use crate::error::Error;
+use crate::config::{Configuration, StorageConfig};
+use crate::data;
+use crate::password;
+use crate::storage;
+use crate::storage::keyFromPath;
+use crate::video::{Video, Chapter, ContainerType, Visibility};
+use crate::video_processing::{videoPath, hashFile, randomTempFilename, removeHLS,
+                              sha256File, RawVideo, UploadOptions};
+
+/// Open and initialize the database specified in the config.
+pub fn openDatabase(config: &Configuration) -> Result<data::Manager, Error>
+{
+    let mut data_manager = data::Manager::newWithFilename(
+        config.databasePath());
+    data_manager.connect()?;
+    data_manager.init()?;
+    Ok(data_manager)
+}
+
+/// The result of `deleteVideos`.
+pub struct DeleteResult
+{
+    /// IDs of the deleted videos.
+    pub deleted: Vec<String>,
+    /// IDs of the videos that failed to be deleted, and why.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Delete the videos with IDs in “ids” from the database, and then
+/// their files from the storage, and their HLS packages. Videos that
+/// are not found are failed. A failure to remove the files does not
+/// bring back the database entry of the video.
+pub fn deleteVideos(ids: &[String], config: &Configuration,
+                    data_manager: &data::Manager,
+                    storage: &dyn storage::Storage) ->
+    Result<DeleteResult, Error>
+{
+    let deleted = data_manager.deleteVideos(ids)?;
+    let mut succeeded = Vec::new();
+    let mut failed = Vec::new();
+    for id in ids
+    {
+        if !deleted.iter().any(|v| &v.id == id)
+        {
+            failed.push((id.clone(), String::from("Video not found")));
+        }
+    }
+    for video in deleted
+    {
+        let result = std::iter::once(&video.path)
+            .chain(video.auxiliaryPaths())
+            .try_for_each(|path| storage.delete(&keyFromPath(path)?));
+        removeHLS(&video.id, config);
+        match result
+        {
+            Ok(_) => succeeded.push(video.id),
+            Err(e) =>
+            {
+                log_error!("Failed to remove files of video {}: {}",
+                           video.id, e);
+                failed.push((video.id, e.to_string()));
+            },
+        }
+    }
+    Ok(DeleteResult { deleted: succeeded, failed })
+}
+
+/// Delete the videos uploaded more than “retention_days” days ago,
+/// if it is set. Only videos with a visibility in
+/// “retention_visibilities” are deleted. Return the number of
+/// deleted videos.
+pub fn pruneOldVideos(config: &Configuration, data_manager: &data::Manager,
+                      storage: &dyn storage::Storage) -> Result<usize, Error>
+{
+    let days = match config.retention_days
+    {
+        Some(days) => days,
+        None => return Ok(0),
+    };
+    let cutoff = OffsetDateTime::now_utc().unix_timestamp()
+        .saturating_sub(days.saturating_mul(24 * 3600).min(i64::MAX as u64)
+                        as i64);
+    let visibilities = config.retentionVisibilities();
+    let ids: Vec<String> = data_manager.getVideosUploadedBefore(cutoff)?
+        .into_iter().filter(|v| visibilities.contains(&v.visibility))
+        .map(|v| v.id).collect();
+    if ids.is_empty()
+    {
+        return Ok(0);
+    }
+    let result = deleteVideos(&ids, config, data_manager, storage)?;
+    info!("Pruned {} videos older than {} days.", result.deleted.len(), days);
+    if !result.failed.is_empty()
+    {
+        warn!("Failed to prune {} videos.", result.failed.len());
+    }
+    Ok(result.deleted.len())
+}
+
+/// Abandon the resumable uploads that have received no data in the
+/// last “pending_upload_life_time_sec” seconds, and remove their
+/// temp files. Return the number of abandoned uploads.
+pub fn expirePendingUploads(config: &Configuration,
+                            data_manager: &data::Manager) ->
+    Result<usize, Error>
+{
+    let expired = data_manager.removeExpiredUploads(
+        config.pending_upload_life_time_sec)?;
+    for upload in &expired
+    {
+        if let Err(e) = std::fs::remove_file(&upload.temp_path)
+        {
+            if e.kind() != std::io::ErrorKind::NotFound
+            {
+                warn!("Failed to remove {:?}: {}", upload.temp_path, e);
+            }
+        }
+    }
+    if !expired.is_empty()
+    {
+        info!("Removed {} abandoned uploads.", expired.len());
+    }
+    Ok(expired.len())
+}
+
+/// Where the database and the files disagree.
+#[derive(Serialize)]
+pub struct IntegrityReport
+{
+    /// Videos whose file is missing from the storage.
+    pub missing_files: Vec<Video>,
+    /// Files in the video directory that do not belong to any video,
+    /// relative to the video directory. This is always empty with
+    /// remote storage.
+    pub orphaned_files: Vec<String>,
+    /// Videos whose file is found renamed, and which now point to
+    /// the renamed file. This is only filled by `relinkMovedVideos`.
+    pub relinked: Vec<Video>,
+}
+
+/// Find the videos whose file is missing, and the files that belong
+/// to no video. Files are named after the IDs of their videos, so a
+/// file is orphaned if its name without extensions is not an ID, and
+/// it is not at the path of a video. Temp files of uploads in
+/// progress are not orphaned. Nothing is changed.
+pub fn verifyLibrary(config: &Configuration, data_manager: &data::Manager,
+                     storage: &dyn storage::Storage) ->
+    Result<IntegrityReport, Error>
+{
+    let mut missing_files = Vec::new();
+    let mut paths: HashSet<PathBuf> = HashSet::new();
+    for video in data_manager.getAllVideos()?
+    {
+        paths.insert(video.path.clone());
+        if !storage.exists(&keyFromPath(&video.path)?)?
+        {
+            missing_files.push(video);
+        }
+    }
+    let mut orphaned_files = Vec::new();
+    if matches!(config.storage, StorageConfig::Local)
+    {
+        let ids: HashSet<String> =
+            data_manager.allVideoIds()?.into_iter().collect();
+        let video_dir = Path::new(&config.video_dir);
+        let mut files = Vec::new();
+        listFiles(video_dir, &mut files)?;
+        for file in files
+        {
+            let relative = file.strip_prefix(video_dir).unwrap_or(&file);
+            let mut parts = relative.iter().filter_map(|s| s.to_str());
+            // HLS files are in a directory named after the video.
+            // Files like scrubbing previews have more than one
+            // extension.
+            let owner = match parts.next()
+            {
+                Some("hls") => parts.next(),
+                _ => file.file_name().and_then(|s| s.to_str())
+                    .and_then(|s| s.split('.').next()),
+            }.unwrap_or("");
+            if ids.contains(owner) || owner.starts_with("temp-") ||
+                paths.contains(relative)
+            {
+                continue;
+            }
+            orphaned_files.push(relative.to_string_lossy().into_owned());
+        }
+    }
+    Ok(IntegrityReport { missing_files, orphaned_files,
+                         relinked: Vec::new() })
+}
+
+/// Recover the videos in “report” whose file is renamed by hand.
+/// The IDs are from the hash of the content, so an orphaned video
+/// file whose hash matches the ID of a missing video is that video’s
+/// file. Such videos are pointed to their file, and moved from the
+/// missing videos to the relinked ones, while the file is no longer
+/// orphaned. Every orphaned video file is hashed, so this can take a
+/// while.
+///
+/// Only video files are relinked. Thumbnails and sprite sheets keep
+/// the paths recorded with the video, and renamed ones stay orphaned.
+/// They can be generated again by rebuilding the thumbnails.
+pub fn relinkMovedVideos(report: &mut IntegrityReport, config: &Configuration,
+                         data_manager: &data::Manager)
+{
+    let missing = &mut report.missing_files;
+    let relinked = &mut report.relinked;
+    let mut still_orphaned = Vec::new();
+    for file in report.orphaned_files.drain(..)
+    {
+        let path = PathBuf::from(&file);
+        let is_video = path.extension().and_then(|e| e.to_str())
+            .and_then(ContainerType::fromExtension).is_some();
+        if missing.is_empty() || !is_video || path.starts_with("hls")
+        {
+            still_orphaned.push(file);
+            continue;
+        }
+        let hash: String = match sha256File(
+            &Path::new(&config.video_dir).join(&path))
+        {
+            Ok(hash) => hash.iter().map(|b| format!("{:02x}", b)).collect(),
+            Err(e) =>
+            {
+                warn!("{}", e);
+                still_orphaned.push(file);
+                continue;
+            },
+        };
+        // IDs are prefixes of the hash, of “id_length_bytes” at the
+        // time of the upload.
+        let index = match missing.iter().position(
+            |v| !v.id.is_empty() && hash.starts_with(&v.id))
+        {
+            Some(index) => index,
+            None =>
+            {
+                still_orphaned.push(file);
+                continue;
+            },
+        };
+        if let Err(e) = data_manager.updateVideoPath(&missing[index].id, &path)
+        {
+            log_error!("Failed to relink video {}: {}", missing[index].id, e);
+            still_orphaned.push(file);
+            continue;
+        }
+        let mut video = missing.remove(index);
+        info!("Relinked video {} from {:?} to {:?}.", video.id, video.path,
+              path);
+        video.path = path;
+        relinked.push(video);
+    }
+    report.orphaned_files = still_orphaned;
+}
+
+/// Re-probe the metadata and regenerate the thumbnail of every
+/// video in the library. If “thumbnails_only” is true, only the
+/// thumbnails are regenerated. Videos whose file is missing are
+/// skipped.
+pub fn rebuild(config: &Configuration, thumbnails_only: bool) ->
+    Result<(), Error>
+{
+    // Probing and thumbnail generation need the video files on the
+    // local file system.
+    if !matches!(config.storage, StorageConfig::Local)
+    {
+        return Err(rterr!("Rebuilding is only supported with local storage."));
+    }
+    let storage = storage::fromConfig(config);
+    let data_manager = openDatabase(config)?;
+    let videos = data_manager.getAllVideos()?;
+    let mut rebuilt = 0;
+    let mut skipped = 0;
+    let mut failed = 0;
+    for video in videos
+    {
+        let path = videoPath(&video, config);
+        if !storage.exists(&keyFromPath(&video.path)?)?
+        {
+            warn!("Video file of {} not found at {:?}. Skipped.",
+                  video.id, path);
+            skipped += 1;
+            continue;
+        }
+        info!("Rebuilding video {}...", video.id);
+        // Thumbnails in an old format are replaced.
+        let old_paths: Vec<PathBuf> = video.auxiliaryPaths().cloned().collect();
+        let video = if thumbnails_only
+        {
+            Ok(video)
+        }
+        else
+        {
+            video.reprobeMetadata(config)
+        };
+        let result = video.and_then(|v| v.generateThumbnail(config))
+            .and_then(|v| v.generateScrubThumbnails(config))
+            .and_then(|v| {
+                data_manager.updateVideo(&v)?;
+                if !thumbnails_only
+                {
+                    data_manager.setChapters(&v.id, &v.chapters)?;
+                    removeHLS(&v.id, config);
+                }
+                for path in &old_paths
+                {
+                    if !v.auxiliaryPaths().any(|p| p == path)
+                    {
+                        storage.delete(&keyFromPath(path)?)?;
+                    }
+                }
+                Ok(())
+            });
+        if let Err(e) = result
+        {
+            log_error!("{}", e);
+            failed += 1;
+        }
+        else
+        {
+            rebuilt += 1;
+        }
+    }
+    println!("Rebuilt {} videos, skipped {}, failed {}.", rebuilt, skipped,
+             failed);
+    Ok(())
+}
+
+/// Regenerate the thumbnails generated with a quality other than
+/// “thumbnail_quality”. Uploaded thumbnails are kept. Return the
+/// number of regenerated thumbnails.
+pub fn regenerateStaleThumbnails(config: &Configuration,
+                                 data_manager: &data::Manager,
+                                 storage: &dyn storage::Storage) ->
+    Result<usize, Error>
+{
+    // Thumbnail generation needs the video files on the local file
+    // system.
+    if !matches!(config.storage, StorageConfig::Local)
+    {
+        return Err(rterr!("Regenerating thumbnails is only supported with \
+                           local storage."));
+    }
+    let mut count = 0;
+    for video in data_manager.getVideosWithStaleThumbnail(
+        config.thumbnail_quality)?
+    {
+        info!("Regenerating thumbnail of video {}...", video.id);
+        // The thumbnail format may have changed as well.
+        let old_path = video.thumbnail_path.clone();
+        let result = video.generateThumbnail(config).and_then(|v| {
+            if v.thumbnail_quality != Some(config.thumbnail_quality)
+            {
+                return Err(rterr!("Failed to generate thumbnail of video {}",
+                                  v.id));
+            }
+            data_manager.updateVideo(&v)?;
+            if let Some(path) = old_path.filter(
+                |p| v.thumbnail_path.as_ref() != Some(p))
+            {
+                storage.delete(&keyFromPath(&path)?)?;
+            }
+            Ok(())
+        });
+        match result
+        {
+            Ok(_) => count += 1,
+            Err(e) => log_error!("{}", e),
+        }
+    }
+    if count > 0
+    {
+        info!("Regenerated {} thumbnails.", count);
+    }
+    Ok(count)
+}
+
+/// Regenerate the thumbnails generated with a quality other than
+/// “thumbnail_quality”, for the “rebuild” command.
+pub fn rebuildStaleThumbnails(config: &Configuration) -> Result<(), Error>
+{
+    let storage = storage::fromConfig(config);
+    let data_manager = openDatabase(config)?;
+    let count = regenerateStaleThumbnails(config, &data_manager,
+                                          storage.as_ref())?;
+    println!("Regenerated {} thumbnails.", count);
+    Ok(())
+}
+
+/// Collect the files under “dir” recursively, in a stable order.
+fn listFiles(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error>
+{
+    let mut entries = std::fs::read_dir(dir).map_err(
+        |e| rterr!("Failed to read directory {:?}: {}", dir, e))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| rterr!("Failed to read directory {:?}: {}", dir, e))?;
+    entries.sort();
+    for path in entries
+    {
+        if path.is_dir()
+        {
+            listFiles(&path, files)?;
+        }
+        else
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Metadata in a sidecar file next to an imported video. These
+/// take precedence over the metadata embedded in the video, which in
+/// turn take precedence over the filename.
+#[derive(Deserialize, Default)]
+struct SidecarMetadata
+{
+    title: Option<String>,
+    desc: Option<String>,
+    artist: Option<String>,
+    /// Accepted for compatibility with other tools. Videos do not
+    /// have tags yet, so these are not used.
+    #[serde(default)]
+    #[allow(dead_code)]
+    tags: Vec<String>,
+}
+
+/// Whether “path” is a sidecar metadata file.
+fn isSidecar(path: &Path) -> bool
+{
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+/// Read the sidecar metadata of the video at “path”, which is
+/// “<path>.json” (like “a.mp4.json”), or the video path with the
+/// extension replaced by “.json” (like “a.json”), whichever is found
+/// first.
+fn readSidecar(path: &Path) -> Result<Option<SidecarMetadata>, Error>
+{
+    let mut with_json = path.as_os_str().to_owned();
+    with_json.push(".json");
+    for sidecar in [PathBuf::from(with_json), path.with_extension("json")]
+    {
+        if !sidecar.is_file()
+        {
+            continue;
+        }
+        let content = std::fs::read_to_string(&sidecar).map_err(
+            |e| rterr!("Failed to read {:?}: {}", sidecar, e))?;
+        return serde_json::from_str(&content).map(Some).map_err(
+            |e| rterr!("Invalid sidecar metadata in {:?}: {}", sidecar, e));
+    }
+    Ok(None)
+}
+
+/// Copy the video at “path” into the video directory, and add it to
+/// the library.
+fn importVideo(path: &Path, hash: String, config: &Configuration,
+               storage: &dyn storage::Storage, data_manager: &data::Manager) ->
+    Result<(), Error>
+{
+    let sidecar = readSidecar(path)?.unwrap_or_default();
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let temp_file = randomTempFilename(&config.video_dir).with_extension(ext);
+    if let Err(e) = std::fs::copy(path, &temp_file)
+    {
+        std::fs::remove_file(&temp_file).ok();
+        return Err(rterr!("Failed to copy {:?}: {}", path, e));
+    }
+    RawVideo {
+        path: temp_file,
+        hash,
+        original_filename: path.file_name().and_then(|n| n.to_str())
+            .unwrap_or("").to_owned(),
+    }.ingest(UploadOptions {
+        visibility: Visibility::Public,
+        uploader: String::from("import"),
+        thumbnail: None,
+        title: sidecar.title,
+        desc: sidecar.desc,
+        artist: sidecar.artist,
+    }, config, storage, data_manager)
+}
+
+/// Add the videos under “dir” to the library, as if they were
+/// uploaded. The files are copied, and the originals are left alone.
+/// Only files with a supported extension are considered, unless
+/// transcoding is enabled. Videos already in the library are
+/// skipped. Metadata in sidecar JSON files are used if present; see
+/// `readSidecar`.
+pub fn importDirectory(config: &Configuration, dir: &Path) -> Result<(), Error>
+{
+    let storage = storage::fromConfig(config);
+    let data_manager = openDatabase(config)?;
+    let mut files = Vec::new();
+    listFiles(dir, &mut files)?;
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut failed = 0;
+    for path in files
+    {
+        let supported = (config.transcode_on_upload && !isSidecar(&path)) ||
+            path.extension().and_then(|e| e.to_str())
+            .and_then(ContainerType::fromExtension).is_some();
+        if !supported
+        {
+            continue;
+        }
+        let result = hashFile(&path, config).and_then(|hash| {
+            if data_manager.findVideoByID(&hash)?.is_some()
+            {
+                return Ok(false);
+            }
+            info!("Importing {:?}...", path);
+            importVideo(&path, hash, config, storage.as_ref(), &data_manager)
+                .map(|_| true)
+        });
+        match result
+        {
+            Ok(true) => imported += 1,
+            Ok(false) =>
+            {
+                info!("{:?} is already in the library. Skipped.", path);
+                skipped += 1;
+            },
+            Err(e) =>
+            {
+                log_error!("Failed to import {:?}: {}", path, e);
+                failed += 1;
+            },
+        }
+    }
+    println!("Imported {} videos, skipped {}, failed {}.", imported, skipped,
+             failed);
+    Ok(())
+}
+
+/// A video in the bulk metadata file of `exportMetadata`. This has
+/// every column of the video, in the same units as the database, so
+/// that an export followed by an import loses nothing.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+struct VideoRecord
+{
+    id: String,
+    path: String,
+    title: String,
+    desc: String,
+    artist: String,
+    views: u32,
+    /// Seconds since the Unix epoch.
+    upload_time: i64,
+    /// The extension name, like “mp4”.
+    container_type: String,
+    original_filename: String,
+    /// In seconds.
+    duration: f64,
+    thumbnail_path: Option<String>,
+    file_size: Option<u64>,
+    bitrate: Option<u64>,
+    visibility: String,
+    uploader: String,
+    sprite_path: Option<String>,
+    sprite_vtt_path: Option<String>,
+    pinned: bool,
+    thumbnail_quality: Option<u8>,
+    #[serde(default)]
+    autoplay: bool,
+    #[serde(default, rename = "loop")]
+    looping: bool,
+    /// These are in their own table, and loaded separately.
+    #[serde(default)]
+    chapters: Vec<ChapterRecord>,
+}
+
+/// A chapter of a `VideoRecord`.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+struct ChapterRecord
+{
+    /// In seconds.
+    start: f64,
+    title: String,
+}
+
+fn path2String(path: &Path) -> Result<String, Error>
+{
+    path.to_str().map(str::to_owned).ok_or_else(
+        || rterr!("Invalid path: {:?}", path))
+}
+
+impl VideoRecord
+{
+    fn fromVideo(video: &Video) -> Result<Self, Error>
+    {
+        Ok(Self {
+            id: video.id.clone(),
+            path: path2String(&video.path)?,
+            title: video.title.clone(),
+            desc: video.desc.clone(),
+            artist: video.artist.clone(),
+            views: video.views,
+            upload_time: video.upload_time.unix_timestamp(),
+            container_type: video.container_type.toExtension().to_owned(),
+            original_filename: video.original_filename.clone(),
+            duration: video.duration.as_seconds_f64(),
+            thumbnail_path: video.thumbnail_path.as_deref().map(path2String)
+                .transpose()?,
+            file_size: video.file_size,
+            bitrate: video.bitrate,
+            visibility: video.visibility.toName().to_owned(),
+            uploader: video.uploader.clone(),
+            sprite_path: video.sprite_path.as_deref().map(path2String)
+                .transpose()?,
+            sprite_vtt_path: video.sprite_vtt_path.as_deref()
+                .map(path2String).transpose()?,
+            pinned: video.pinned,
+            thumbnail_quality: video.thumbnail_quality,
+            autoplay: video.autoplay,
+            looping: video.looping,
+            chapters: video.chapters.iter().map(|c| ChapterRecord {
+                start: c.start.as_seconds_f64(),
+                title: c.title.clone(),
+            }).collect(),
+        })
+    }
+
+    fn toVideo(self) -> Result<Video, Error>
+    {
+        let chapters = self.chapters.into_iter().map(|c| Ok(Chapter {
+            start: time::Duration::checked_seconds_f64(c.start).ok_or_else(
+                || rterr!("Invalid chapter start of video {}: {}", self.id,
+                          c.start))?,
+            title: c.title,
+        })).collect::<Result<Vec<_>, Error>>()?;
+        Ok(Video {
+            upload_time: OffsetDateTime::from_unix_timestamp(self.upload_time)
+                .map_err(|_| rterr!("Invalid upload time of video {}: {}",
+                                    self.id, self.upload_time))?,
+            container_type: ContainerType::fromExtension(&self.container_type)
+                .ok_or_else(|| rterr!("Invalid container type of video {}: {}",
+                                      self.id, self.container_type))?,
+            duration: time::Duration::checked_seconds_f64(self.duration)
+                .ok_or_else(|| rterr!("Invalid duration of video {}: {}",
+                                      self.id, self.duration))?,
+            visibility: Visibility::fromName(&self.visibility)
+                .ok_or_else(|| rterr!("Invalid visibility of video {}: {}",
+                                      self.id, self.visibility))?,
+            id: self.id,
+            path: PathBuf::from(self.path),
+            title: self.title,
+            desc: self.desc,
+            artist: self.artist,
+            views: self.views,
+            original_filename: self.original_filename,
+            thumbnail_path: self.thumbnail_path.map(PathBuf::from),
+            file_size: self.file_size,
+            bitrate: self.bitrate,
+            uploader: self.uploader,
+            sprite_path: self.sprite_path.map(PathBuf::from),
+            sprite_vtt_path: self.sprite_vtt_path.map(PathBuf::from),
+            pinned: self.pinned,
+            thumbnail_quality: self.thumbnail_quality,
+            autoplay: self.autoplay,
+            looping: self.looping,
+            chapters,
+        })
+    }
+}
+
+/// Write the metadata of all videos to “path” as a JSON array. See
+/// `VideoRecord`. The video files themselves are not exported.
+pub fn exportMetadata(config: &Configuration, path: &Path) -> Result<(), Error>
+{
+    let data_manager = openDatabase(config)?;
+    let records = data_manager.getAllVideos()?.into_iter().map(|mut video| {
+        video.chapters = data_manager.getChapters(&video.id)?;
+        VideoRecord::fromVideo(&video)
+    }).collect::<Result<Vec<_>, _>>()?;
+    let content = serde_json::to_string_pretty(&records).map_err(
+        |e| rterr!("Failed to serialize metadata: {}", e))?;
+    std::fs::write(path, content).map_err(
+        |e| rterr!("Failed to write {:?}: {}", path, e))?;
+    println!("Exported {} videos.", records.len());
+    Ok(())
+}
+
+/// Read a file written by `exportMetadata`, and add or overwrite the
+/// videos in it, matched by ID. The video files must already be in
+/// the storage; videos whose files are missing are skipped.
+pub fn importMetadata(config: &Configuration, path: &Path) -> Result<(), Error>
+{
+    let content = std::fs::read_to_string(path).map_err(
+        |e| rterr!("Failed to read {:?}: {}", path, e))?;
+    let records: Vec<VideoRecord> = serde_json::from_str(&content).map_err(
+        |e| rterr!("Invalid metadata in {:?}: {}", path, e))?;
+    let storage = storage::fromConfig(config);
+    let data_manager = openDatabase(config)?;
+    let mut imported = 0;
+    let mut skipped = 0;
+    for record in records
+    {
+        let video = record.toVideo()?;
+        if !storage.exists(&keyFromPath(&video.path)?)?
+        {
+            warn!("File of video {} not found at {:?}. Skipped.", video.id,
+                  video.path);
+            skipped += 1;
+            continue;
+        }
+        data_manager.upsertVideo(&video)?;
+        data_manager.setChapters(&video.id, &video.chapters)?;
+        imported += 1;
+    }
+    println!("Imported metadata of {} videos, skipped {}.", imported, skipped);
+    Ok(())
+}
+
+/// Add a user account, or change the password of an existing one.
+/// The password is read from the first line of the standard input.
+/// Once a user exists, the site password no longer logs in.
+pub fn addUser(config: &Configuration, username: &str) -> Result<(), Error>
+{
+    if username.is_empty() || username.contains(':')
+    {
+        return Err(rterr!("Invalid username: {:?}", username));
+    }
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).map_err(
+        |e| rterr!("Failed to read password: {}", e))?;
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty()
+    {
+        return Err(rterr!("Empty password"));
+    }
+    let data_manager = openDatabase(config)?;
+    data_manager.setUser(username, &password::hashPassword(password))?;
+    println!("Set password of user {}.", username);
+    Ok(())
+}
+
+/// Create an API token named “name”, and print it. The token is only
+/// shown here.
+pub fn createAPIToken(config: &Configuration, name: &str,
+                      scope: data::APITokenScope) -> Result<(), Error>
+{
+    let data_manager = openDatabase(config)?;
+    let token = format!("{:032x}", rand::random::<u128>());
+    data_manager.addAPIToken(&token, name, scope)?;
+    println!("{}", token);
+    Ok(())
+}
+
+pub fn revokeAPIToken(config: &Configuration, name: &str) -> Result<(), Error>
+{
+    let data_manager = openDatabase(config)?;
+    if !data_manager.removeAPIToken(name)?
+    {
+        return Err(rterr!("API token {} not found", name));
+    }
+    println!("Revoked API token {}.", name);
+    Ok(())
+}
+
+pub fn listAPITokens(config: &Configuration) -> Result<(), Error>
+{
+    let data_manager = openDatabase(config)?;
+    for token in data_manager.getAPITokens()?
+    {
+        println!("{}\t{}\t{}", token.name, token.scope.toName(),
+                 token.create_time.date());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn testReadSidecar() -> Result<(), Error>
+    {
+        let dir = randomTempFilename(std::env::temp_dir());
+        std::fs::create_dir_all(&dir).unwrap();
+        let video = dir.join("a.mp4");
+        assert!(readSidecar(&video)?.is_none());
+
+        std::fs::write(dir.join("a.json"),
+                       r#"{"title": "Short", "tags": ["x"]}"#).unwrap();
+        assert_eq!(readSidecar(&video)?.unwrap().title.as_deref(),
+                   Some("Short"));
+        // “a.mp4.json” is preferred.
+        std::fs::write(dir.join("a.mp4.json"),
+                       r#"{"title": "Long", "artist": "Me"}"#).unwrap();
+        let sidecar = readSidecar(&video)?.unwrap();
+        assert_eq!(sidecar.title.as_deref(), Some("Long"));
+        assert_eq!(sidecar.artist.as_deref(), Some("Me"));
+        assert!(sidecar.desc.is_none());
+
+        std::fs::write(dir.join("a.mp4.json"), "not json").unwrap();
+        assert!(readSidecar(&video).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn testRelinkMovedVideo() -> Result<(), Error>
+    {
+        let dir = randomTempFilename(std::env::temp_dir());
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Configuration {
+            video_dir: dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let file = dir.join("renamed.mp4");
+        std::fs::write(&file, "video content").unwrap();
+        let id = hashFile(&file, &config)?;
+        data_manager.addVideo(&Video::new(id.clone(), format!("{}.mp4", id)))?;
+        std::fs::write(dir.join("other.mp4"), "other content").unwrap();
+
+        let storage = storage::fromConfig(&config);
+        let mut report = verifyLibrary(&config, &data_manager,
+                                       storage.as_ref())?;
+        assert_eq!(report.missing_files.len(), 1);
+        assert_eq!(report.orphaned_files.len(), 2);
+        assert!(report.relinked.is_empty());
+        relinkMovedVideos(&mut report, &config, &data_manager);
+        assert!(report.missing_files.is_empty());
+        assert_eq!(report.orphaned_files, vec!["other.mp4"]);
+        assert_eq!(report.relinked.len(), 1);
+        assert_eq!(data_manager.findVideoByID(&id)?.unwrap().path,
+                   Path::new("renamed.mp4"));
+        // The renamed file now belongs to the video.
+        let report = verifyLibrary(&config, &data_manager, storage.as_ref())?;
+        assert_eq!(report.orphaned_files, vec!["other.mp4"]);
+        assert!(report.relinked.is_empty());
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn testVideoRecord() -> Result<(), Error>
+    {
+        let mut video = Video::new(String::from("abc"), "abc.mp4".to_owned());
+        video.views = 42;
+        video.upload_time = OffsetDateTime::from_unix_timestamp(1700000000)
+            .unwrap();
+        video.duration = time::Duration::seconds_f64(61.234567);
+        video.thumbnail_path = Some(PathBuf::from("abc.webp"));
+        video.file_size = Some(1 << 40);
+        video.visibility = Visibility::Unlisted;
+        video.uploader = String::from("token:script");
+        video.pinned = true;
+        video.looping = true;
+        video.chapters = vec![
+            Chapter { start: time::Duration::ZERO, title: String::new() },
+            Chapter { start: time::Duration::seconds_f64(30.5),
+                      title: String::from("Second") },
+        ];
+        let record = VideoRecord::fromVideo(&video)?;
+        let json = serde_json::to_string(&record).unwrap();
+        let parsed: VideoRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, record);
+        let restored = parsed.toVideo()?;
+        assert_eq!(restored.duration, video.duration);
+        assert_eq!(restored.chapters.len(), 2);
+        assert_eq!(restored.chapters[1].start, video.chapters[1].start);
+        assert_eq!(VideoRecord::fromVideo(&restored)?, record);
+
+        let mut invalid = record;
+        invalid.visibility = String::from("secret");
+        assert!(invalid.toVideo().is_err());
+        Ok(())
+    }
+}
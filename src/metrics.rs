@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use warp::http::status::StatusCode;
+
+use crate::data::LibraryStats;
+use crate::error::Error;
+
+/// Counters of what happened since the server started. These are
+/// exposed at “/metrics” in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics
+{
+    uploads: AtomicU64,
+    views: AtomicU64,
+    /// Number of failed uploads, keyed by reason.
+    upload_failures: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// A short label of why an upload failed.
+fn failureReason(e: &Error) -> &'static str
+{
+    match e
+    {
+        Error::HTTPStatus(StatusCode::BAD_REQUEST, _) => "bad_request",
+        Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE, _) => "too_large",
+        Error::HTTPStatus(StatusCode::CONFLICT, _) => "conflict",
+        Error::HTTPStatus(StatusCode::TOO_MANY_REQUESTS, _) => "quota_exceeded",
+        Error::HTTPStatus(StatusCode::SERVICE_UNAVAILABLE, _) => "busy",
+        Error::HTTPStatus(_, _) => "other",
+        Error::DataError(_) => "data_error",
+        Error::RuntimeError(_) => "runtime_error",
+    }
+}
+
+impl Metrics
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn countUpload(&self)
+    {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn countView(&self)
+    {
+        self.views.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn countUploadFailure(&self, e: &Error)
+    {
+        *self.upload_failures.lock().unwrap().entry(failureReason(e))
+            .or_insert(0) += 1;
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    /// The gauges come from “stats”.
+    pub fn render(&self, stats: &LibraryStats) -> String
+    {
+        let mut result = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str,
+                          samples: &[(String, u64)]| {
+            writeln!(result, "# HELP {} {}", name, help).unwrap();
+            writeln!(result, "# TYPE {} {}", name, kind).unwrap();
+            for (labels, value) in samples
+            {
+                writeln!(result, "{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        metric("metube_uploads_total", "counter",
+               "Number of videos uploaded.",
+               &[(String::new(), self.uploads.load(Ordering::Relaxed))]);
+        metric("metube_views_total", "counter",
+               "Number of video pages served.",
+               &[(String::new(), self.views.load(Ordering::Relaxed))]);
+        let failures: Vec<(String, u64)> = self.upload_failures.lock()
+            .unwrap().iter().map(
+                |(reason, count)| (format!("{{reason=\"{}\"}}", reason), *count))
+            .collect();
+        metric("metube_upload_failures_total", "counter",
+               "Number of failed uploads by reason.", &failures);
+        metric("metube_videos", "gauge", "Number of videos in the library.",
+               &[(String::new(), stats.video_count)]);
+        metric("metube_storage_bytes", "gauge",
+               "Total size of the video files in bytes.",
+               &[(String::new(), stats.total_size)]);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn testRender()
+    {
+        let metrics = Metrics::new();
+        metrics.countUpload();
+        metrics.countView();
+        metrics.countView();
+        metrics.countUploadFailure(&Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, String::new()));
+        let text = metrics.render(&LibraryStats {
+            video_count: 3, total_size: 1024, total_views: 10 });
+        assert!(text.contains("\nmetube_uploads_total 1\n"));
+        assert!(text.contains("\nmetube_views_total 2\n"));
+        assert!(text.contains(
+            "\nmetube_upload_failures_total{reason=\"bad_request\"} 1\n"));
+        assert!(text.contains("# TYPE metube_videos gauge\nmetube_videos 3\n"));
+        assert!(text.contains("\nmetube_storage_bytes 1024\n"));
+    }
+}
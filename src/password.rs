@@ -0,0 +1,77 @@
+use base64::engine::Engine;
+use sha2::Sha256;
+
+static BASE64: &base64::engine::general_purpose::GeneralPurpose =
+    &base64::engine::general_purpose::STANDARD_NO_PAD;
+
+/// Number of PBKDF2 iterations of new password hashes. Existing
+/// hashes keep the number they are created with.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// PBKDF2 with HMAC-SHA256, deriving a single 32-byte block.
+fn pbkdf2SHA256(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8>
+{
+    let mut result = vec![0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut result);
+    result
+}
+
+fn hashPasswordWithIterations(password: &str, iterations: u32) -> String
+{
+    let salt = rand::random::<[u8; 16]>();
+    format!("pbkdf2-sha256${}${}${}", iterations, BASE64.encode(salt),
+            BASE64.encode(pbkdf2SHA256(password.as_bytes(), &salt,
+                                       iterations)))
+}
+
+/// Hash a password to be stored, in the form of
+/// “pbkdf2-sha256$<iterations>$<salt>$<hash>”.
+pub fn hashPassword(password: &str) -> String
+{
+    hashPasswordWithIterations(password, PBKDF2_ITERATIONS)
+}
+
+/// Whether “password” matches the hash from `hashPassword`. An
+/// invalid hash matches nothing.
+pub fn verifyPassword(password: &str, hash: &str) -> bool
+{
+    let parts: Vec<&str> = hash.split('$').collect();
+    let [scheme, iterations, salt, expected] = parts[..] else
+    {
+        return false;
+    };
+    let (Ok(iterations), Ok(salt), Ok(expected)) =
+        (iterations.parse::<u32>(), BASE64.decode(salt), BASE64.decode(expected))
+    else
+    {
+        return false;
+    };
+    if scheme != "pbkdf2-sha256" || iterations == 0
+    {
+        return false;
+    }
+    let actual = pbkdf2SHA256(password.as_bytes(), &salt, iterations);
+    // Compare in constant time.
+    actual.len() == expected.len() &&
+        actual.iter().zip(&expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn testPassword()
+    {
+        // Test vector from RFC 7914, section 11.
+        let key = pbkdf2SHA256(b"passwd", b"salt", 1);
+        assert_eq!(key[..8], [0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f]);
+
+        let hash = hashPasswordWithIterations("secret", 10);
+        assert!(verifyPassword("secret", &hash));
+        assert!(!verifyPassword("Secret", &hash));
+        assert!(!verifyPassword("secret", "plain"));
+        assert_ne!(hash, hashPasswordWithIterations("secret", 10));
+    }
+}
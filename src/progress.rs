@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use warp::http::status::StatusCode;
+
+use crate::error::Error;
+
+#[derive(Clone, Copy)]
+pub struct Progress
+{
+    /// Number of bytes of the video file received so far.
+    pub received: u64,
+    /// Length of the whole upload request, if the client sent one.
+    /// This includes the other form fields, so it is a bit larger
+    /// than the video file.
+    pub total: Option<u64>,
+}
+
+impl Progress
+{
+    pub fn percent(&self) -> Option<u64>
+    {
+        self.total.filter(|t| *t > 0)
+            .map(|t| (self.received.saturating_mul(100) / t).min(100))
+    }
+}
+
+/// The progress of the uploads that are being received, keyed by an
+/// ID chosen by the client. These are reported at
+/// “/upload/progress/<id>”.
+#[derive(Default)]
+pub struct UploadProgress
+{
+    uploads: Mutex<HashMap<String, Progress>>,
+}
+
+/// Whether “id” can be used as an upload ID. This is a bit lenient
+/// so that clients can use UUIDs or random hex strings.
+pub fn isValidUploadID(id: &str) -> bool
+{
+    !id.is_empty() && id.len() <= 64 && id.chars().all(
+        |c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl UploadProgress
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Start tracking an upload. The upload is tracked until the
+    /// returned handle is dropped.
+    pub fn start(self: &Arc<Self>, id: &str, total: Option<u64>) ->
+        Result<ProgressHandle, Error>
+    {
+        if !isValidUploadID(id)
+        {
+            return Err(Error::HTTPStatus(
+                StatusCode::BAD_REQUEST, format!("Invalid upload ID: {}", id)));
+        }
+        let mut uploads = self.uploads.lock().unwrap();
+        if uploads.contains_key(id)
+        {
+            return Err(Error::HTTPStatus(
+                StatusCode::CONFLICT,
+                format!("Upload {} is already in progress", id)));
+        }
+        uploads.insert(id.to_owned(), Progress { received: 0, total });
+        Ok(ProgressHandle { progress: self.clone(), id: id.to_owned() })
+    }
+
+    /// The progress of upload “id”, or None if it is not (or no
+    /// longer) being received.
+    pub fn get(&self, id: &str) -> Option<Progress>
+    {
+        self.uploads.lock().unwrap().get(id).copied()
+    }
+}
+
+/// Reports the bytes received of one upload.
+pub struct ProgressHandle
+{
+    progress: Arc<UploadProgress>,
+    id: String,
+}
+
+impl ProgressHandle
+{
+    /// Set the total length, when it is only known after the upload
+    /// has started.
+    pub fn setTotal(&self, total: Option<u64>)
+    {
+        if let Some(p) = self.progress.uploads.lock().unwrap().get_mut(&self.id)
+        {
+            p.total = total;
+        }
+    }
+
+    pub fn add(&self, bytes: u64)
+    {
+        if let Some(p) = self.progress.uploads.lock().unwrap().get_mut(&self.id)
+        {
+            p.received += bytes;
+        }
+    }
+}
+
+impl Drop for ProgressHandle
+{
+    fn drop(&mut self)
+    {
+        self.progress.uploads.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The resumable uploads that are being appended to by a PATCH
+/// request. Two requests with the same offset would otherwise both
+/// pass the offset check and both append.
+#[derive(Default)]
+pub struct UploadLocks
+{
+    ids: Mutex<HashSet<String>>,
+}
+
+impl UploadLocks
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Lock upload “id” until the returned guard is dropped. It is
+    /// `LOCKED` if another request holds the lock.
+    pub fn lock(self: &Arc<Self>, id: &str) -> Result<UploadLock, Error>
+    {
+        if !self.ids.lock().unwrap().insert(id.to_owned())
+        {
+            return Err(Error::HTTPStatus(
+                StatusCode::LOCKED,
+                format!("Upload {} is being written by another request", id)));
+        }
+        Ok(UploadLock { locks: self.clone(), id: id.to_owned() })
+    }
+}
+
+pub struct UploadLock
+{
+    locks: Arc<UploadLocks>,
+    id: String,
+}
+
+impl Drop for UploadLock
+{
+    fn drop(&mut self)
+    {
+        self.locks.ids.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn testProgress() -> Result<(), Error>
+    {
+        let progress = Arc::new(UploadProgress::new());
+        assert!(progress.start("a/b", None).is_err());
+        let handle = progress.start("abc", Some(200))?;
+        assert!(progress.start("abc", None).is_err());
+        handle.add(50);
+        assert_eq!(progress.get("abc").unwrap().percent(), Some(25));
+        drop(handle);
+        assert!(progress.get("abc").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn testUploadLocks() -> Result<(), Error>
+    {
+        let locks = Arc::new(UploadLocks::new());
+        let lock = locks.lock("abc")?;
+        match locks.lock("abc")
+        {
+            Err(Error::HTTPStatus(code, _)) =>
+                assert_eq!(code, StatusCode::LOCKED),
+            _ => panic!("Upload is locked twice"),
+        }
+        locks.lock("def")?;
+        drop(lock);
+        locks.lock("abc")?;
+        Ok(())
+    }
+}
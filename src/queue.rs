@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use log::info;
+use log::error as log_error;
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::urls::feedURL;
+use crate::config::Configuration;
+use crate::data;
+use crate::error::Error;
+use crate::storage::Storage;
+use crate::video::{Video, Visibility};
+use crate::websub;
+
+/// Maximal number of videos waiting to be processed. Submitting more
+/// waits until there is room.
+const QUEUE_SIZE: usize = 100;
+
+/// A queue of admitted videos that are processed in the background
+/// by a fixed number of workers. See `Video::process`. Queued videos
+/// are recorded in the database, so that the ones left unprocessed
+/// by a shutdown are queued again at the next start.
+#[derive(Clone)]
+pub struct ProcessingQueue
+{
+    sender: mpsc::Sender<Video>,
+    data_manager: data::Manager,
+}
+
+/// Tell the WebSub hub, if any, that the feed has a new video.
+fn notifyHub(config: &Configuration)
+{
+    if let Some(hub) = &config.websub_hub
+    {
+        if let Err(e) = websub::publish(hub, &feedURL(config))
+        {
+            log_error!("{}", e);
+        }
+    }
+}
+
+impl ProcessingQueue
+{
+    /// Start the workers. This must be called inside a tokio
+    /// runtime.
+    pub fn start(config: Configuration, storage: Arc<dyn Storage>,
+                 data_manager: data::Manager) -> Self
+    {
+        let (sender, mut receiver) = mpsc::channel::<Video>(QUEUE_SIZE);
+        let workers = Arc::new(Semaphore::new(config.processing_workers));
+        let config = Arc::new(config);
+        let queue = Self { sender, data_manager: data_manager.clone() };
+        queue.requeueUnprocessed();
+        tokio::spawn(async move {
+            while let Some(video) = receiver.recv().await
+            {
+                let permit = workers.clone().acquire_owned().await.unwrap();
+                let config = config.clone();
+                let storage = storage.clone();
+                let data_manager = data_manager.clone();
+                tokio::task::spawn_blocking(move || {
+                    let id = video.id.clone();
+                    let public = video.visibility == Visibility::Public;
+                    match video.process(&config, storage.as_ref(),
+                                        &data_manager)
+                    {
+                        Ok(()) => if public
+                        {
+                            notifyHub(&config);
+                        },
+                        Err(e) => log_error!("Failed to process video {}: {}",
+                                             id, e),
+                    }
+                    if let Err(e) = data_manager.removeUnprocessedVideo(&id)
+                    {
+                        log_error!("{}", e);
+                    }
+                    drop(permit);
+                });
+            }
+        });
+        queue
+    }
+
+    /// Queue the videos that were submitted but not processed before
+    /// the last shutdown.
+    fn requeueUnprocessed(&self)
+    {
+        let videos = match self.data_manager.getUnprocessedVideos()
+        {
+            Ok(videos) => videos,
+            Err(e) =>
+            {
+                log_error!("{}", e);
+                return;
+            },
+        };
+        if videos.is_empty()
+        {
+            return;
+        }
+        info!("Queueing {} unprocessed videos...", videos.len());
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            for video in videos
+            {
+                if sender.send(video).await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    pub async fn submit(&self, video: Video) -> Result<(), Error>
+    {
+        self.data_manager.addUnprocessedVideo(&video.id)?;
+        self.sender.send(video).await.map_err(
+            |_| rterr!("Processing queue is closed"))
+    }
+}
@@ -0,0 +1,67 @@
+use crate::config::Configuration;
+
+/// The path of a page or file, without the “serve_under_path”
+/// prefix. Section pages end with a slash, and the others do not;
+/// see `app::SLASHED_SECTIONS`.
+pub fn urlFor(name: &str, arg: &str) -> String
+{
+    match name
+    {
+        "index" => String::from("/"),
+        "video" => String::from("/v/") + arg,
+        "embed" => String::from("/embed/") + arg,
+        "upload" => String::from("/upload/"),
+        "login" => String::from("/login/"),
+        "admin" => String::from("/admin/"),
+        "admin_verify" => String::from("/admin/verify"),
+        "hls" => String::from("/hls/") + arg + "/index.m3u8",
+        "history" => String::from("/history/"),
+        "history_clear" => String::from("/history/clear"),
+        "pin" => String::from("/pin/") + arg,
+        "artist" => String::from("/artist/") + &urlencoding::encode(arg),
+        "uploads" => String::from("/uploads/") + arg,
+        "static" => String::from("/static/") + arg,
+        "video_file" => String::from("/video/") + arg,
+        "download" => String::from("/download/") + arg,
+        "thumb" => String::from("/thumb/") + arg,
+        "api_video" => String::from("/api/video/") + arg,
+        "api_videos" => String::from("/api/videos"),
+        "api_position" => String::from("/api/position/") + arg,
+        "feed" => String::from("/feed.xml"),
+        "favicon" => String::from("/favicon.ico"),
+        "logo" => String::from("/logo"),
+        _ => String::from("/"),
+    }
+}
+
+/// The absolute URL of the root of the site, without the trailing
+/// slash. Prepend this to the paths from `urlFor` to make absolute
+/// URLs.
+pub fn siteURL(config: &Configuration) -> String
+{
+    config.site_info.url_domain.trim_end_matches('/').to_owned()
+        + &pathPrefix(&config.serve_under_path)
+}
+
+/// The absolute URL of the feed, which is also the topic of WebSub.
+pub fn feedURL(config: &Configuration) -> String
+{
+    siteURL(config) + &urlFor("feed", "")
+}
+
+/// The prefix to prepend to the paths from `urlFor`.
+pub fn pathPrefix(serve_path: &str) -> String
+{
+    if serve_path.is_empty() || serve_path == "/"
+    {
+        String::new()
+    }
+    else if serve_path.starts_with("/")
+    {
+        serve_path.to_owned()
+    }
+    else
+    {
+        String::from("/") + serve_path
+    }
+}
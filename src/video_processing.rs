@@ -5,33 +5,285 @@ use std::io::BufWriter;
 use std::fs::File;
 use std::ffi::OsStr;
 use std::process::Command;
-use std::str;
+use std::time::Instant;
 
 use futures_util::StreamExt;
 use bytes::buf::Buf;
-use log::debug;
+use log::{debug, info, warn};
 use log::error as log_error;
 use time::OffsetDateTime;
 use warp::http::status::StatusCode;
 use sha2::Digest;
 use regex::Regex;
+use serde::Serialize;
 
 use crate::data;
 use crate::error::Error;
-use crate::video::{Video, ContainerType};
+use crate::video::{Video, Chapter, ContainerType, ThumbnailFormat,
+                   Visibility};
 use crate::config::Configuration;
+use crate::storage::{Storage, keyFromPath};
+use crate::progress::ProgressHandle;
+
+/// An ffmpeg command, with the thread limits in the config. The
+/// “-threads” here applies to decoding the first input; encoders get
+/// their own “-threads” from `encoderThreadArgs`.
+fn ffmpeg(config: &Configuration) -> Command
+{
+    let mut command = Command::new("ffmpeg");
+    if config.ffmpeg_threads > 0
+    {
+        let threads = config.ffmpeg_threads.to_string();
+        command.args(["-filter_threads", &threads, "-threads", &threads]);
+    }
+    command
+}
+
+/// The output option that limits the threads of the encoders.
+fn encoderThreadArgs(config: &Configuration) -> Vec<String>
+{
+    if config.ffmpeg_threads > 0
+    {
+        vec![String::from("-threads"), config.ffmpeg_threads.to_string()]
+    }
+    else
+    {
+        Vec::new()
+    }
+}
 
 pub fn videoPath(video: &Video, config: &Configuration) -> PathBuf
 {
     Path::new(&config.video_dir).join(&video.path)
 }
 
+/// Relative path of the thumbnail of the video at the relative path
+/// “video_path”. Thumbnails are always named by this.
+pub fn thumbnailPath(video_path: &Path, config: &Configuration) -> PathBuf
+{
+    video_path.with_extension(config.thumbnailFormat().toExtension())
+}
+
+/// Like `thumbnailPath`, but for the sprite sheet of scrubbing
+/// previews.
+pub fn spritePath(video_path: &Path, config: &Configuration) -> PathBuf
+{
+    video_path.with_extension(
+        format!("sprite.{}", config.thumbnailFormat().toExtension()))
+}
+
+/// Path of the thumbnail of “video”, accessible from the CWD.
 pub fn expectedThumbnailPath(video: &Video, config: &Configuration) -> PathBuf
 {
-    Path::new(&config.video_dir).join(&video.path).with_extension("webp")
+    Path::new(&config.video_dir).join(thumbnailPath(&video.path, config))
+}
+
+/// The ffmpeg arguments to encode an image in the thumbnail format,
+/// with the configured quality.
+fn imageEncoderArgs(config: &Configuration) -> Vec<String>
+{
+    let quality = config.thumbnail_quality.min(100) as u32;
+    match config.thumbnailFormat()
+    {
+        ThumbnailFormat::WebP =>
+            vec!["-c:v".into(), "libwebp".into(), "-q:v".into(),
+                 quality.to_string()],
+        // For MJPEG, -q:v ranges from 2 (best) to 31 (worst).
+        ThumbnailFormat::Jpeg =>
+            vec!["-c:v".into(), "mjpeg".into(), "-q:v".into(),
+                 (31 - quality * 29 / 100).to_string()],
+        // For AV1, -crf ranges from 0 (best) to 63 (worst).
+        ThumbnailFormat::Avif =>
+            vec!["-c:v".into(), "libaom-av1".into(), "-still-picture".into(),
+                 "1".into(), "-crf".into(), (63 - quality * 63 / 100).to_string()],
+    }
+}
+
+/// Directory of the HLS playlist and segments of the video with ID
+/// “id”, accessible from the CWD.
+pub fn hlsDir(id: &str, config: &Configuration) -> PathBuf
+{
+    Path::new(&config.video_dir).join("hls").join(id)
+}
+
+/// Package a video into an HLS playlist “index.m3u8” and fMP4
+/// segments in `hlsDir`, without re-encoding. The files are written
+/// into a temp dir and moved into place at the end, so that a
+/// partially packaged video is never served. Nothing is done if the
+/// video is already packaged.
+pub fn packageHLS(video: &Video, config: &Configuration) -> Result<(), Error>
+{
+    let dir = hlsDir(&video.id, config);
+    if dir.exists()
+    {
+        return Ok(());
+    }
+    let parent = dir.parent().unwrap();
+    std::fs::create_dir_all(parent).map_err(
+        |e| rterr!("Failed to create {:?}: {}", parent, e))?;
+    let temp_dir = randomTempFilename(parent);
+    std::fs::create_dir(&temp_dir).map_err(
+        |e| rterr!("Failed to create {:?}: {}", temp_dir, e))?;
+    let status = ffmpeg(config)
+        .args(["-y", "-i", videoPath(video, config).to_str().unwrap(),
+               "-c", "copy", "-f", "hls", "-hls_time", "6",
+               "-hls_playlist_type", "vod", "-hls_segment_type", "fmp4",
+               "-hls_fmp4_init_filename", "init.mp4", "-hls_segment_filename",
+               temp_dir.join("seg%05d.m4s").to_str().unwrap(),
+               temp_dir.join("index.m3u8").to_str().unwrap()])
+        .stderr(std::process::Stdio::null())
+        .status();
+    if !status.map(|s| s.success()).unwrap_or(false)
+    {
+        std::fs::remove_dir_all(&temp_dir).ok();
+        return Err(rterr!("Failed to package video {} into HLS", video.id));
+    }
+    // Another request may have packaged the video in the meantime.
+    if std::fs::rename(&temp_dir, &dir).is_err()
+    {
+        std::fs::remove_dir_all(&temp_dir).ok();
+        if !dir.exists()
+        {
+            return Err(rterr!("Failed to move HLS files into {:?}", dir));
+        }
+    }
+    Ok(())
+}
+
+/// Remove the HLS package of the video with ID “id”, if any. It is
+/// packaged again on the next request. The package is only a cache
+/// of the video file, so it is removed whenever the file changes or
+/// goes away.
+pub fn removeHLS(id: &str, config: &Configuration)
+{
+    let dir = hlsDir(id, config);
+    if dir.exists()
+    {
+        if let Err(e) = std::fs::remove_dir_all(&dir)
+        {
+            warn!("Failed to remove HLS files in {:?}: {}", dir, e);
+        }
+    }
+}
+
+/// Total size in bytes of all files under “dir”, recursively.
+pub fn directorySize<P: AsRef<Path>>(dir: P) -> Result<u64, Error>
+{
+    let mut total = 0;
+    let entries = std::fs::read_dir(dir.as_ref()).map_err(
+        |e| rterr!("Failed to read directory {:?}: {}", dir.as_ref(), e))?;
+    for entry in entries
+    {
+        let entry = entry.map_err(
+            |e| rterr!("Failed to read directory {:?}: {}", dir.as_ref(), e))?;
+        let meta = entry.metadata().map_err(
+            |e| rterr!("Failed to get metadata of {:?}: {}", entry.path(), e))?;
+        if meta.is_dir()
+        {
+            total += directorySize(entry.path())?;
+        }
+        else
+        {
+            total += meta.len();
+        }
+    }
+    Ok(total)
 }
 
-fn randomTempFilename<P: AsRef<Path>>(dir: P) -> PathBuf
+/// Make a video ID from the hash of the video content.
+pub fn hashToID(hash: &[u8], config: &Configuration) -> String
+{
+    let id_len = config.id_length_bytes.min(hash.len());
+    let byte_strs: Vec<_> = hash[..id_len].iter()
+        .map(|b| format!("{:02x}", b)).collect();
+    byte_strs.join("")
+}
+
+/// Calculate the SHA-256 hash of the content of an existing file.
+pub fn sha256File(path: &Path) -> Result<Vec<u8>, Error>
+{
+    let mut f = File::open(path).map_err(
+        |e| rterr!("Failed to open {:?}: {}", path, e))?;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut f, &mut hasher).map_err(
+        |e| rterr!("Failed to read {:?}: {}", path, e))?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Calculate the video ID of an existing file from its content.
+pub fn hashFile(path: &Path, config: &Configuration) -> Result<String, Error>
+{
+    Ok(hashToID(&sha256File(path)?, config))
+}
+
+/// Append the bytes from “stream” to the end of the file at “path”,
+/// and return the new size of the file. If the file would grow
+/// larger than “max_size”, nothing is appended from the offending
+/// chunk on and an error is returned. If the stream fails half way,
+/// the bytes received so far are kept in the file.
+pub async fn appendStreamToFile<S, B>(path: &Path, stream: S, max_size: u64) ->
+    Result<u64, Error>
+where
+    S: futures_util::Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let f = std::fs::OpenOptions::new().append(true).open(path).map_err(
+        |e| rterr!("Failed to open {:?}: {}", path, e))?;
+    let mut size = f.metadata().map_err(
+        |e| rterr!("Failed to get metadata of {:?}: {}", path, e))?.len();
+    let mut f = BufWriter::new(f);
+    futures_util::pin_mut!(stream);
+    while let Some(buffer) = stream.next().await
+    {
+        let mut buffer = buffer.map_err(
+            |e| rterr!("Failed to receive upload data: {}", e))?;
+        if size + buffer.remaining() as u64 > max_size
+        {
+            f.flush().map_err(|e| rterr!("Failed to write {:?}: {}", path, e))?;
+            return Err(Error::HTTPStatus(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                String::from("Data exceeds the upload length")));
+        }
+        while buffer.has_remaining()
+        {
+            let bytes = buffer.chunk();
+            f.write_all(bytes).map_err(
+                |e| rterr!("Failed to write {:?}: {}", path, e))?;
+            size += bytes.len() as u64;
+            let len = bytes.len();
+            buffer.advance(len);
+        }
+    }
+    f.flush().map_err(|e| rterr!("Failed to write {:?}: {}", path, e))?;
+    Ok(size)
+}
+
+/// Rename “from” to “to”, unless “to” already exists, in which case
+/// the error is `AlreadyExists` and neither file is touched. Linking
+/// fails atomically if “to” exists, unlike renaming, which replaces
+/// it.
+fn renameNoClobber(from: &Path, to: &Path) -> std::io::Result<()>
+{
+    match std::fs::hard_link(from, to)
+    {
+        Ok(()) => std::fs::remove_file(from),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(e),
+        // Some file systems do not support hard links.
+        Err(_) if to.exists() => Err(std::io::ErrorKind::AlreadyExists.into()),
+        Err(_) => std::fs::rename(from, to),
+    }
+}
+
+/// The error of adding the file of video “id”, when a file with the
+/// same name is already in the video directory.
+fn fileExistsError(id: &str, path: &Path) -> Error
+{
+    warn!("Video file {:?} already exists. It is kept.", path);
+    Error::HTTPStatus(StatusCode::CONFLICT,
+                      format!("Video {} already exists", id))
+}
+
+pub fn randomTempFilename<P: AsRef<Path>>(dir: P) -> PathBuf
 {
     loop
     {
@@ -44,7 +296,7 @@ fn randomTempFilename<P: AsRef<Path>>(dir: P) -> PathBuf
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct ProbedMetadataSection
 {
     pub name: String,
@@ -59,12 +311,22 @@ impl ProbedMetadataSection
     }
 }
 
-fn parseProbeOutput(output: &str) -> Result<Vec<ProbedMetadataSection>, Error>
+/// Parse the output of ffprobe. Sections are returned in the order
+/// they begin in the output. A section nested in another one (such
+/// as a SIDE_DATA section in a STREAM) is returned as a separate
+/// section right after its parent, and does not affect the metadata
+/// of the parent. Ffprobe passes through the bytes of tags as is,
+/// which are not necessarily valid UTF-8. Invalid bytes are replaced
+/// by U+FFFD.
+fn parseProbeOutput(output: &[u8]) -> Result<Vec<ProbedMetadataSection>, Error>
 {
+    let output = String::from_utf8_lossy(output);
     let sec_begin_pattern = Regex::new(r"^\[([^/]+)\]$").unwrap();
     let sec_end_pattern = Regex::new(r"^\[/([^/]+)\]$").unwrap();
-    let mut result = Vec::new();
-    let mut current_section = ProbedMetadataSection::new();
+    let mut result: Vec<ProbedMetadataSection> = Vec::new();
+    // Indices into “result” of the sections that are currently open,
+    // innermost last.
+    let mut open_sections: Vec<usize> = Vec::new();
     for line in output.lines()
     {
         if line.is_empty()
@@ -73,36 +335,46 @@ fn parseProbeOutput(output: &str) -> Result<Vec<ProbedMetadataSection>, Error>
         }
         if let Some(cap) = sec_begin_pattern.captures(line)
         {
-            current_section = ProbedMetadataSection::new();
-            current_section.name = cap.get(1).unwrap().as_str().to_owned();
+            let mut section = ProbedMetadataSection::new();
+            section.name = cap.get(1).unwrap().as_str().to_owned();
+            result.push(section);
+            open_sections.push(result.len() - 1);
         }
         else if let Some(cap) = sec_end_pattern.captures(line)
         {
-            if cap.get(1).unwrap().as_str() != current_section.name
+            let name = cap.get(1).unwrap().as_str();
+            let index = open_sections.pop().ok_or_else(
+                || rterr!("Unmatched section end: {}.", name))?;
+            if name != result[index].name
             {
                 return Err(rterr!("Unmatched section end: expect {}, found {}.",
-                                  current_section.name,
-                                  cap.get(1).unwrap().as_str()));
+                                  result[index].name, name));
             }
-            result.push(current_section.clone());
         }
         else
         {
+            let index = *open_sections.last().ok_or_else(
+                || rterr!("Metadata line outside of section: {}", line))?;
             let mut split = line.splitn(2, "=");
             let key = split.next().ok_or_else(
                 || rterr!("Invalid metadata line: {}", line))?;
             let value = split.next().ok_or_else(
                 || rterr!("Invalid metadata line: {}", line))?;
-            current_section.metadata.insert(key.to_owned(), value.to_owned());
+            result[index].metadata.insert(key.to_owned(), value.to_owned());
         }
     }
+    if let Some(index) = open_sections.pop()
+    {
+        return Err(rterr!("Unterminated section: {}", result[index].name));
+    }
     debug!("Metadata from probe: {:?}", result);
     Ok(result)
 }
 
-fn probeVideo(f: &Path) -> Result<Vec<ProbedMetadataSection>, Error>
+pub fn probeVideo(f: &Path) -> Result<Vec<ProbedMetadataSection>, Error>
 {
     let output = Command::new("ffprobe").arg("-show_format")
+        .arg("-show_streams").arg("-show_chapters")
         .arg(f.to_str().ok_or_else(|| rterr!("Invalid video path: {:?}", f))?)
         .output().map_err(|e| rterr!("Failed to run ffprobe: {}", e))?;
     if !output.status.success()
@@ -116,7 +388,7 @@ fn probeVideo(f: &Path) -> Result<Vec<ProbedMetadataSection>, Error>
             return Err(rterr!("Ffprobe terminated with signal."));
         }
     }
-    parseProbeOutput(unsafe { str::from_utf8_unchecked(&output.stdout) })
+    parseProbeOutput(&output.stdout)
 }
 
 fn fillProbedMetadata(mut video: Video, metadata: Vec<ProbedMetadataSection>) ->
@@ -137,17 +409,29 @@ fn fillProbedMetadata(mut video: Video, metadata: Vec<ProbedMetadataSection>) ->
                 return Err(rterr!("format_name not found"));
             }
 
-            if let Some(value) = section.metadata.get("duration")
+            // Some containers and streams do not report a duration,
+            // or report “N/A”. Such videos are still accepted, with
+            // a duration of zero.
+            match section.metadata.get("duration")
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|d| d.is_finite() && *d >= 0.0)
             {
-                video.duration = time::Duration::seconds_f64(
-                    value.parse().map_err(
-                        |_| rterr!("Invalid duration: {}", value))?);
-            }
-            else
-            {
-                return Err(rterr!("Duration not found"));
+                Some(duration) =>
+                    video.duration = time::Duration::seconds_f64(duration),
+                None =>
+                {
+                    warn!("Duration of video {} is unknown.", video.id);
+                    video.duration = time::Duration::ZERO;
+                },
             }
 
+            // Size and bit rate are informational. Missing or
+            // invalid values (such as “N/A”) are simply not recorded.
+            video.file_size = section.metadata.get("size")
+                .and_then(|v| v.parse().ok());
+            video.bitrate = section.metadata.get("bit_rate")
+                .and_then(|v| v.parse().ok());
+
             // Get title from possible tags.
             if let Some(value) = section.metadata.get("TAG:title")
             {
@@ -186,14 +470,399 @@ fn fillProbedMetadata(mut video: Video, metadata: Vec<ProbedMetadataSection>) ->
                 video.artist = value.clone();
             }
         }
+        else if section.name == "CHAPTER"
+        {
+            // Chapters without a valid start are skipped.
+            if let Some(start) = section.metadata.get("start_time")
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|t| t.is_finite() && *t >= 0.0)
+            {
+                video.chapters.push(Chapter {
+                    start: time::Duration::seconds_f64(start),
+                    title: section.metadata.get("TAG:title")
+                        .or_else(|| section.metadata.get("TAG:TITLE"))
+                        .cloned().unwrap_or_default(),
+                });
+            }
+        }
     }
+    video.chapters.sort_by_key(|c| c.start);
     Ok(video)
 }
 
+/// Whether the probed video should be transcoded before it is
+/// served.
+fn needsTranscode(metadata: &[ProbedMetadataSection], config: &Configuration) ->
+    bool
+{
+    config.transcode_on_upload && !isWebCompatible(metadata)
+}
+
+/// Transcode the video at “path”, relative to the video directory,
+/// into the container of “transcode_container”. The original file is
+/// replaced by the result. Return the relative path of the result.
+fn transcodeFile(path: &Path, config: &Configuration) -> Result<PathBuf, Error>
+{
+    let container = config.transcodeContainer();
+    let ext = container.toExtension();
+    let input = Path::new(&config.video_dir).join(path);
+    let temp_output = input.with_extension(format!("transcoding.{}", ext));
+    let output = input.with_extension(ext);
+    debug!("Transcoding video {:?} --> {:?}...", input, output);
+    if let Err(e) = transcodeVideo(&input, &temp_output, container, config)
+    {
+        std::fs::remove_file(&temp_output).ok();
+        return Err(e);
+    }
+    if let Err(e) = std::fs::rename(&temp_output, &output)
+    {
+        std::fs::remove_file(&temp_output).ok();
+        return Err(rterr!("Failed to rename transcoded file: {}", e));
+    }
+    if output != input
+    {
+        std::fs::remove_file(&input).ok();
+    }
+    Ok(path.with_extension(ext))
+}
+
+/// Whether browsers are able to play the probed video without
+/// transcoding. Only the container, and the codecs of the video and
+/// audio streams are checked.
+fn isWebCompatible(metadata: &[ProbedMetadataSection]) -> bool
+{
+    let container = metadata.iter().find(|s| s.name == "FORMAT")
+        .and_then(|s| s.metadata.get("format_name"))
+        .and_then(|name| ContainerType::fromFormatName(name));
+    let container = match container
+    {
+        Some(c) => c,
+        None => return false,
+    };
+    metadata.iter().filter(|s| s.name == "STREAM").all(|s| {
+        // Embedded cover art is not played, so it doesn’t matter.
+        if s.metadata.get("DISPOSITION:attached_pic")
+            .is_some_and(|v| v == "1")
+        {
+            return true;
+        }
+        let codec = s.metadata.get("codec_name").map(|c| c.as_str())
+            .unwrap_or("");
+        match (s.metadata.get("codec_type").map(|t| t.as_str()), container)
+        {
+            (Some("video"), ContainerType::Mp4) => codec == "h264",
+            (Some("video"), ContainerType::WebM) =>
+                codec == "vp8" || codec == "vp9",
+            (Some("audio"), ContainerType::Mp4) =>
+                codec == "aac" || codec == "mp3",
+            (Some("audio"), ContainerType::WebM) =>
+                codec == "vorbis" || codec == "opus",
+            _ => true,
+        }
+    })
+}
+
+/// Index of the stream of the embedded cover art, if any.
+fn coverArtStream(metadata: &[ProbedMetadataSection]) -> Option<u32>
+{
+    metadata.iter().filter(|s| s.name == "STREAM")
+        .find(|s| s.metadata.get("DISPOSITION:attached_pic")
+              .is_some_and(|v| v == "1"))
+        .and_then(|s| s.metadata.get("index"))
+        .and_then(|i| i.parse().ok())
+}
+
+/// The ffmpeg arguments of the input video and of seeking to “time”
+/// seconds in it. With “-ss” before “-i”, ffmpeg seeks the input by
+/// key frames, which is fast; after it, ffmpeg decodes every frame up
+/// to “time”, which is accurate.
+fn thumbnailSeekArgs(video_path: &Path, time: f64, config: &Configuration) ->
+    Vec<String>
+{
+    let input = [String::from("-i"), video_path.to_str().unwrap().to_owned()];
+    let seek = [String::from("-ss"), time.to_string()];
+    if config.fast_thumbnail_seek
+    {
+        seek.into_iter().chain(input).collect()
+    }
+    else
+    {
+        input.into_iter().chain(seek).collect()
+    }
+}
+
+/// Write the cover art embedded in the video at “video_path” to
+/// “output” as a thumbnail. Return false if there is no cover art,
+/// or it cannot be extracted.
+fn extractCoverArt(video_path: &Path, output: &Path, config: &Configuration) ->
+    bool
+{
+    let index = match probeVideo(video_path).ok()
+        .and_then(|metadata| coverArtStream(&metadata))
+    {
+        Some(i) => i,
+        None => return false,
+    };
+    debug!("Using cover art in stream {} of {:?}...", index, video_path);
+    let status = ffmpeg(config)
+        .args(["-y", "-i", video_path.to_str().unwrap(), "-map",
+               &format!("0:{}", index), "-frames:v", "1", "-vf",
+               THUMBNAIL_SCALE_FILTER])
+        .args(imageEncoderArgs(config))
+        .arg(output)
+        .stderr(std::process::Stdio::null())
+        .status();
+    if status.is_ok_and(|s| s.success())
+    {
+        true
+    }
+    else
+    {
+        std::fs::remove_file(output).ok();
+        false
+    }
+}
+
+/// The ffmpeg filter to scale thumbnails, so that the longer side is
+/// at most 512 pixels.
+const THUMBNAIL_SCALE_FILTER: &str =
+    r#"scale=if(gte(iw\,ih)\,min(512\,iw)\,-2):if(lt(iw\,ih)\,min(512\,ih)\,-2)"#;
+
+/// Maximal number of tiles in a sprite sheet of scrubbing previews.
+const SPRITE_TILES_MAX: u64 = 1000;
+
+/// Maximal width and height of a WebP image.
+const WEBP_SIZE_MAX: u32 = 16383;
+
+/// Maximal number of tiles in a sprite sheet with the tile size and
+/// the columns in the config, so that its height fits in a WebP
+/// image. The config makes sure a single row fits.
+fn spriteTilesMax(config: &Configuration) -> u64
+{
+    let rows = (WEBP_SIZE_MAX / config.scrub_tile_height) as u64;
+    SPRITE_TILES_MAX.min(rows * config.scrub_columns as u64)
+}
+
+/// Write the WebVTT file that maps the time spans of a video to the
+/// tiles in its sprite sheet, which is at “sprite_url” relative to
+/// the VTT file. There are “count” tiles, each “interval” seconds
+/// apart.
+fn makeSpriteVTT(sprite_url: &str, count: u64, interval: f64, duration: f64,
+                 config: &Configuration) -> String
+{
+    fn timestamp(t: f64) -> String
+    {
+        let ms = (t * 1000.0).round() as u64;
+        format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60,
+                ms / 1000 % 60, ms % 1000)
+    }
+
+    let (w, h) = (config.scrub_tile_width, config.scrub_tile_height);
+    let columns = config.scrub_columns as u64;
+    let mut vtt = String::from("WEBVTT\n");
+    for i in 0..count
+    {
+        let start = i as f64 * interval;
+        let end = (start + interval).min(duration);
+        vtt += &format!("\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                        timestamp(start), timestamp(end), sprite_url,
+                        (i % columns) as u32 * w, (i / columns) as u32 * h,
+                        w, h);
+    }
+    vtt
+}
+
+/// Number of frames sampled from a short video to pick its
+/// thumbnail.
+const THUMBNAIL_CANDIDATES: f64 = 8.0;
+
+/// From the output of ffmpeg’s `signalstats` and `metadata=print`
+/// filters, find the time of the frame with the widest range of
+/// luminance. Black and faded frames have a narrow range.
+fn pickThumbnailTime(output: &str) -> Option<f64>
+{
+    let mut best: Option<(f64, f64)> = None;
+    let mut time = None;
+    let mut low = None;
+    for line in output.lines()
+    {
+        // Log lines are prefixed with the name of the filter.
+        let line = match line.find("] ")
+        {
+            Some(i) => &line[i + 2..],
+            None => line,
+        };
+        if let Some(i) = line.find("pts_time:")
+        {
+            time = line[i + 9..].trim().parse::<f64>().ok();
+            low = None;
+        }
+        else if let Some(value) = line.strip_prefix("lavfi.signalstats.YLOW=")
+        {
+            low = value.trim().parse::<f64>().ok();
+        }
+        else if let Some(value) = line.strip_prefix(
+            "lavfi.signalstats.YHIGH=")
+        {
+            if let (Some(t), Some(l), Ok(h)) =
+                (time, low, value.trim().parse::<f64>())
+            {
+                if best.is_none_or(|(_, score)| h - l > score)
+                {
+                    best = Some((t, h - l));
+                }
+            }
+        }
+    }
+    best.map(|(t, _)| t)
+}
+
+/// Sample some frames evenly from a video, and return the time of
+/// the one that looks the least blank. Return None if the analysis
+/// fails.
+fn analyzeThumbnailTime(video_path: &Path, duration: time::Duration,
+                        config: &Configuration) -> Option<f64>
+{
+    let duration = duration.as_seconds_f64();
+    if duration <= 0.0
+    {
+        return None;
+    }
+    let filter = format!("fps={},signalstats,metadata=mode=print",
+                         THUMBNAIL_CANDIDATES / duration);
+    let output = ffmpeg(config)
+        .args(["-hide_banner", "-nostats", "-i", video_path.to_str()?,
+               "-an", "-vf", &filter, "-f", "null", "-"])
+        .stdout(std::process::Stdio::null())
+        .output().ok()?;
+    if !output.status.success()
+    {
+        return None;
+    }
+    pickThumbnailTime(&String::from_utf8_lossy(&output.stderr))
+}
+
+/// Whether the probed file is a still image.
+fn isImage(metadata: &[ProbedMetadataSection]) -> bool
+{
+    metadata.iter().find(|s| s.name == "FORMAT")
+        .and_then(|s| s.metadata.get("format_name"))
+        .is_some_and(|name| name.ends_with("_pipe") || name == "image2")
+}
+
+/// Convert the image at `input` into a thumbnail at `output`,
+/// scaled in the same way as generated thumbnails. Fail if `input`
+/// is not an image.
+pub fn convertThumbnail(input: &Path, output: &Path, config: &Configuration) ->
+    Result<(), Error>
+{
+    if !isImage(&probeVideo(input)?)
+    {
+        return Err(Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                     String::from("Thumbnail is not an image")));
+    }
+    let status = ffmpeg(config)
+        .args(["-y", "-i", input.to_str().unwrap(), "-frames:v", "1", "-vf",
+               THUMBNAIL_SCALE_FILTER])
+        .args(imageEncoderArgs(config))
+        .arg(output)
+        .stderr(std::process::Stdio::null())
+        .status().map_err(|e| rterr!("Failed to run ffmpeg: {}", e))?;
+    if !status.success()
+    {
+        std::fs::remove_file(output).ok();
+        return Err(rterr!("Failed to convert thumbnail {:?}", input));
+    }
+    Ok(())
+}
+
+/// Transcode the video file at `input` into `output` with codecs
+/// suitable for `container`. Only the first video stream and the
+/// audio streams are kept.
+fn transcodeVideo(input: &Path, output: &Path, container: ContainerType,
+                  config: &Configuration) -> Result<(), Error>
+{
+    let mut codec_args: Vec<&str> = match container
+    {
+        ContainerType::Mp4 => vec!["-c:v", "libx264", "-pix_fmt", "yuv420p",
+                                   "-c:a", "aac", "-movflags", "+faststart"],
+        ContainerType::WebM => vec!["-c:v", "libvpx-vp9", "-b:v", "0",
+                                    "-crf", "32", "-c:a", "libopus"],
+    };
+    if let (ContainerType::Mp4, Some(preset)) =
+        (container, &config.transcode_preset)
+    {
+        codec_args.extend(["-preset", preset]);
+    }
+    let status = ffmpeg(config)
+        .args(["-y", "-i", input.to_str().ok_or_else(
+            || rterr!("Invalid video path: {:?}", input))?,
+               "-map", "0:v:0", "-map", "0:a?"])
+        .args(codec_args)
+        .args(encoderThreadArgs(config))
+        .arg(output.to_str().ok_or_else(
+            || rterr!("Invalid video path: {:?}", output))?)
+        .stderr(std::process::Stdio::null())
+        .status().map_err(|e| rterr!("Failed to run ffmpeg: {}", e))?;
+    if !status.success()
+    {
+        return Err(rterr!("Failed to transcode {:?}.", input));
+    }
+    Ok(())
+}
+
+/// Reject the video if it is longer than allowed by the config.
+fn checkDuration(video: Video, config: &Configuration) -> Result<Video, Error>
+{
+    if let Some(max_sec) = config.max_duration_sec
+    {
+        if video.duration > time::Duration::seconds(max_sec as i64)
+        {
+            return Err(Error::HTTPStatus(
+                StatusCode::BAD_REQUEST,
+                format!("Video is longer than {} seconds", max_sec)));
+        }
+    }
+    Ok(video)
+}
+
+/// Reject the video if its container is not allowed by the config.
+fn checkContainer(video: Video, config: &Configuration) -> Result<Video, Error>
+{
+    if !config.allowed_containers.is_empty() &&
+        !config.allowed_containers.iter().any(
+            |c| ContainerType::fromExtension(c) == Some(video.container_type))
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::BAD_REQUEST,
+            format!("Container {} is not allowed",
+                    video.container_type.toExtension())));
+    }
+    Ok(video)
+}
+
+/// Information about an upload that does not come from the video
+/// file itself.
+pub struct UploadOptions
+{
+    pub visibility: Visibility,
+    /// See `Video::uploader`.
+    pub uploader: String,
+    /// Path of an uploaded image to use as the thumbnail, instead of
+    /// a generated one. The caller is responsible for removing it.
+    pub thumbnail: Option<PathBuf>,
+    /// These override the metadata probed from the video file.
+    pub title: Option<String>,
+    pub desc: Option<String>,
+    pub artist: Option<String>,
+}
+
 /// Some bytes that are being uploaded
 pub struct UploadingVideo
 {
     pub part: warp::multipart::Part,
+    /// Where to report the number of bytes received.
+    pub progress: Option<ProgressHandle>,
 }
 
 /// A video file that is just uploaded.
@@ -210,7 +879,8 @@ impl UploadingVideo
     /// This will create a temp file under the video directory. This
     /// is important, because later the video will be renamed to the
     /// correct name. We need the rename to happen in the same storage
-    /// volumn so that it can succeed.
+    /// volumn so that it can succeed. The file is removed if it grows
+    /// larger than “per_file_size_max”.
     pub async fn saveToTemp(self, config: &Configuration) ->
         Result<RawVideo, Error>
     {
@@ -228,6 +898,7 @@ impl UploadingVideo
             },
         };
         let mut hasher = sha2::Sha256::new();
+        let mut size: u64 = 0;
         let mut buffers = self.part.stream();
         while let Some(buffer) = buffers.next().await
         {
@@ -240,10 +911,26 @@ impl UploadingVideo
             }
             let mut buffer = buffer.map_err(
                 |e| rterr!("Failed to acquire buffer from form data: {}", e))?;
+            size += buffer.remaining() as u64;
+            if config.per_file_size_max.is_some_and(|max| size > max)
+            {
+                drop(f);
+                if std::fs::remove_file(&temp_file).is_err()
+                {
+                    log_error!("Failed to remove temp file at {:?}.", temp_file);
+                }
+                return Err(Error::HTTPStatus(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    String::from("Video file is too large")));
+            }
             while buffer.has_remaining()
             {
                 let bytes = buffer.chunk();
                 hasher.update(bytes);
+                if let Some(progress) = &self.progress
+                {
+                    progress.add(bytes.len() as u64);
+                }
                 if let Err(e) = f.write_all(bytes)
                 {
                     drop(f);
@@ -257,30 +944,52 @@ impl UploadingVideo
             }
         }
 
-        let hash = hasher.finalize();
-        let byte_strs: Vec<_> = hash[..6].iter().map(|b| format!("{:02x}", b))
-            .collect();
-
         Ok(RawVideo {
             path: temp_file,
-            hash: byte_strs.join(""),
+            hash: hashToID(&hasher.finalize(), config),
             original_filename: orig_name,
         })
     }
 }
 
+/// Run one stage of the upload pipeline of video “id”, logging when
+/// it starts and how long it took, so that it is easy to tell where
+/// an upload is stuck or failed.
+fn stage<T, F>(id: &str, name: &str, f: F) -> Result<T, Error>
+    where F: FnOnce() -> Result<T, Error>
+{
+    debug!("Video {}: {}...", id, name);
+    let start = Instant::now();
+    let result = f();
+    match &result
+    {
+        Ok(_) => debug!("Video {}: {} done in {:.2?}.", id, name,
+                        start.elapsed()),
+        Err(e) => debug!("Video {}: {} failed after {:.2?}: {}", id, name,
+                         start.elapsed(), e),
+    }
+    result
+}
+
 impl RawVideo
 {
+    /// Move the file to the library, named by its hash. The file
+    /// gets its extension after probing, from its container rather
+    /// than the uploaded filename. If a file of that name exists,
+    /// such as from a concurrent upload of the same video, it is a
+    /// `CONFLICT`, and that file is left alone.
     pub fn moveToLibrary(self, config: &Configuration) ->
         Result<Self, Error>
     {
-        let ext = self.path.extension().or(Some(OsStr::new(""))).unwrap();
-        let video_file: PathBuf = Path::new(&config.video_dir).join(&self.hash)
-            .with_extension(ext);
+        let video_file: PathBuf = Path::new(&config.video_dir).join(&self.hash);
         debug!("Moving video {:?} --> {:?}...", self.path, video_file);
-        if let Err(e) = std::fs::rename(&self.path, &video_file)
+        if let Err(e) = renameNoClobber(&self.path, &video_file)
         {
             std::fs::remove_file(&self.path).ok();
+            if e.kind() == std::io::ErrorKind::AlreadyExists
+            {
+                return Err(fileExistsError(&self.hash, &video_file));
+            }
             std::fs::remove_file(&video_file).ok();
             return Err(rterr!("Failed to rename temp file: {}", e));
         }
@@ -319,11 +1028,97 @@ impl RawVideo
         Ok(self)
     }
 
-    pub fn probeMetadata(self, config: &Configuration) -> Result<Video, Error>
+    /// Add this file into the library. Its metadata is probed and
+    /// checked, and the video is added to the database. What takes
+    /// longer, such as transcoding and thumbnail generation, is left
+    /// to `Video::process`. The uploaded thumbnail, if any, is used
+    /// here, because it is removed by the caller. A video that is
+    /// already in the library is a `CONFLICT`, and the existing files
+    /// are left alone.
+    pub fn admit(self, options: UploadOptions, config: &Configuration,
+                 data_manager: &data::Manager) -> Result<Video, Error>
+    {
+        if data_manager.findVideoByID(&self.hash)?.is_some()
+        {
+            std::fs::remove_file(&self.path).ok();
+            return Err(Error::HTTPStatus(
+                StatusCode::CONFLICT,
+                format!("Video {} already exists", self.hash)));
+        }
+        let id = self.hash.clone();
+        let raw = stage(&id, "moveToLibrary", || self.moveToLibrary(config))?;
+        let raw = stage(&id, "makeRelativePath",
+                        || raw.makeRelativePath(config))?;
+        let mut video = stage(&id, "probeMetadata",
+                              || raw.probeMetadata(config))?;
+        video.visibility = options.visibility;
+        video.uploader = options.uploader;
+        if let Some(title) = options.title
+        {
+            video.title = title;
+        }
+        if let Some(desc) = options.desc
+        {
+            video.desc = desc;
+        }
+        if let Some(artist) = options.artist
+        {
+            video.artist = artist;
+        }
+        if video.artist.is_empty()
+        {
+            video.artist = config.default_artist.clone();
+        }
+        // Videos of unknown duration are not considered short.
+        video.looping = video.duration > time::Duration::ZERO &&
+            config.loop_duration_max_sec.is_some_and(
+                |max| video.duration <= time::Duration::seconds(max as i64));
+        if let Some(image) = &options.thumbnail
+        {
+            if let Err(e) = video.useThumbnailImage(image, config)
+            {
+                // A thumbnail will be generated instead.
+                log_error!("Failed to use uploaded thumbnail: {}", e);
+            }
+        }
+        if let Err(e) = stage(&id, "addToDatabase",
+                              || data_manager.addVideo(&video))
+        {
+            std::fs::remove_file(videoPath(&video, config)).ok();
+            if let Some(thumbnail) = &video.thumbnail_path
+            {
+                std::fs::remove_file(Path::new(&config.video_dir)
+                                     .join(thumbnail)).ok();
+            }
+            return Err(e);
+        }
+        if !video.chapters.is_empty()
+        {
+            // The video is still usable without chapters.
+            if let Err(e) = data_manager.setChapters(&video.id, &video.chapters)
+            {
+                log_error!("Failed to add chapters of video {}: {}", id, e);
+            }
+        }
+        Ok(video)
+    }
+
+    /// Run the whole pipeline to add this file into the library.
+    pub fn ingest(self, options: UploadOptions, config: &Configuration,
+                  storage: &dyn Storage, data_manager: &data::Manager) ->
+        Result<(), Error>
+    {
+        self.admit(options, config, data_manager)?
+            .process(config, storage, data_manager)
+    }
+
+    /// Probe the metadata of the video file, which must have a
+    /// relative path at this point. The file is removed if the video
+    /// is not acceptable. The container is not checked if the video
+    /// is going to be transcoded.
+    pub fn probeMetadata(self, config: &Configuration) ->
+        Result<Video, Error>
     {
-        let mut video = Video::new(self.hash, &self.path);
-        video.original_filename = self.original_filename;
-        video.upload_time = OffsetDateTime::now_utc();
         let metadata = match probeVideo(
             &Path::new(&config.video_dir).join(&self.path))
         {
@@ -334,13 +1129,20 @@ impl RawVideo
                 return Err(e);
             },
         };
+        let transcode = needsTranscode(&metadata, config);
+        let mut video = Video::new(self.hash, &self.path);
+        video.original_filename = self.original_filename;
+        video.upload_time = OffsetDateTime::now_utc();
 
         match fillProbedMetadata(video, metadata)
+            .and_then(|v| checkDuration(v, config))
+            .and_then(|v| if transcode { Ok(v) } else { checkContainer(v, config) })
+            .and_then(|v| v.useCanonicalExtension(config))
         {
             Ok(video) => Ok(video),
             Err(e) => {
                 std::fs::remove_file(
-                    &Path::new(&config.video_dir).join(&self.path)).ok();
+                    Path::new(&config.video_dir).join(&self.path)).ok();
                 Err(e)
             }
         }
@@ -348,29 +1150,68 @@ impl RawVideo
 }
 impl Video
 {
+    /// Rename the video file so that its extension is the canonical
+    /// one of its container. An existing file of the new name is a
+    /// `CONFLICT`, like in `RawVideo::moveToLibrary`.
+    fn useCanonicalExtension(mut self, config: &Configuration) ->
+        Result<Video, Error>
+    {
+        let path = self.path.with_extension(self.container_type.toExtension());
+        if path != self.path
+        {
+            let to = Path::new(&config.video_dir).join(&path);
+            debug!("Renaming video {:?} --> {:?}...", self.path, to);
+            renameNoClobber(&videoPath(&self, config), &to).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::AlreadyExists
+                {
+                    fileExistsError(&self.id, &to)
+                }
+                else
+                {
+                    rterr!("Failed to rename video {:?}: {}", self.path, e)
+                }
+            })?;
+            self.path = path;
+        }
+        Ok(self)
+    }
+
     /// Thumbnail generation shouldn’t usually fail. This function
     /// should almost always return Ok(), unless something panicking
     /// happend.
     pub fn generateThumbnail(mut self, config: &Configuration) ->
         Result<Video, Error>
     {
-        let thumb_time_sec = if self.duration > time::Duration::seconds(30)
+        let video_path = videoPath(&self, config);
+        let thumbnail_path = expectedThumbnailPath(&self, config);
+        // Embedded cover art is preferred over a frame.
+        if extractCoverArt(&video_path, &thumbnail_path, config)
+        {
+            self.thumbnail_path = Some(thumbnailPath(&self.path, config));
+            self.thumbnail_quality = Some(config.thumbnail_quality);
+            return Ok(self);
+        }
+        // Short videos often start with a black or fading frame, so
+        // the frame is picked by looking at them.
+        let thumb_time_sec = if self.duration.is_zero()
+        {
+            // The duration is unknown, so take the first frame.
+            0.0
+        }
+        else if self.duration > time::Duration::seconds(30)
         {
             10.0
         }
         else
         {
-            self.duration.as_seconds_f64() / 3.0
+            analyzeThumbnailTime(&video_path, self.duration, config)
+                .unwrap_or_else(|| self.duration.as_seconds_f64() / 3.0)
         };
-        let video_path = videoPath(&self, config);
-        let thumbnail_path = expectedThumbnailPath(&self, config);
-        let status = Command::new("ffmpeg")
-            .args(["-y", "-i", video_path.to_str().unwrap(), "-ss",
-                   &thumb_time_sec.to_string(), "-frames:v", "1", "-vf",
-                   r#"scale=if(gte(iw\,ih)\,min(512\,iw)\,-2):if(lt(iw\,ih)\,min(512\,ih)\,-2)"#,
-                   "-c:v", "libwebp", "-q:v",
-                   &config.thumbnail_quality.to_string(),
-                   thumbnail_path.to_str().unwrap()])
+        let status = ffmpeg(config).arg("-y")
+            .args(thumbnailSeekArgs(&video_path, thumb_time_sec, config))
+            .args(["-frames:v", "1", "-vf", THUMBNAIL_SCALE_FILTER])
+            .args(imageEncoderArgs(config))
+            .arg(&thumbnail_path)
             .stderr(std::process::Stdio::null())
             .status();
         if status.is_err()
@@ -379,21 +1220,200 @@ impl Video
         }
         if status.unwrap().success()
         {
-            self.thumbnail_path = Some(self.path.with_extension("webp"));
+            self.thumbnail_path = Some(thumbnailPath(&self.path, config));
+            self.thumbnail_quality = Some(config.thumbnail_quality);
         }
         Ok(self)
     }
 
-    pub fn addToDatabase(self, config: &Configuration,
-                         data_manager: &data::Manager) -> Result<(), Error>
+    /// Generate the sprite sheet and the WebVTT file of scrubbing
+    /// previews, if it is enabled in the config. Like thumbnails,
+    /// failing to generate them is not an error.
+    pub fn generateScrubThumbnails(mut self, config: &Configuration) ->
+        Result<Video, Error>
     {
-        if let Err(e) = data_manager.addVideo(&self)
+        let duration = self.duration.as_seconds_f64();
+        if !config.scrub_thumbnails || duration <= 0.0
+        {
+            return Ok(self);
+        }
+        let interval = (config.scrub_interval_sec as f64)
+            .max(duration / spriteTilesMax(config) as f64);
+        let count = (duration / interval).ceil().max(1.0) as u64;
+        let columns = (config.scrub_columns as u64).min(count);
+        let rows = count.div_ceil(columns);
+        let (w, h) = (config.scrub_tile_width, config.scrub_tile_height);
+        let filter = format!(
+            "fps=1/{},scale={w}:{h}:force_original_aspect_ratio=decrease,\
+             pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,tile={}x{}", interval, columns,
+            rows);
+        let sprite_path = spritePath(&self.path, config);
+        let vtt_path = self.path.with_extension("sprite.vtt");
+        let sprite_file = Path::new(&config.video_dir).join(&sprite_path);
+        let status = ffmpeg(config)
+            .args(["-y", "-i", videoPath(&self, config).to_str().unwrap(),
+                   "-an", "-vf", &filter, "-frames:v", "1"])
+            .args(imageEncoderArgs(config))
+            .arg(&sprite_file)
+            .stderr(std::process::Stdio::null())
+            .status();
+        if !status.map(|s| s.success()).unwrap_or(false)
         {
-            std::fs::remove_file(&videoPath(&self, config)).ok();
-            return Err(e)
+            log_error!("Failed to generate scrubbing previews of {}", self.id);
+            std::fs::remove_file(&sprite_file).ok();
+            return Ok(self);
         }
+        let sprite_name = sprite_path.file_name().unwrap().to_string_lossy();
+        let vtt = makeSpriteVTT(&sprite_name, count, interval, duration,
+                                config);
+        if let Err(e) = std::fs::write(
+            Path::new(&config.video_dir).join(&vtt_path), vtt)
+        {
+            log_error!("Failed to write {:?}: {}", vtt_path, e);
+            std::fs::remove_file(&sprite_file).ok();
+            return Ok(self);
+        }
+        self.sprite_path = Some(sprite_path);
+        self.sprite_vtt_path = Some(vtt_path);
+        Ok(self)
+    }
+
+    /// Use the image at `image` as the thumbnail, instead of
+    /// generating one from the video.
+    pub fn useThumbnailImage(&mut self, image: &Path, config: &Configuration) ->
+        Result<(), Error>
+    {
+        convertThumbnail(image, &expectedThumbnailPath(self, config), config)?;
+        self.thumbnail_path = Some(thumbnailPath(&self.path, config));
+        // An uploaded thumbnail cannot be generated again.
+        self.thumbnail_quality = None;
         Ok(())
     }
+
+    /// Probe the metadata of a video that is already in the
+    /// library, and update the fields of the video from it. Unlike
+    /// `RawVideo::probeMetadata`, the video file is kept when
+    /// probing fails.
+    pub fn reprobeMetadata(self, config: &Configuration) ->
+        Result<Video, Error>
+    {
+        let metadata = probeVideo(&videoPath(&self, config))?;
+        fillProbedMetadata(self, metadata)
+    }
+
+    /// Put the video file and its thumbnail and scrubbing previews,
+    /// which are in the video directory at this point, into the
+    /// storage. If the video file cannot be stored, all are removed.
+    pub fn store(mut self, config: &Configuration, storage: &dyn Storage) ->
+        Result<Video, Error>
+    {
+        let thumbnail_file = self.thumbnail_path.as_ref()
+            .map(|p| Path::new(&config.video_dir).join(p));
+        let sprite_files: Vec<PathBuf> = self.sprite_path.iter()
+            .chain(self.sprite_vtt_path.iter())
+            .map(|p| Path::new(&config.video_dir).join(p)).collect();
+        if let Err(e) = keyFromPath(&self.path)
+            .and_then(|key| storage.put(&key, &videoPath(&self, config)))
+        {
+            std::fs::remove_file(videoPath(&self, config)).ok();
+            for f in thumbnail_file.iter().chain(sprite_files.iter())
+            {
+                std::fs::remove_file(f).ok();
+            }
+            return Err(e);
+        }
+        let sprite_result = self.sprite_path.iter()
+            .chain(self.sprite_vtt_path.iter()).zip(sprite_files.iter())
+            .try_for_each(|(p, f)| storage.put(&keyFromPath(p)?, f));
+        if let Err(e) = sprite_result
+        {
+            log_error!("Failed to store scrubbing previews: {}", e);
+            for f in &sprite_files
+            {
+                std::fs::remove_file(f).ok();
+            }
+            self.sprite_path = None;
+            self.sprite_vtt_path = None;
+        }
+        if let (Some(thumbnail), Some(f)) = (&self.thumbnail_path, thumbnail_file)
+        {
+            // The video is still usable without a thumbnail.
+            if let Err(e) = keyFromPath(thumbnail)
+                .and_then(|key| storage.put(&key, &f))
+            {
+                log_error!("Failed to store thumbnail: {}", e);
+                std::fs::remove_file(f).ok();
+                self.thumbnail_path = None;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Transcode the video if it is enabled and the video is not
+    /// web-compatible. The probed properties of the file are updated,
+    /// but the title and such are kept.
+    fn transcodeIfNeeded(&mut self, config: &Configuration) -> Result<(), Error>
+    {
+        let metadata = probeVideo(&videoPath(self, config))?;
+        if !needsTranscode(&metadata, config)
+        {
+            return Ok(());
+        }
+        let path = transcodeFile(&self.path, config)?;
+        let probed = fillProbedMetadata(
+            Video::new(self.id.clone(), &path),
+            probeVideo(&Path::new(&config.video_dir).join(&path))?)?;
+        self.path = path;
+        self.container_type = probed.container_type;
+        self.duration = probed.duration;
+        self.file_size = probed.file_size;
+        self.bitrate = probed.bitrate;
+        removeHLS(&self.id, config);
+        Ok(())
+    }
+
+    /// Finish adding a video that is admitted by `RawVideo::admit`:
+    /// transcode it if needed, generate the thumbnails, put the files
+    /// into the storage, and update the video in the database. If the
+    /// video file cannot be stored, the video is removed.
+    pub fn process(mut self, config: &Configuration, storage: &dyn Storage,
+                   data_manager: &data::Manager) -> Result<(), Error>
+    {
+        let id = self.id.clone();
+        let start = Instant::now();
+        if let Err(e) = stage(&id, "transcode",
+                              || self.transcodeIfNeeded(config))
+        {
+            // The original video is kept.
+            log_error!("Failed to transcode video {}: {}", id, e);
+        }
+        let video = if self.thumbnail_path.is_none()
+        {
+            stage(&id, "generateThumbnail", || self.generateThumbnail(config))?
+        }
+        else
+        {
+            self
+        };
+        let video = stage(&id, "generateScrubThumbnails",
+                          || video.generateScrubThumbnails(config))?;
+        match stage(&id, "store", || video.store(config, storage))
+        {
+            Ok(video) =>
+            {
+                stage(&id, "updateDatabase",
+                      || data_manager.updateProcessedVideo(&video))?;
+                info!("Added video {} ({:?}), processed in {:.2?}.", id,
+                      video.original_filename, start.elapsed());
+                Ok(())
+            },
+            Err(e) =>
+            {
+                data_manager.deleteVideos(&[id]).ok();
+                Err(e)
+            },
+        }
+    }
 }
 
 #[cfg(test)]
@@ -431,6 +1451,334 @@ mod tests
         }
     }
 
+    #[test]
+    fn testMakeSpriteVTT()
+    {
+        let config = Configuration {
+            scrub_tile_width: 100,
+            scrub_tile_height: 50,
+            scrub_columns: 2,
+            ..Default::default()
+        };
+        assert_eq!(makeSpriteVTT("a.sprite.webp", 3, 10.0, 25.5, &config),
+                   "WEBVTT\n\n\
+                    00:00:00.000 --> 00:00:10.000\n\
+                    a.sprite.webp#xywh=0,0,100,50\n\n\
+                    00:00:10.000 --> 00:00:20.000\n\
+                    a.sprite.webp#xywh=100,0,100,50\n\n\
+                    00:00:20.000 --> 00:00:25.500\n\
+                    a.sprite.webp#xywh=0,50,100,50\n");
+    }
+
+    #[test]
+    fn testSpriteTilesMax()
+    {
+        let mut config = Configuration {
+            scrub_tile_height: 90,
+            scrub_columns: 10,
+            ..Default::default()
+        };
+        assert_eq!(spriteTilesMax(&config), 1000);
+        config.scrub_tile_height = 1000;
+        assert_eq!(spriteTilesMax(&config), 160);
+    }
+
+    #[test]
+    fn testThumbnailSeekArgs()
+    {
+        let mut config = Configuration::default();
+        assert_eq!(thumbnailSeekArgs(Path::new("a.mp4"), 10.0, &config),
+                   vec!["-i", "a.mp4", "-ss", "10"]);
+        config.fast_thumbnail_seek = true;
+        assert_eq!(thumbnailSeekArgs(Path::new("a.mp4"), 1.5, &config),
+                   vec!["-ss", "1.5", "-i", "a.mp4"]);
+    }
+
+    #[test]
+    fn testPickThumbnailTime()
+    {
+        let output = "\
+[Parsed_metadata_2 @ 0x1] frame:0    pts:0       pts_time:0
+[Parsed_metadata_2 @ 0x1] lavfi.signalstats.YLOW=16
+[Parsed_metadata_2 @ 0x1] lavfi.signalstats.YHIGH=17
+[Parsed_metadata_2 @ 0x1] frame:1    pts:1       pts_time:0.25
+[Parsed_metadata_2 @ 0x1] lavfi.signalstats.YLOW=20
+[Parsed_metadata_2 @ 0x1] lavfi.signalstats.YHIGH=200
+[Parsed_metadata_2 @ 0x1] frame:2    pts:2       pts_time:0.5
+[Parsed_metadata_2 @ 0x1] lavfi.signalstats.YLOW=30
+[Parsed_metadata_2 @ 0x1] lavfi.signalstats.YHIGH=100
+";
+        assert_eq!(pickThumbnailTime(output), Some(0.25));
+        assert_eq!(pickThumbnailTime("garbage"), None);
+    }
+
+    #[test]
+    fn testParseProbeOutputInvalidUTF8() -> Result<(), Error>
+    {
+        let mut output = b"[FORMAT]\nformat_name=matroska,webm\nTAG:title=a"
+            .to_vec();
+        output.extend_from_slice(&[0xff, 0xfe]);
+        output.extend_from_slice(b"b\n[/FORMAT]\n");
+        let sections = parseProbeOutput(&output)?;
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].metadata.get("format_name").unwrap(),
+                   "matroska,webm");
+        assert_eq!(sections[0].metadata.get("TAG:title").unwrap(),
+                   "a\u{fffd}\u{fffd}b");
+        Ok(())
+    }
+
+    #[test]
+    fn testParseProbeOutputMultiStream() -> Result<(), Error>
+    {
+        let output = br#"[STREAM]
+index=0
+codec_name=h264
+codec_type=video
+width=1920
+height=1080
+DISPOSITION:default=1
+[SIDE_DATA]
+side_data_type=Display Matrix
+rotation=0
+[/SIDE_DATA]
+TAG:language=und
+[/STREAM]
+[STREAM]
+index=1
+codec_name=aac
+codec_type=audio
+channels=2
+DISPOSITION:default=1
+TAG:language=eng
+[/STREAM]
+[STREAM]
+index=2
+codec_name=opus
+codec_type=audio
+channels=6
+DISPOSITION:default=0
+TAG:language=jpn
+[/STREAM]
+[FORMAT]
+format_name=mov,mp4,m4a,3gp,3g2,mj2
+nb_streams=3
+duration=10.000000
+[/FORMAT]
+"#;
+        let sections = parseProbeOutput(output)?;
+        let names: Vec<&str> = sections.iter().map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["STREAM", "SIDE_DATA", "STREAM", "STREAM",
+                               "FORMAT"]);
+        let video = &sections[0].metadata;
+        assert_eq!(video.get("codec_name").unwrap(), "h264");
+        assert_eq!(video.get("width").unwrap(), "1920");
+        // Keys after the nested section still belong to the stream.
+        assert_eq!(video.get("TAG:language").unwrap(), "und");
+        assert!(video.get("rotation").is_none());
+        assert_eq!(sections[1].metadata.get("rotation").unwrap(), "0");
+        let audio1 = &sections[2].metadata;
+        assert_eq!(audio1.get("codec_name").unwrap(), "aac");
+        assert_eq!(audio1.get("channels").unwrap(), "2");
+        assert_eq!(audio1.get("TAG:language").unwrap(), "eng");
+        let audio2 = &sections[3].metadata;
+        assert_eq!(audio2.get("codec_name").unwrap(), "opus");
+        assert_eq!(audio2.get("channels").unwrap(), "6");
+        assert_eq!(audio2.get("TAG:language").unwrap(), "jpn");
+        assert_eq!(sections[4].metadata.get("nb_streams").unwrap(), "3");
+
+        assert!(parseProbeOutput(b"[STREAM]\nindex=0\n").is_err());
+        assert!(parseProbeOutput(b"[STREAM]\n[/FORMAT]\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn testProbedChapters() -> Result<(), Error>
+    {
+        let output = br#"[CHAPTER]
+id=1
+time_base=1/1000
+start=65000
+start_time=65.000000
+end=120000
+end_time=120.000000
+TAG:title=Second
+[/CHAPTER]
+[CHAPTER]
+id=0
+time_base=1/1000
+start=0
+start_time=0.000000
+end=65000
+end_time=65.000000
+[/CHAPTER]
+[CHAPTER]
+id=2
+start_time=N/A
+[/CHAPTER]
+[FORMAT]
+format_name=matroska,webm
+duration=120.000000
+[/FORMAT]
+"#;
+        let video = fillProbedMetadata(
+            Video::new("1".to_owned(), Path::new("1.webm")),
+            parseProbeOutput(output)?)?;
+        let chapters: Vec<(f64, &str)> = video.chapters.iter()
+            .map(|c| (c.start.as_seconds_f64(), c.title.as_str())).collect();
+        assert_eq!(chapters, vec![(0.0, ""), (65.0, "Second")]);
+        Ok(())
+    }
+
+    #[test]
+    fn testUnknownDuration() -> Result<(), Error>
+    {
+        let sections = parseProbeOutput(
+            b"[FORMAT]\nformat_name=matroska,webm\nduration=N/A\n[/FORMAT]\n")?;
+        let video = fillProbedMetadata(
+            Video::new("1".to_owned(), Path::new("1.webm")), sections)?;
+        assert_eq!(video.duration, time::Duration::ZERO);
+        let sections = parseProbeOutput(
+            b"[FORMAT]\nformat_name=matroska,webm\n[/FORMAT]\n")?;
+        let video = fillProbedMetadata(
+            Video::new("1".to_owned(), Path::new("1.webm")), sections)?;
+        assert_eq!(video.duration, time::Duration::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn testCoverArtStream() -> Result<(), Error>
+    {
+        let sections = parseProbeOutput(
+            b"[STREAM]\nindex=0\ncodec_type=audio\n[/STREAM]\n\
+              [STREAM]\nindex=1\ncodec_type=video\n\
+              DISPOSITION:attached_pic=1\n[/STREAM]\n")?;
+        assert_eq!(coverArtStream(&sections), Some(1));
+        assert_eq!(coverArtStream(&sections[..1]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn testWebCompatible() -> Result<(), Error>
+    {
+        let probe = |format: &str, video: &str, audio: &str| {
+            parseProbeOutput(format!(
+                "[STREAM]\ncodec_type=video\ncodec_name={}\n[/STREAM]\n\
+                 [STREAM]\ncodec_type=audio\ncodec_name={}\n[/STREAM]\n\
+                 [FORMAT]\nformat_name={}\n[/FORMAT]\n",
+                video, audio, format).as_bytes())
+        };
+        assert!(isWebCompatible(&probe("mov,mp4,m4a,3gp,3g2,mj2", "h264",
+                                       "aac")?));
+        assert!(isWebCompatible(&probe("matroska,webm", "vp9", "opus")?));
+        assert!(!isWebCompatible(&probe("matroska,webm", "h264", "aac")?));
+        assert!(!isWebCompatible(&probe("mov,mp4,m4a,3gp,3g2,mj2", "hevc",
+                                        "aac")?));
+        assert!(!isWebCompatible(&probe("avi", "h264", "mp3")?));
+        Ok(())
+    }
+
+    #[test]
+    fn testDisallowedContainer() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let mut clean_up = FileDeleter::new();
+        let video_dir = std::env::temp_dir();
+        let config = Configuration {
+            video_dir: video_dir.to_str().ok_or(
+                rterr!("Invalid video dir"))?.to_owned(),
+            allowed_containers: vec![String::from("mp4")],
+            ..Default::default()
+        };
+        let temp_file = video_dir.join("test-disallowed.webm");
+        std::fs::copy("test-data/test-av1-opus.webm", &temp_file)?;
+        clean_up.register(&temp_file);
+        clean_up.register(video_dir.join("23456"));
+        let v = RawVideo {
+            path: temp_file,
+            hash: "23456".to_owned(),
+            original_filename: "test-av1-opus.webm".to_owned(),
+        };
+        let result = v.moveToLibrary(&config)?
+            .makeRelativePath(&config)?
+            .probeMetadata(&config);
+        match result
+        {
+            Err(Error::HTTPStatus(code, _)) =>
+                assert_eq!(code, StatusCode::BAD_REQUEST),
+            Err(e) => return Err(e.into()),
+            Ok(_) => panic!("Disallowed container is accepted"),
+        }
+        assert!(!video_dir.join("23456").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn testExistingFileIsKept() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let mut clean_up = FileDeleter::new();
+        let video_dir = randomTempFilename(std::env::temp_dir());
+        std::fs::create_dir_all(&video_dir)?;
+        let config = Configuration {
+            video_dir: video_dir.to_str().ok_or(
+                rterr!("Invalid video dir"))?.to_owned(),
+            ..Default::default()
+        };
+        // A file of another video with the same name, but no row in
+        // the database.
+        std::fs::write(video_dir.join("45678.webm"), "existing")?;
+        clean_up.register(video_dir.join("45678.webm"));
+        clean_up.register(video_dir.join("45678"));
+        let temp_file = video_dir.join("test-existing.webm");
+        std::fs::copy("test-data/test-av1-opus.webm", &temp_file)?;
+        clean_up.register(&temp_file);
+        let v = RawVideo {
+            path: temp_file.clone(),
+            hash: "45678".to_owned(),
+            original_filename: "test-av1-opus.webm".to_owned(),
+        };
+        match v.moveToLibrary(&config)?.makeRelativePath(&config)?
+            .probeMetadata(&config)
+        {
+            Err(Error::HTTPStatus(code, _)) =>
+                assert_eq!(code, StatusCode::CONFLICT),
+            _ => panic!("Existing file is not a conflict"),
+        }
+        assert_eq!(std::fs::read(video_dir.join("45678.webm"))?, b"existing");
+        assert!(!video_dir.join("45678").exists());
+        assert!(!temp_file.exists());
+        std::fs::remove_dir_all(&video_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn testCanonicalExtension() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let mut clean_up = FileDeleter::new();
+        let video_dir = std::env::temp_dir();
+        let config = Configuration {
+            video_dir: video_dir.to_str().ok_or(
+                rterr!("Invalid video dir"))?.to_owned(),
+            ..Default::default()
+        };
+        let temp_file = video_dir.join("test-no-extension");
+        std::fs::copy("test-data/test-av1-opus.webm", &temp_file)?;
+        clean_up.register(&temp_file);
+        clean_up.register(video_dir.join("34567"));
+        clean_up.register(video_dir.join("34567.webm"));
+        let v = RawVideo {
+            path: temp_file,
+            hash: "34567".to_owned(),
+            original_filename: "no-extension".to_owned(),
+        };
+        let video = v.moveToLibrary(&config)?
+            .makeRelativePath(&config)?
+            .probeMetadata(&config)?;
+        assert_eq!(video.path, Path::new("34567.webm"));
+        assert!(video_dir.join("34567.webm").exists());
+        Ok(())
+    }
+
     #[test]
     fn testVideoPipeline() -> Result<(), Box<dyn std::error::Error>>
     {
@@ -439,25 +1787,34 @@ mod tests
         let mut config = Configuration::default();
         config.video_dir = video_dir.to_str().ok_or(
             rterr!("Invalid video dir"))?.to_owned();
+        // The artist tag takes precedence.
+        config.default_artist = String::from("Someone");
+        config.loop_duration_max_sec = Some(10);
         let temp_file = video_dir.join("test.webm");
         std::fs::copy("test-data/test-av1-opus.webm", &temp_file)?;
         clean_up.register(video_dir.join("test.webm"));
         let v = RawVideo {
-            path: temp_file,
+            path: temp_file.clone(),
             hash: "12345".to_owned(),
             original_filename: "test-av1-opus.webm".to_owned(),
         };
+        let storage = crate::storage::LocalStorage::new(&video_dir);
         let mut data_manager = data::Manager::new(
             crate::sqlite_connection::Source::Memory);
         data_manager.connect()?;
         data_manager.init()?;
         clean_up.register(video_dir.join("12345.webm"));
         clean_up.register(video_dir.join("12345.webp"));
-        v.moveToLibrary(&config)?
-            .makeRelativePath(&config)?
-            .probeMetadata(&config)?
-            .generateThumbnail(&config)?
-            .addToDatabase(&config, &data_manager)?;
+        let options = UploadOptions {
+            visibility: Visibility::Public,
+            uploader: String::new(),
+            thumbnail: None,
+            title: None,
+            desc: None,
+            artist: None,
+        };
+        v.admit(options, &config, &data_manager)?
+            .process(&config, &storage, &data_manager)?;
 
         let v = data_manager.findVideoByID("12345")?;
         assert!(v.is_some());
@@ -472,9 +1829,35 @@ mod tests
         assert_eq!(v.container_type, ContainerType::WebM);
         assert_eq!(&v.original_filename, "test-av1-opus.webm");
         assert_eq!(v.duration, time::Duration::seconds(10));
+        assert!(v.looping);
         assert!(v.thumbnail_path.is_some());
         assert!(video_dir.join(&v.thumbnail_path.unwrap()).exists());
 
+        // Uploading the same file again is a conflict, and the
+        // existing video is kept.
+        std::fs::copy("test-data/test-av1-opus.webm", &temp_file)?;
+        let v = RawVideo {
+            path: temp_file.clone(),
+            hash: "12345".to_owned(),
+            original_filename: "again.webm".to_owned(),
+        };
+        let options = UploadOptions {
+            visibility: Visibility::Public,
+            uploader: String::new(),
+            thumbnail: None,
+            title: None,
+            desc: None,
+            artist: None,
+        };
+        match v.admit(options, &config, &data_manager)
+        {
+            Err(Error::HTTPStatus(code, _)) =>
+                assert_eq!(code, StatusCode::CONFLICT),
+            _ => panic!("Duplicate upload is not a conflict"),
+        }
+        assert!(!temp_file.exists());
+        assert!(video_dir.join("12345.webm").exists());
+
         Ok(())
     }
 }
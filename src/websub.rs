@@ -0,0 +1,14 @@
+use log::info;
+
+use crate::error::Error;
+
+/// Notify a WebSub hub that the content of “topic” has changed, so
+/// that the hub pushes it to the subscribers.
+pub fn publish(hub: &str, topic: &str) -> Result<(), Error>
+{
+    ureq::post(hub)
+        .send_form([("hub.mode", "publish"), ("hub.url", topic)])
+        .map_err(|e| rterr!("Failed to notify WebSub hub {}: {}", hub, e))?;
+    info!("Notified WebSub hub {} of {}.", hub, topic);
+    Ok(())
+}
@@ -0,0 +1,12 @@
+// Seek to the start of a chapter when its link in the chapter list
+// is clicked.
+const chapter_player = document.querySelector("video.VideoPlayer");
+
+for(const link of document.querySelectorAll("#Chapters a[data-start]"))
+{
+    link.addEventListener("click", function (event) {
+        event.preventDefault();
+        chapter_player.currentTime = parseFloat(link.dataset.start);
+        chapter_player.play();
+    });
+}
@@ -0,0 +1,27 @@
+// Periodically save the playback position of the video, so that
+// playback can resume from there next time.
+const player = document.querySelector("video.VideoPlayer");
+const position_url = player.dataset.positionUrl;
+var last_saved = -1;
+
+function savePosition() {
+    const position = player.currentTime;
+    if(Math.abs(position - last_saved) < 1)
+    {
+        return;
+    }
+    last_saved = position;
+    fetch(position_url, {
+        method: "POST",
+        headers: {"Content-Type": "application/json"},
+        body: JSON.stringify({position: position}),
+    });
+}
+
+player.addEventListener("timeupdate", function () {
+    if(Math.abs(player.currentTime - last_saved) >= 10)
+    {
+        savePosition();
+    }
+});
+player.addEventListener("pause", savePosition);
@@ -0,0 +1,60 @@
+// Show a preview image when hovering over the seek bar. The previews
+// are tiles in a sprite sheet, described by the metadata track of
+// the video.
+const scrub_player = document.querySelector("video.VideoPlayer");
+const scrub_track = scrub_player.querySelector("track[kind=metadata]");
+const scrub_preview = document.getElementById("ScrubPreview");
+// The seek bar is in the bottom part of the native controls.
+const SEEK_BAR_HEIGHT = 40;
+
+scrub_track.track.mode = "hidden";
+
+function findCue(time) {
+    const cues = scrub_track.track.cues;
+    if(cues === null)
+    {
+        return null;
+    }
+    for(const cue of cues)
+    {
+        if(time >= cue.startTime && time < cue.endTime)
+        {
+            return cue;
+        }
+    }
+    return null;
+}
+
+function hidePreview() {
+    scrub_preview.style.display = "none";
+}
+
+scrub_player.addEventListener("mousemove", function (e) {
+    const rect = scrub_player.getBoundingClientRect();
+    if(rect.bottom - e.clientY > SEEK_BAR_HEIGHT ||
+       !isFinite(scrub_player.duration))
+    {
+        hidePreview();
+        return;
+    }
+    const x = e.clientX - rect.left;
+    const cue = findCue(x / rect.width * scrub_player.duration);
+    const match = cue === null ? null :
+          cue.text.match(/^(.*)#xywh=(\d+),(\d+),(\d+),(\d+)$/);
+    if(match === null)
+    {
+        hidePreview();
+        return;
+    }
+    const [left, top, width, height] = match.slice(2).map(Number);
+    const url = new URL(match[1], scrub_track.src);
+    scrub_preview.style.backgroundImage = 'url("' + url.href + '")';
+    scrub_preview.style.backgroundPosition = -left + "px " + -top + "px";
+    scrub_preview.style.width = width + "px";
+    scrub_preview.style.height = height + "px";
+    scrub_preview.style.left =
+        Math.min(Math.max(x - width / 2, 0), rect.width - width) + "px";
+    scrub_preview.style.bottom = SEEK_BAR_HEIGHT + "px";
+    scrub_preview.style.display = "block";
+});
+scrub_player.addEventListener("mouseleave", hidePreview);
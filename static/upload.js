@@ -7,27 +7,66 @@ if(match !== null && match[1] !== undefined)
     serve_prefix = match[1];
 }
 
-function postFile() {
-    var formdata = new FormData();
-    formdata.append('FileToUpload', document.getElementById('FileToUpload').files[0]);
-    var request = new XMLHttpRequest();
+function randomUploadID() {
+    var bytes = new Uint8Array(16);
+    crypto.getRandomValues(bytes);
+    return Array.from(bytes, b => b.toString(16).padStart(2, '0')).join('');
+}
 
-    request.upload.addEventListener('progress', function (e) {
-        var file1Size = document.getElementById('FileToUpload').files[0].size;
+function showProgress(percent) {
+    document.getElementById('ProgressBar').style.width = percent + '%';
+    document.getElementById('ProgressBar').innerHTML = percent + '%';
+}
 
-        if (e.loaded <= file1Size) {
-            var percent = Math.round(e.loaded / file1Size * 100);
-            document.getElementById('ProgressBar').style.width = percent + '%';
-            document.getElementById('ProgressBar').innerHTML = percent + '%';
+function postFile() {
+    var video = document.getElementById('FileToUpload');
+    var video_url = document.getElementById('UrlToUpload').value;
+    // Without a file, the server fetches the video from the URL.
+    // Uploaded thumbnails are not supported then.
+    var from_url = video.files[0] === undefined && video_url !== '';
+    var formdata = from_url ? new URLSearchParams() : new FormData();
+    // The upload ID goes before the video, so that the server knows
+    // it when receiving the video.
+    var upload_id = randomUploadID();
+    formdata.append('upload_id', upload_id);
+    formdata.append('visibility', document.getElementById('Visibility').value);
+    for(const field of ['title', 'artist', 'desc'])
+    {
+        formdata.append(field, document.getElementsByName(field)[0].value);
+    }
+    if(from_url)
+    {
+        formdata.append('url', video_url);
+    }
+    else
+    {
+        formdata.append(video.name, video.files[0]);
+        var thumbnail = document.getElementById('ThumbnailToUpload').files[0];
+        if(thumbnail !== undefined)
+        {
+            formdata.append('thumbnail', thumbnail);
         }
+    }
+    var request = new XMLHttpRequest();
 
-        if(e.loaded == e.total){
-            document.getElementById('ProgressBar').style.width = '100%';
-            document.getElementById('ProgressBar').innerHTML = '100%';
-        }
+    // The progress is reported by the server as it writes the video.
+    var events = new EventSource(serve_prefix + '/upload/progress/' + upload_id);
+    events.onmessage = function (e) {
+        showProgress(parseInt(e.data));
+    };
+    events.addEventListener('done', function () {
+        showProgress(100);
+        events.close();
+    });
+    request.addEventListener('loadend', function () {
+        events.close();
     });
 
-    request.open('post', serve_prefix + '/upload/');
+    request.open('post', serve_prefix + (from_url ? '/upload-url' : '/upload/'));
     request.timeout = 45000;
     request.send(formdata);
 }
+
+document.addEventListener('DOMContentLoaded', function () {
+    document.getElementById('UploadButton').addEventListener('click', postFile);
+});